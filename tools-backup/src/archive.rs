@@ -0,0 +1,541 @@
+//! Export snapshots into tar archives and import them again
+//!
+//! The archive contains the original items, i.e., symlinks are stored as real
+//! symlinks and the permissions and modification times are taken from the
+//! manifest. Archives with a `.zst` extension are compressed with zstd. On
+//! import, compressed archives are detected by their magic bytes. The actions
+//! of an import are recorded in the event log of the new snapshot, exports
+//! only print their events.
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, Builder, EntryType, Header};
+use tools_utils::{Error, Result};
+
+use super::backup;
+use super::events::{Event, EventFormat, EventLog};
+use super::manifest::{self, EntryKind, ManifestEntry};
+use super::snapshot::{self, SnapshotEntry};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Statistics of an import
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportReport {
+    pub copied_files: u64,
+    pub copied_bytes: u64,
+    pub linked_files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// FIFOs and device nodes, recreated if permitted
+    pub special_files: u64,
+    /// Archive entries of unsupported types, e.g., devices
+    pub skipped: u64,
+}
+
+/// Write all items of the snapshot into a tar archive
+///
+/// Returns the number of exported items.
+pub fn export_snapshot(
+    snapshot: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    format: EventFormat,
+) -> Result<u64> {
+    let output = output.as_ref();
+    let mut log = EventLog::print_only(format);
+    let file =
+        File::create(output).map_err(|e| format!("export_snapshot: cannot create file: {}", e))?;
+
+    if is_compressed_path(output) {
+        let encoder = zstd::Encoder::new(file, 0)
+            .map_err(|e| format!("export_snapshot: cannot create encoder: {}", e))?;
+        let (count, encoder) = write_archive(snapshot.as_ref(), encoder, &mut log)?;
+        encoder
+            .finish()
+            .map_err(|e| format!("export_snapshot: cannot write file: {}", e))?;
+        Ok(count)
+    } else {
+        let (count, _) = write_archive(snapshot.as_ref(), file, &mut log)?;
+        Ok(count)
+    }
+}
+
+fn write_archive<W: Write>(snapshot: &Path, writer: W, log: &mut EventLog) -> Result<(u64, W)> {
+    let mut builder = Builder::new(writer);
+    let mut count = 0;
+
+    for entry in snapshot::read_snapshot(snapshot)? {
+        let mut header = Header::new_gnu();
+        header.set_mtime(entry.mtime.max(0) as u64);
+        header.set_mode(entry.mode.unwrap_or(match entry.kind {
+            EntryKind::Directory => 0o755,
+            _ => 0o644,
+        }));
+
+        match entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, &entry.path, io::empty())
+            }
+            EntryKind::Symlink => {
+                let link_target = entry.link_target.as_ref().ok_or_else(|| {
+                    Error::from(format!("export_snapshot: {:?} has no target", entry.path))
+                })?;
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, &entry.path, link_target)
+            }
+            EntryKind::File => {
+                let file = File::open(&entry.data_path)
+                    .map_err(|e| format!("export_snapshot: cannot open file: {}", e))?;
+                let size = file
+                    .metadata()
+                    .map_err(|e| format!("export_snapshot: could not retrieve metadata: {}", e))?
+                    .len();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(size);
+                builder.append_data(&mut header, &entry.path, file)
+            }
+            EntryKind::Fifo | EntryKind::BlockDevice | EntryKind::CharDevice => {
+                header.set_entry_type(match entry.kind {
+                    EntryKind::Fifo => EntryType::Fifo,
+                    EntryKind::BlockDevice => EntryType::Block,
+                    _ => EntryType::Char,
+                });
+                header.set_size(0);
+                let (major, minor) = device_numbers(entry.rdev.unwrap_or_default());
+                header
+                    .set_device_major(major)
+                    .and_then(|_| header.set_device_minor(minor))
+                    .and_then(|_| builder.append_data(&mut header, &entry.path, io::empty()))
+            }
+            EntryKind::Socket => {
+                log.record(
+                    Event::new("skip")
+                        .with_source(&entry.path)
+                        .with_detail("sockets cannot be archived"),
+                )?;
+                continue;
+            }
+        }
+        .map_err(|e| format!("export_snapshot: cannot write {:?}: {}", entry.path, e))?;
+        count += 1;
+    }
+
+    let writer = builder
+        .into_inner()
+        .map_err(|e| format!("export_snapshot: cannot write archive: {}", e))?;
+    Ok((count, writer))
+}
+
+/// Recreate a snapshot from a tar archive
+///
+/// Files with the same path, size, and modification time as in the reference
+/// snapshot are hard-linked to the reference instead of written.
+pub fn import_snapshot(
+    archive: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    reference: Option<impl AsRef<Path>>,
+    format: EventFormat,
+) -> Result<ImportReport> {
+    let target = target.as_ref();
+    let file = File::open(archive.as_ref())
+        .map_err(|e| format!("import_snapshot: cannot open file: {}", e))?;
+    let mut reader = BufReader::new(file);
+    let is_compressed = reader
+        .fill_buf()
+        .map(|buffer| buffer.starts_with(&ZSTD_MAGIC))
+        .map_err(|e| format!("import_snapshot: cannot read file: {}", e))?;
+
+    let reference_entries = match reference {
+        Some(reference) => snapshot::read_snapshot(reference)?
+            .into_iter()
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| (entry.path.clone(), entry))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    backup::ensure_directory_exists(target)?;
+    let mut log = EventLog::open(target, format)?;
+    let result = if is_compressed {
+        zstd::Decoder::with_buffer(reader)
+            .map_err(|e| Error::from(format!("import_snapshot: cannot create decoder: {}", e)))
+            .and_then(|decoder| read_archive(decoder, target, &reference_entries, &mut log))
+    } else {
+        read_archive(reader, target, &reference_entries, &mut log)
+    };
+    if let Err(e) = &result {
+        log.record(
+            Event::new("error")
+                .with_source(archive.as_ref())
+                .with_error(e),
+        )?;
+    }
+    log.flush()?;
+    result
+}
+
+fn read_archive<R: Read>(
+    reader: R,
+    target: &Path,
+    reference_entries: &HashMap<PathBuf, SnapshotEntry>,
+    log: &mut EventLog,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut entries = Vec::<ManifestEntry>::new();
+    let mut seen = HashSet::<PathBuf>::new();
+
+    let mut archive = Archive::new(reader);
+    let archive_entries = archive
+        .entries()
+        .map_err(|e| format!("import_snapshot: cannot read archive: {}", e))?;
+    for archive_entry in archive_entries {
+        let mut archive_entry =
+            archive_entry.map_err(|e| format!("import_snapshot: invalid archive entry: {}", e))?;
+        let path = archive_entry
+            .path()
+            .map_err(|e| format!("import_snapshot: invalid path: {}", e))?
+            .into_owned();
+        let path = relative_path(&path)?;
+        // NOTE: a second entry must never write into a file linked to the reference
+        if !seen.insert(path.clone()) {
+            return Err(Error::from(format!(
+                "import_snapshot: duplicate path in archive {:?}",
+                path
+            )));
+        }
+        let target_item = target.join(&path);
+
+        let header = archive_entry.header();
+        let mtime = header.mtime().unwrap_or(0) as i64;
+        let mode = header.mode().ok();
+        let size = header.size().unwrap_or(0);
+        let entry_type = header.entry_type();
+        let mut rdev = None;
+
+        let event = Event::new("directory")
+            .with_source(&path)
+            .with_target(&target_item);
+        let (kind, link_target, event) = match entry_type {
+            EntryType::Directory => {
+                backup::backup_directory(&target_item)?;
+                report.directories += 1;
+                (EntryKind::Directory, None, event)
+            }
+            EntryType::Symlink => {
+                let link_target = archive_entry
+                    .link_name()
+                    .map_err(|e| format!("import_snapshot: invalid link target: {}", e))?
+                    .ok_or_else(|| {
+                        Error::from(format!("import_snapshot: {:?} has no target", path))
+                    })?
+                    .into_owned();
+                backup::write_link_placeholder(&target_item, &link_target)?;
+                report.symlinks += 1;
+                let link_target = link_target.to_string_lossy().into_owned();
+                let event = Event {
+                    action: "symlink",
+                    ..event
+                }
+                .with_detail(format!("-> {}", link_target));
+                (EntryKind::Symlink, Some(link_target), event)
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                if let Some(parent) = target_item.parent() {
+                    backup::ensure_directory_exists(parent)?;
+                }
+                let reference = reference_entries
+                    .get(&path)
+                    .filter(|entry| entry.size == size && entry.mtime == mtime);
+                let link_error = match reference {
+                    Some(reference) => fs::hard_link(&reference.data_path, &target_item).err(),
+                    None => None,
+                };
+                let event = match reference {
+                    Some(reference) if link_error.is_none() => {
+                        report.linked_files += 1;
+                        Event {
+                            action: "link",
+                            ..event
+                        }
+                        .with_reference(Some(&reference.data_path))
+                    }
+                    _ => {
+                        let mut file = OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(&target_item)
+                            .map_err(|e| {
+                                format!(
+                                    "import_snapshot: cannot create file {:?}: {}",
+                                    target_item, e
+                                )
+                            })?;
+                        let bytes = io::copy(&mut archive_entry, &mut file)
+                            .map_err(|e| format!("import_snapshot: cannot write file: {}", e))?;
+                        report.copied_bytes += bytes;
+                        report.copied_files += 1;
+                        let mtime = mtime.max(0) as u64;
+                        utime::set_file_times(&target_item, mtime, mtime)
+                            .map_err(|e| format!("import_snapshot: could not set mtime: {}", e))?;
+
+                        let mut event = Event {
+                            action: "copy",
+                            bytes: Some(bytes),
+                            ..event
+                        };
+                        if let Some(e) = link_error {
+                            event = event.with_detail(format!("cannot link: {}", e));
+                        }
+                        event
+                    }
+                };
+                (EntryKind::File, None, event)
+            }
+            EntryType::Fifo | EntryType::Block | EntryType::Char => {
+                let kind = match entry_type {
+                    EntryType::Fifo => EntryKind::Fifo,
+                    EntryType::Block => EntryKind::BlockDevice,
+                    _ => EntryKind::CharDevice,
+                };
+                let major = header.device_major().ok().flatten().unwrap_or(0);
+                let minor = header.device_minor().ok().flatten().unwrap_or(0);
+                rdev = Some(make_device(major, minor)).filter(|_| kind != EntryKind::Fifo);
+
+                if let Some(parent) = target_item.parent() {
+                    backup::ensure_directory_exists(parent)?;
+                }
+                let mode = mode.unwrap_or(0o644) & 0o7777;
+                let detail = match backup::create_special_node(
+                    &target_item,
+                    kind,
+                    mode,
+                    rdev.unwrap_or(0),
+                ) {
+                    Ok(()) => kind.name().to_owned(),
+                    Err(e) => format!("{}, metadata only: {}", kind.name(), e),
+                };
+                report.special_files += 1;
+                let event = Event {
+                    action: "special",
+                    ..event
+                }
+                .with_detail(detail);
+                (kind, None, event)
+            }
+            other => {
+                log.record(
+                    Event::new("skip")
+                        .with_source(&path)
+                        .with_detail(format!("unsupported type {:?}", other)),
+                )?;
+                report.skipped += 1;
+                continue;
+            }
+        };
+        log.record(event)?;
+
+        entries.push(ManifestEntry {
+            path: manifest::manifest_path(&path),
+            stored_path: None,
+            kind,
+            size: if kind == EntryKind::File { size } else { 0 },
+            mtime,
+            mode: mode.map(|mode| mode & 0o7777),
+            link_target,
+            rdev,
+        });
+    }
+
+    manifest::write_manifest(target, &entries)?;
+    Ok(report)
+}
+
+/// Ensure the archive path stays inside the target
+fn relative_path(path: &Path) -> Result<PathBuf> {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => result.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(Error::from(format!(
+                    "import_snapshot: invalid path in archive {:?}",
+                    path
+                )))
+            }
+        }
+    }
+    if result == Path::new(manifest::METADATA_DIR) || result.starts_with(manifest::METADATA_DIR) {
+        return Err(Error::from(format!(
+            "import_snapshot: reserved path in archive {:?}",
+            path
+        )));
+    }
+    Ok(result)
+}
+
+#[cfg(target_os = "linux")]
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    (libc::major(rdev), libc::minor(rdev))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    ((rdev >> 8) as u32 & 0xfff, rdev as u32 & 0xff)
+}
+
+#[cfg(target_os = "linux")]
+fn make_device(major: u32, minor: u32) -> u64 {
+    libc::makedev(major, minor)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn make_device(major: u32, minor: u32) -> u64 {
+    (u64::from(major) << 8) | u64::from(minor & 0xff)
+}
+
+fn is_compressed_path(path: &Path) -> bool {
+    path.extension().map(|ext| ext == "zst").unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backup::{run_backup, BackupOptions, NoOpIgnoreSpec};
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn export_and_import_roundtrip() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), None)?
+            .with_file(("source", "bar", "baz.txt"), Some("world"), None)?
+            .with_directory(("repo", "2020-01"))?
+            .with_directory("out")?;
+        run_backup(
+            spec.path("source"),
+            spec.path(("repo", "2020-01")),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        for name in &["snapshot.tar", "snapshot.tar.zst"] {
+            let archive = spec.path(("out", *name));
+            let target = spec.path(("out", format!("{}.d", name).as_str()));
+            assert_eq!(
+                export_snapshot(spec.path(("repo", "2020-01")), &archive, EventFormat::Text)?,
+                3
+            );
+
+            let report = import_snapshot(
+                &archive,
+                &target,
+                Some(spec.path(("repo", "2020-01"))),
+                EventFormat::Text,
+            )?;
+            assert_eq!(report.linked_files, 2);
+            assert_eq!(report.directories, 1);
+            assert_eq!(read_file(target.join("foo.txt"))?, "hello");
+            let events = read_file(target.join(manifest::METADATA_DIR).join("events.jsonl"))?;
+            let mut actions = events
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .map(|event| event["action"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            actions.sort();
+            assert_eq!(actions, vec!["directory", "link", "link"]);
+            assert_eq!(
+                snapshot::read_snapshot(&target)?,
+                snapshot::read_snapshot(spec.path(("repo", "2020-01")))?
+                    .into_iter()
+                    .map(|entry| SnapshotEntry {
+                        data_path: target.join(&entry.path),
+                        ..entry
+                    })
+                    .collect::<Vec<_>>()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn export_restores_symlinks() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("snapshot", "link"), Some("LINK foo.txt"), None)?
+            .with_directory("out")?;
+        let archive = spec.path(("out", "snapshot.tar"));
+        export_snapshot(spec.path("snapshot"), &archive, EventFormat::Text)?;
+
+        let mut archive = Archive::new(File::open(&archive).unwrap());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.header().entry_type(), EntryType::Symlink);
+        assert_eq!(
+            entry.link_name().unwrap().unwrap().as_ref(),
+            Path::new("foo.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn import_rejects_paths_outside_of_the_target() {
+        assert!(relative_path(Path::new("../foo")).is_err());
+        assert!(relative_path(Path::new("/foo")).is_err());
+        assert!(relative_path(Path::new(".tools-backup/manifest.jsonl")).is_err());
+        assert_eq!(
+            relative_path(Path::new("./foo/bar")).unwrap(),
+            Path::new("foo").join("bar")
+        );
+    }
+
+    #[test]
+    fn import_rejects_duplicate_paths() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), None)?
+            .with_directory(("repo", "2020-01"))?
+            .with_directory("out")?;
+        run_backup(
+            spec.path("source"),
+            spec.path(("repo", "2020-01")),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+        let reference = snapshot::read_snapshot(spec.path(("repo", "2020-01")))?;
+
+        // NOTE: the first entry is linked to the reference, the second is not
+        let archive = spec.path(("out", "snapshot.tar"));
+        let mut builder = Builder::new(File::create(&archive).unwrap());
+        for content in &["hello", "evil!"] {
+            let mut header = Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mtime(reference[0].mtime as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "foo.txt", content.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let target = spec.path(("out", "snapshot"));
+        let err = import_snapshot(
+            &archive,
+            &target,
+            Some(spec.path(("repo", "2020-01"))),
+            EventFormat::Text,
+        )
+        .expect_err("duplicate paths must be rejected");
+        assert!(err.to_string().contains("duplicate path"));
+        assert_eq!(
+            read_file(spec.path(("repo", "2020-01", "foo.txt")))?,
+            "hello"
+        );
+        Ok(())
+    }
+}
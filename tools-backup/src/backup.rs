@@ -1,70 +1,383 @@
 //! Helpers to run backups
 use glob::Pattern;
 use std::{
-    fs::{self, File},
-    io::{BufRead, BufReader, Write},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 use tools_utils::{Error, Result};
 use walkdir::WalkDir;
 
+use super::collision::NameResolver;
+use super::events::{Event, EventFormat, EventLog};
+use super::faults;
+use super::manifest::{self, EntryKind, ManifestEntry, METADATA_DIR};
+use super::throttle::Throttle;
+
+/// The prefix of items written under a temporary name, renamed once complete
+pub const PARTIAL_PREFIX: &str = ".tools-backup-partial-";
+
+/// The OS error signaling that the maximum number of links is reached
+#[cfg(unix)]
+const TOO_MANY_LINKS: i32 = libc::EMLINK;
+#[cfg(windows)]
+const TOO_MANY_LINKS: i32 = 1142; // ERROR_TOO_MANY_LINKS
+#[cfg(not(any(unix, windows)))]
+const TOO_MANY_LINKS: i32 = -1;
+
+/// Options that modify how individual items are backed up
+#[derive(Default)]
+pub struct BackupOptions {
+    /// Limits on the IO performed while copying files
+    pub throttle: Throttle,
+    /// If given, files with at least this number of hard-links are copied
+    /// instead of linked. Independent of this setting, files are copied if the
+    /// filesystem refuses to create more links.
+    pub max_links: Option<u64>,
+    /// How often to retry copying files that were modified during the copy
+    pub copy_retries: u32,
+    /// If true, names differing only in case or Unicode normalization are
+    /// treated as equal and colliding items are renamed
+    pub fold_names: bool,
+    /// How the events of the backup are printed
+    pub event_format: EventFormat,
+    /// If the backup reads from a snapshot of the source, the original source
+    /// path used to report items
+    pub display_source: Option<PathBuf>,
+}
+
+impl BackupOptions {
+    /// The path under which an item of the source is reported
+    pub fn display_path(&self, source: &Path, item: &Path) -> PathBuf {
+        match (&self.display_source, item.strip_prefix(source)) {
+            (Some(display_source), Ok(rel_item)) => display_source.join(rel_item),
+            _ => item.to_owned(),
+        }
+    }
+}
+
+/// The action performed to backup a single item
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackupAction {
+    /// `unstable` is true, if the source was modified during all attempts to
+    /// copy it
+    /// `link_limit` is true, if the file was copied, because the reference
+    /// has too many hard-links
+    Copied {
+        bytes: u64,
+        unstable: bool,
+        link_limit: bool,
+    },
+    Linked,
+    Directory,
+    Symlink,
+    /// A FIFO, socket, or device node. `recreated` is true, if the node was
+    /// created in the target, otherwise it is only recorded in the manifest.
+    Special {
+        kind: EntryKind,
+        recreated: bool,
+    },
+    Unsupported,
+}
+
+/// Statistics of a backup run
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BackupReport {
+    pub copied_files: u64,
+    pub copied_bytes: u64,
+    pub linked_files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Items skipped, because the target already existed
+    pub skipped: u64,
+    /// Items skipped, because they matched the ignore spec
+    pub ignored: u64,
+    /// Items removed from the target, because they no longer exist in the
+    /// source (only in mirror mode)
+    pub deleted: u64,
+    /// Files that were modified while being copied, the snapshot may contain
+    /// an inconsistent version of them
+    pub unstable_files: Vec<PathBuf>,
+    /// FIFOs, sockets, and device nodes
+    pub special_files: Vec<PathBuf>,
+    /// Items stored under a different name due to name collisions
+    pub renamed: u64,
+}
+
+impl BackupReport {
+    pub fn record(&mut self, source: &Path, action: BackupAction) {
+        match action {
+            BackupAction::Copied {
+                bytes, unstable, ..
+            } => {
+                self.copied_files += 1;
+                self.copied_bytes += bytes;
+                if unstable {
+                    self.unstable_files.push(source.to_owned());
+                }
+            }
+            BackupAction::Linked => self.linked_files += 1,
+            BackupAction::Directory => self.directories += 1,
+            BackupAction::Symlink => self.symlinks += 1,
+            BackupAction::Special { .. } => self.special_files.push(source.to_owned()),
+            BackupAction::Unsupported => {}
+        }
+    }
+}
+
+impl BackupAction {
+    /// The kind of the item as stored in the snapshot, if any
+    pub fn entry_kind(self) -> Option<EntryKind> {
+        match self {
+            BackupAction::Copied { .. } | BackupAction::Linked => Some(EntryKind::File),
+            BackupAction::Directory => Some(EntryKind::Directory),
+            BackupAction::Symlink => Some(EntryKind::Symlink),
+            BackupAction::Special { kind, .. } => Some(kind),
+            BackupAction::Unsupported => None,
+        }
+    }
+}
+
 /// Run a full backup
+///
+/// After all items are backed up, the manifest with their metadata is written
+/// into the metadata directory of the target. Items renamed due to name
+/// collisions are looked up in the reference via its manifest. All actions are
+/// recorded in the event log of the target.
 pub fn run_backup(
     source: impl AsRef<Path>,
     target: impl AsRef<Path>,
     reference: Option<impl AsRef<Path>>,
     ignore_spec: &impl IgnoreSpec,
-) -> Result<()> {
+    options: &BackupOptions,
+) -> Result<BackupReport> {
     let source = source.as_ref();
-    let target = target.as_ref();
     let reference = reference.as_ref().map(|p| p.as_ref());
+    let mut run = BackupRun::new(source, target.as_ref(), reference, options)?;
 
-    let mut walker = WalkDir::new(source).into_iter();
+    let result = run.backup_tree(source, ignore_spec);
+    run.finish(result)
+}
 
-    loop {
-        let entry = match walker.next() {
-            None => {
-                println!("No more items");
-                break;
-            }
-            Some(Err(e)) => {
-                return Err(Error::from(format!(
-                    "run_backup: Invalid directory entry: {}",
-                    e
-                )))
-            }
-            Some(Ok(entry)) => entry,
-        };
+/// The state of a single backup into a target
+///
+/// Items are added by backing up subtrees of the source or by adding manifest
+/// entries directly. The manifest is written once the run is finished.
+pub struct BackupRun<'a> {
+    source: &'a Path,
+    target: &'a Path,
+    reference: Option<&'a Path>,
+    /// The stored path of each item of the reference, if it has a manifest
+    reference_paths: Option<HashMap<String, String>>,
+    resolver: NameResolver,
+    options: &'a BackupOptions,
+    pub report: BackupReport,
+    entries: Vec<ManifestEntry>,
+    pub log: EventLog,
+}
 
-        let item = entry.path();
+impl<'a> BackupRun<'a> {
+    pub fn new(
+        source: &'a Path,
+        target: &'a Path,
+        reference: Option<&'a Path>,
+        options: &'a BackupOptions,
+    ) -> Result<Self> {
+        let log = EventLog::open(target, options.event_format)?;
+        let reference_paths = match reference {
+            Some(reference) => manifest::read_manifest(reference)?.map(|entries| {
+                // NOTE: paths with replacement characters may be lossy and
+                // therefore ambiguous, see `reference_path`
+                entries
+                    .into_iter()
+                    .filter(|entry| !entry.path.contains(char::REPLACEMENT_CHARACTER))
+                    .map(|entry| (entry.path.clone(), entry.stored_path().to_owned()))
+                    .collect::<HashMap<_, _>>()
+            }),
+            None => None,
+        };
+        Ok(Self {
+            source,
+            target,
+            reference,
+            reference_paths,
+            resolver: NameResolver::new(source, options.fold_names),
+            options,
+            report: BackupReport::default(),
+            entries: Vec::new(),
+            log,
+        })
+    }
 
-        if item == source {
-            continue;
+    /// Backup an item of the source including all its children
+    pub fn backup_tree(&mut self, start: &Path, ignore_spec: &impl IgnoreSpec) -> Result<()> {
+        let source = self.source;
+        let start_target = start
+            .strip_prefix(source)
+            .map(|rel_start| self.target.join(rel_start));
+        if let Ok(start_target) = start_target {
+            if start_target.is_dir() {
+                self.remove_partial_copies(&start_target)?;
+            }
         }
+        let mut walker = WalkDir::new(start).into_iter();
+
+        loop {
+            let entry = match walker.next() {
+                None => break,
+                Some(Err(e)) => {
+                    return Err(Error::from(format!(
+                        "run_backup: Invalid directory entry: {}",
+                        e
+                    )))
+                }
+                Some(Ok(entry)) => entry,
+            };
+
+            let item = entry.path();
+
+            if item == source {
+                continue;
+            }
 
-        if ignore_spec.is_ignored(&item)? {
-            println!("skip {:?}", item);
-            // NOTE: for some reason this cannot be based on item.is_dir()
-            if entry.file_type().is_dir() {
-                walker.skip_current_dir();
+            if ignore_spec.is_ignored(item)? {
+                self.log.record(
+                    Event::new("ignore").with_source(self.options.display_path(source, item)),
+                )?;
+                self.report.ignored += 1;
+                // NOTE: for some reason this cannot be based on item.is_dir()
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            let rel_item = item
+                .strip_prefix(source)
+                .map_err(|e| format!("Cannot determine relative path: {}", e))?;
+            if rel_item == Path::new(METADATA_DIR) {
+                self.log.record(
+                    Event::new("skip")
+                        .with_source(self.options.display_path(source, item))
+                        .with_detail("reserved name"),
+                )?;
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
             }
-            continue;
+            self.backup_entry(item, rel_item, entry.file_type().is_dir())?;
         }
-        let rel_item = item
-            .strip_prefix(source)
-            .map_err(|e| format!("Cannot determine relative path: {}", e))?;
-        let target_item = target.join(&rel_item);
-        let reference_item = reference.map(|p| p.join(&rel_item));
+        Ok(())
+    }
+
+    fn backup_entry(&mut self, item: &Path, rel_item: &Path, is_dir: bool) -> Result<()> {
+        let display_item = self.options.display_path(self.source, item);
+        let stored_item = self.resolver.stored_path(rel_item, is_dir)?;
+        // NOTE: children of renamed directories are stored under a different
+        // path, but keep their own name
+        let renamed = stored_item.file_name() != rel_item.file_name();
+        if renamed {
+            self.report.renamed += 1;
+        }
+        let target_item = self.target.join(&stored_item);
+        let reference_item = match (self.reference, &self.reference_paths) {
+            (Some(reference), Some(paths)) => {
+                reference_path(paths, rel_item).map(|stored| reference.join(stored))
+            }
+            (Some(reference), None) => Some(reference.join(rel_item)),
+            (None, _) => None,
+        };
 
         // NOTE: the directories are still processed by walkdir
-        if target_item.exists() {
-            println!("SKIP {:?} [exists]", item);
-            continue;
+        let (kind, mut event) = if target_item.exists() {
+            if is_dir && target_item.is_dir() {
+                self.remove_partial_copies(&target_item)?;
+            }
+            self.report.skipped += 1;
+            let kind = item
+                .symlink_metadata()
+                .ok()
+                .and_then(|m| EntryKind::of(&m.file_type()));
+            (kind, Event::new("skip").with_detail("exists"))
+        } else {
+            let action = backup_item(item, &target_item, reference_item.as_ref(), self.options)?;
+            self.report.record(&display_item, action);
+            let event = Event::from_action(action).with_reference(reference_item.as_ref());
+            (action.entry_kind(), event)
+        };
+
+        let manifest_entry = match kind {
+            Some(kind) => {
+                let mut manifest_entry = ManifestEntry::from_source(rel_item, item, kind)?;
+                if stored_item != rel_item {
+                    manifest_entry.stored_path = Some(manifest::manifest_path(&stored_item));
+                }
+                if renamed {
+                    event = event.with_detail("renamed due to a name collision");
+                }
+                if let Some(link_target) = &manifest_entry.link_target {
+                    event = event.with_detail(format!("-> {}", link_target));
+                }
+                Some(manifest_entry)
+            }
+            None => None,
+        };
+        self.log
+            .record(event.with_source(display_item).with_target(&target_item))?;
+        self.entries.extend(manifest_entry);
+        Ok(())
+    }
+
+    /// Remove the partial copies left behind by a killed backup
+    ///
+    /// Failed copies remove their partial file, but a killed process cannot.
+    /// Existing directories of the target are therefore cleaned when the
+    /// backup is resumed.
+    fn remove_partial_copies(&mut self, directory: &Path) -> Result<()> {
+        let entries = directory
+            .read_dir()
+            .map_err(|e| format!("run_backup: cannot read directory {:?}: {}", directory, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("run_backup: cannot read entry: {}", e))?;
+            let is_file = entry.file_type().map(|t| t.is_file()).unwrap_or(false);
+            if !is_file
+                || !entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(PARTIAL_PREFIX)
+            {
+                continue;
+            }
+            faults::check("remove partial copy")?;
+            fs::remove_file(entry.path())
+                .map_err(|e| format!("run_backup: cannot remove partial copy: {}", e))?;
+            self.log.record(
+                Event::new("delete")
+                    .with_target(entry.path())
+                    .with_detail("partial copy"),
+            )?;
         }
+        Ok(())
+    }
 
-        backup_item(item, target_item, reference_item)?;
+    /// Add an item that is already stored in the target
+    pub fn add_entry(&mut self, entry: ManifestEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Write the manifest, or record the error of the run, and flush the log
+    pub fn finish(mut self, result: Result<()>) -> Result<BackupReport> {
+        let result = result.and_then(|_| manifest::write_manifest(self.target, &self.entries));
+        if let Err(e) = &result {
+            let source = self.options.display_path(self.source, self.source);
+            self.log
+                .record(Event::new("error").with_source(source).with_error(e))?;
+        }
+        self.log.flush()?;
+        result.map(|_| self.report)
     }
-    Ok(())
 }
 
 pub trait IgnoreSpec {
@@ -121,7 +434,71 @@ impl IgnoreSpec for NoOpIgnoreSpec {
     }
 }
 
-/// Backup an item (file, directory, or symlink)
+/// An item is ignored if it is ignored by any of the two specs
+impl<A: IgnoreSpec, B: IgnoreSpec> IgnoreSpec for (A, B) {
+    fn is_ignored(&self, path: &Path) -> Result<bool> {
+        Ok(self.0.is_ignored(path)? || self.1.is_ignored(path)?)
+    }
+}
+
+/// The name of the file that tags cache directories
+///
+/// See <https://bford.info/cachedir/>
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Ignore directories based on their contents
+///
+/// Directories are ignored if they contain a valid `CACHEDIR.TAG` or any of
+/// the marker files, e.g., `.nobackup`.
+pub struct MarkerIgnoreSpec {
+    cachedir_tag: bool,
+    markers: Vec<String>,
+}
+
+impl MarkerIgnoreSpec {
+    pub fn new(cachedir_tag: bool) -> Self {
+        Self {
+            cachedir_tag,
+            markers: Vec::new(),
+        }
+    }
+
+    pub fn with_marker(mut self, marker: impl Into<String>) -> Self {
+        self.markers.push(marker.into());
+        self
+    }
+}
+
+impl IgnoreSpec for MarkerIgnoreSpec {
+    fn is_ignored(&self, path: &Path) -> Result<bool> {
+        // NOTE: symlinks to directories are not followed
+        let is_dir = path.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false);
+        if !is_dir {
+            return Ok(false);
+        }
+        if self.cachedir_tag && has_cachedir_tag(path)? {
+            return Ok(true);
+        }
+        Ok(self.markers.iter().any(|marker| path.join(marker).exists()))
+    }
+}
+
+fn has_cachedir_tag(path: &Path) -> Result<bool> {
+    let mut file = match File::open(path.join(CACHEDIR_TAG)) {
+        Ok(file) => file,
+        Err(_) => return Ok(false),
+    };
+    let mut header = Vec::with_capacity(CACHEDIR_TAG_SIGNATURE.len());
+    (&mut file)
+        .take(CACHEDIR_TAG_SIGNATURE.len() as u64)
+        .read_to_end(&mut header)
+        .map_err(|e| format!("has_cachedir_tag: cannot read file: {}", e))?;
+    Ok(header == CACHEDIR_TAG_SIGNATURE)
+}
+
+/// Backup an item (file, directory, symlink, or special file)
 ///
 /// Arguments:
 ///
@@ -129,28 +506,138 @@ impl IgnoreSpec for NoOpIgnoreSpec {
 /// * `target` the target path that will be created
 /// * `reference`: a previous backup if it exists. Will be used to check whether
 ///   a hard-link can be used to deduplicate the files.
+/// * `options`: options that modify how the item is backed up
 ///
 pub fn backup_item(
     source: impl AsRef<Path>,
     target: impl AsRef<Path>,
     reference: Option<impl AsRef<Path>>,
-) -> Result<()> {
+    options: &BackupOptions,
+) -> Result<BackupAction> {
     let source = source.as_ref();
+    // NOTE: symlinks must not be followed
     let metadata = source
-        .metadata()
+        .symlink_metadata()
         .map_err(|e| format!("backup_item: could not retrieve metadata: {}", e))?;
-    let file_type = metadata.file_type();
 
-    if file_type.is_dir() {
-        backup_directory(target)?;
-    } else if file_type.is_file() {
-        backup_file(source, target, reference)?;
-    } else if file_type.is_symlink() {
-        backup_symlink(source, target)?;
+    let action = match EntryKind::of(&metadata.file_type()) {
+        Some(EntryKind::Directory) => {
+            backup_directory(target)?;
+            BackupAction::Directory
+        }
+        Some(EntryKind::File) => backup_file(source, target, reference, options)?,
+        Some(EntryKind::Symlink) => {
+            backup_symlink(source, target)?;
+            BackupAction::Symlink
+        }
+        Some(kind) => backup_special(target.as_ref(), kind, &metadata)?,
+        None => BackupAction::Unsupported,
+    };
+    Ok(action)
+}
+
+/// Backup a FIFO, socket, or device node
+///
+/// The node is recreated in the target, if permitted. Creating device nodes
+/// typically requires root privileges. Sockets are never recreated, since
+/// they are only meaningful while the owning process is running. In any case,
+/// the node is recorded in the manifest.
+pub fn backup_special(
+    target: &Path,
+    kind: EntryKind,
+    metadata: &fs::Metadata,
+) -> Result<BackupAction> {
+    if let Some(parent) = target.parent() {
+        ensure_directory_exists(parent)?;
+    }
+
+    // NOTE: nodes that cannot be created are only recorded in the manifest
+    faults::check("create special file")?;
+    let recreated = kind != EntryKind::Socket
+        && create_special_node(target, kind, special_mode(metadata), special_rdev(metadata))
+            .is_ok();
+    Ok(BackupAction::Special { kind, recreated })
+}
+
+/// Create a FIFO or device node
+#[cfg(unix)]
+pub fn create_special_node(target: &Path, kind: EntryKind, mode: u32, rdev: u64) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let file_type = match kind {
+        EntryKind::Fifo => libc::S_IFIFO,
+        EntryKind::BlockDevice => libc::S_IFBLK,
+        EntryKind::CharDevice => libc::S_IFCHR,
+        _ => return Err(io::Error::other(format!("cannot create {}", kind.name()))),
+    };
+    let path = CString::new(target.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mode = file_type as u32 | (mode & 0o7777);
+    if unsafe { libc::mknod(path.as_ptr(), mode as _, rdev as _) } != 0 {
+        return Err(io::Error::last_os_error());
     }
     Ok(())
 }
 
+#[cfg(not(unix))]
+pub fn create_special_node(
+    _target: &Path,
+    kind: EntryKind,
+    _mode: u32,
+    _rdev: u64,
+) -> io::Result<()> {
+    Err(io::Error::other(format!(
+        "cannot create {} on this platform",
+        kind.name()
+    )))
+}
+
+#[cfg(unix)]
+fn special_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn special_mode(_metadata: &fs::Metadata) -> u32 {
+    0o644
+}
+
+#[cfg(unix)]
+fn special_rdev(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.rdev()
+}
+
+#[cfg(not(unix))]
+fn special_rdev(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// The stored path of an item in a reference with manifest
+///
+/// Manifest paths of names that are not valid UTF-8 are lossy. As these names
+/// are never renamed, they are resolved via the stored path of their parent.
+/// Valid names containing replacement characters cannot be told apart from
+/// lossy ones and are not resolved.
+fn reference_path(paths: &HashMap<String, String>, rel_item: &Path) -> Option<PathBuf> {
+    match rel_item.to_str() {
+        Some(path) if path.contains(char::REPLACEMENT_CHARACTER) => None,
+        Some(_) => paths
+            .get(&manifest::manifest_path(rel_item))
+            .map(|stored| manifest::native_path(stored)),
+        None => {
+            let parent = rel_item.parent()?;
+            let stored_parent = if parent.as_os_str().is_empty() {
+                PathBuf::new()
+            } else {
+                reference_path(paths, parent)?
+            };
+            Some(stored_parent.join(rel_item.file_name()?))
+        }
+    }
+}
+
 /// Backup a 'normal' file
 ///
 /// Arguments:
@@ -159,12 +646,15 @@ pub fn backup_item(
 /// * `target` the target path that will be created
 /// * `reference`: a previous backup if it exists. Will be used to check whether
 ///   a hard-link can be used to deduplicate the files.
+/// * `options`: options that modify how the file is backed up, e.g., the
+///   limits on the IO bandwidth
 ///
 pub fn backup_file(
     source: impl AsRef<Path>,
     target: impl AsRef<Path>,
     reference: Option<impl AsRef<Path>>,
-) -> Result<()> {
+    options: &BackupOptions,
+) -> Result<BackupAction> {
     let source = source.as_ref();
     let target = target.as_ref();
     let reference = reference.as_ref().map(|r| r.as_ref());
@@ -174,16 +664,111 @@ pub fn backup_file(
     }
 
     if !should_link(source, reference) {
-        println!("COPY {:?}", target);
-        fs::copy(source, target)
+        return copy_file(source, target, options, false);
+    }
+
+    let reference = reference.unwrap();
+    let limit_reached = match (options.max_links, link_count(reference)) {
+        (Some(max_links), Some(count)) => count >= max_links,
+        _ => false,
+    };
+    if limit_reached {
+        // NOTE: the copy becomes the reference for the following backups
+        return copy_file(source, target, options, true);
+    }
+
+    match faults::check_io("link file").and_then(|()| fs::hard_link(reference, target)) {
+        Ok(()) => Ok(BackupAction::Linked),
+        Err(e) if is_too_many_links(&e) => copy_file(source, target, options, true),
+        Err(e) => Err(Error::from(format!(
+            "backup_file: could not create link: {}",
+            e
+        ))),
+    }
+}
+
+/// Copy the file and retry if the source is modified during the copy
+///
+/// The file is copied into a temporary file next to the target, that is
+/// renamed once complete. Interrupted copies therefore never leave a truncated
+/// target behind, that would be skipped when the backup is resumed.
+fn copy_file(
+    source: &Path,
+    target: &Path,
+    options: &BackupOptions,
+    link_limit: bool,
+) -> Result<BackupAction> {
+    let directory = target
+        .parent()
+        .ok_or_else(|| Error::from("backup_file: target without parent directory"))?;
+    let partial = tempfile::Builder::new()
+        .prefix(PARTIAL_PREFIX)
+        .tempfile_in(directory)
+        .map_err(|e| format!("backup_file: could not create temporary file: {}", e))?;
+
+    let mut attempt = 0;
+    let (bytes, unstable) = loop {
+        let before = FileState::of(source)?;
+        faults::check("copy file")?;
+        let bytes = options
+            .throttle
+            .copy(source, partial.path())
             .map_err(|e| format!("backup_file: could not copy file: {:?}", e))?;
-    } else {
-        let reference = reference.unwrap();
-        println!("LINK {:?}", reference);
-        std::fs::hard_link(reference, target)
-            .map_err(|e| format!("backup_file: could not create link: {}", e))?;
+        let after = FileState::of(source)?;
+
+        if before == after {
+            break (bytes, false);
+        }
+        if attempt >= options.copy_retries {
+            break (bytes, true);
+        }
+        attempt += 1;
+    };
+
+    faults::check("move copy into place")?;
+    partial
+        .persist_noclobber(target)
+        .map_err(|e| format!("backup_file: could not move copy into place: {}", e.error))?;
+    Ok(BackupAction::Copied {
+        bytes,
+        unstable,
+        link_limit,
+    })
+}
+
+/// The properties used to detect modifications of a file
+#[derive(Debug, PartialEq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileState {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata = path
+            .metadata()
+            .map_err(|e| format!("backup_file: could not retrieve metadata: {}", e))?;
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
     }
-    Ok(())
+}
+
+/// Check whether the error signals that the maximum number of links is reached
+fn is_too_many_links(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(TOO_MANY_LINKS)
+}
+
+#[cfg(unix)]
+fn link_count(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    path.metadata().ok().map(|m| m.nlink())
+}
+
+#[cfg(not(unix))]
+fn link_count(_path: &Path) -> Option<u64> {
+    None
 }
 
 fn should_link(source: impl AsRef<Path>, reference: Option<impl AsRef<Path>>) -> bool {
@@ -205,7 +790,7 @@ pub fn backup_directory(target: impl AsRef<Path>) -> Result<()> {
     let target = target.as_ref();
 
     if !target.exists() {
-        println!("DIR  {:?}", target);
+        faults::check("create directory")?;
         fs::create_dir_all(target)
             .map_err(|e| format!("backup_directory: Could not create directory: {}", e))?;
     } else if !target.is_dir() {
@@ -223,20 +808,29 @@ pub fn backup_symlink(source: impl AsRef<Path>, target: impl AsRef<Path>) -> Res
 
     let src_item = std::fs::read_link(source)
         .map_err(|e| format!("backup_symlink: cannot read link: {}", e))?;
-    println!("SYM  {:?} -> {:?}", target, src_item);
+    write_link_placeholder(target, &src_item)
+}
+
+/// Store a symlink as a text file of the form `LINK <link target>`
+pub fn write_link_placeholder(target: &Path, link_target: &Path) -> Result<()> {
     if let Some(parent) = target.parent() {
         ensure_directory_exists(parent)?;
     }
 
     let content = format!(
         "LINK {}",
-        src_item
+        link_target
             .to_str()
             .ok_or_else(|| Error::from("backup_symblink: Cannot represent path as utf8: {}"))?
     );
 
-    let mut f =
-        File::create(target).map_err(|e| format!("backup_symlink: cannot create file: {}", e))?;
+    faults::check("write link placeholder")?;
+    // NOTE: never write into an existing file, it may be linked to other snapshots
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)
+        .map_err(|e| format!("backup_symlink: cannot create file {:?}: {}", target, e))?;
     f.write_all(content.as_bytes())
         .map_err(|e| format!("backup_symlink: cannot write file: {}", e))?;
 
@@ -252,8 +846,10 @@ pub fn ensure_directory_exists(path: impl AsRef<Path>) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::super::test_spec::Spec;
+    use super::super::faults;
+    use super::super::test_spec::{dir, file, read_file, Spec, Tree};
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn backup_file_example_no_reference() -> Result<()> {
@@ -266,6 +862,7 @@ mod tests {
             spec.path("target"),
             Option::<&Path>::None,
             &NoOpIgnoreSpec,
+            &BackupOptions::default(),
         )?;
 
         spec.assert()?;
@@ -285,13 +882,618 @@ mod tests {
             spec.path("target"),
             Some(spec.path("reference")),
             &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        spec.assert()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn backup_file_copies_when_link_limit_is_reached() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), Some(0))?
+            .with_file(("prev", "foo.txt"), Some("hello"), Some(1))?
+            .expect_file(("first", "foo.txt"), Some("hello"), None)
+            .expect_file(("second", "foo.txt"), Some("hello"), None);
+        let options = BackupOptions {
+            max_links: Some(2),
+            ..BackupOptions::default()
+        };
+        let ino = |path: PathBuf| path.metadata().unwrap().ino();
+
+        // the first backup links, as the reference has only a single link
+        for &(target, linked, copied) in &[("first", 1, 0), ("second", 0, 1)] {
+            let report = run_backup(
+                spec.path("source"),
+                spec.path(target),
+                Some(spec.path("prev")),
+                &NoOpIgnoreSpec,
+                &options,
+            )?;
+            assert_eq!((report.linked_files, report.copied_files), (linked, copied));
+        }
+        spec.assert()?;
+
+        assert_eq!(
+            ino(spec.path(("prev", "foo.txt"))),
+            ino(spec.path(("first", "foo.txt")))
+        );
+        assert_ne!(
+            ino(spec.path(("prev", "foo.txt"))),
+            ino(spec.path(("second", "foo.txt")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn backup_file_copies_when_the_filesystem_refuses_links() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), Some(0))?
+            .with_file(("prev", "foo.txt"), Some("hello"), Some(1))?
+            .expect_file(("target", "foo.txt"), Some("hello"), None);
+
+        faults::fail_with_os_error(Some(("link file", TOO_MANY_LINKS)));
+        let report = run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Some(spec.path("prev")),
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        );
+        faults::fail_with_os_error(None);
+        let report = report?;
+
+        spec.assert()?;
+        assert_eq!(report.linked_files, 0);
+        assert_eq!(report.copied_files, 1);
+
+        let events = read_file(spec.path(("target", METADATA_DIR, "events.jsonl")))?;
+        let event = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|event| event["action"] == "copy")
+            .unwrap();
+        assert_eq!(event["detail"], "link limit reached");
+        Ok(())
+    }
+
+    #[test]
+    fn backup_file_flags_files_modified_during_copy() -> Result<()> {
+        let spec = Spec::new()?.with_file(("source", "foo.txt"), Some(&"a".repeat(4096)), None)?;
+
+        for copy_retries in 0..2 {
+            let source = spec.path(("source", "foo.txt"));
+            let target = spec.path(("target", format!("foo-{}.txt", copy_retries).as_str()));
+            let options = BackupOptions {
+                copy_retries,
+                ..BackupOptions::default()
+            };
+
+            // modify the file once, while the first copy is in progress
+            let mut modified = false;
+            faults::on_step(Some(Box::new(move |step| {
+                if step == "copy file" && !modified {
+                    modified = true;
+                    fs::OpenOptions::new()
+                        .append(true)
+                        .open(&source)
+                        .and_then(|mut f| f.write_all(b"b"))
+                        .unwrap();
+                }
+            })));
+            let action = backup_file(
+                spec.path(("source", "foo.txt")),
+                &target,
+                Option::<&Path>::None,
+                &options,
+            );
+            faults::on_step(None);
+            let action = action?;
+
+            let expected_unstable = copy_retries == 0;
+            assert!(
+                matches!(action, BackupAction::Copied { unstable, .. } if unstable == expected_unstable)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn marker_ignore_spec_skips_tagged_directories() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(
+                ("source", "cache", CACHEDIR_TAG),
+                Some("Signature: 8a477f597d28d172789f06886806bc55\n# a cache"),
+                None,
+            )?
+            .with_file(
+                ("source", "invalid", CACHEDIR_TAG),
+                Some("no signature"),
+                None,
+            )?
+            .with_file(("source", "private", ".nobackup"), None, None)?
+            .with_file(("source", "foo.txt"), Some("hello"), None)?;
+
+        let ignore_spec = MarkerIgnoreSpec::new(true).with_marker(".nobackup");
+        let report = run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &(NoOpIgnoreSpec, ignore_spec),
+            &BackupOptions::default(),
+        )?;
+
+        assert_eq!(report.ignored, 2);
+        assert!(!spec.path(("target", "cache")).exists());
+        assert!(!spec.path(("target", "private")).exists());
+        assert!(spec.path(("target", "invalid", CACHEDIR_TAG)).exists());
+        assert!(spec.path(("target", "foo.txt")).exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backup_records_symlinks_and_special_files() -> Result<()> {
+        use std::{ffi::CString, os::unix::fs::FileTypeExt};
+
+        let spec = Spec::new()?
+            .with_file(("source", "foo", "bar.txt"), Some("hello"), None)?
+            .with_directory("target")?;
+        std::os::unix::fs::symlink("foo", spec.path(("source", "link"))).unwrap();
+        let fifo = CString::new(spec.path(("source", "fifo")).to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let report = run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        assert_eq!(report.symlinks, 1);
+        assert_eq!(report.special_files, vec![spec.path(("source", "fifo"))]);
+        assert_eq!(read_file(spec.path(("target", "link")))?, "LINK foo");
+        let fifo = spec.path(("target", "fifo")).symlink_metadata().unwrap();
+        assert!(fifo.file_type().is_fifo());
+
+        let entries = manifest::read_manifest(spec.path("target"))?.unwrap();
+        let kinds = entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.kind))
+            .collect::<Vec<_>>();
+        assert!(kinds.contains(&("fifo", EntryKind::Fifo)));
+        assert!(kinds.contains(&("link", EntryKind::Symlink)));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backup_with_reference_matches_tree_fixture() -> Result<()> {
+        use super::super::test_spec::{link_placeholder, symlink, tree};
+
+        let spec = Spec::new()?
+            .with_tree(
+                "source",
+                tree([
+                    ("same.txt", file("same").mtime(-3600)),
+                    ("docs/changed.txt", file("new").mtime(0)),
+                    ("docs/link", symlink("../same.txt")),
+                    ("empty", dir()),
+                ]),
+            )?
+            .with_tree(
+                "reference",
+                tree([
+                    ("same.txt", file("same").hard_link("same")),
+                    ("docs/changed.txt", file("old").mtime(-3600)),
+                ]),
+            )?
+            .expect_tree(
+                "target",
+                tree([
+                    ("same.txt", file("same").hard_link("same")),
+                    ("docs/changed.txt", file("new").hard_link("changed")),
+                    ("docs/link", link_placeholder("../same.txt")),
+                    ("empty", dir()),
+                ]),
+            )
+            .expect_tree(
+                "reference",
+                tree([
+                    ("same.txt", file("same").hard_link("same")),
+                    ("docs/changed.txt", file("old").hard_link("previous")),
+                ]),
+            );
+
+        run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Some(spec.path("reference")),
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        spec.assert()
+    }
+
+    /// Ignore all generated files with the extension `.tmp`
+    struct TmpIgnoreSpec;
+
+    impl IgnoreSpec for TmpIgnoreSpec {
+        fn is_ignored(&self, path: &Path) -> Result<bool> {
+            Ok(path.extension() == Some(std::ffi::OsStr::new("tmp")))
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    enum ReferenceState {
+        Missing,
+        Same,
+        Changed,
+    }
+
+    #[derive(Debug, Clone)]
+    struct GeneratedFile {
+        path: PathBuf,
+        content: String,
+        reference: ReferenceState,
+    }
+
+    impl GeneratedFile {
+        fn new(path: &str, content: &str, reference: ReferenceState) -> Self {
+            Self {
+                path: PathBuf::from(path),
+                content: content.to_owned(),
+                reference,
+            }
+        }
+
+        fn is_ignored(&self) -> bool {
+            TmpIgnoreSpec.is_ignored(&self.path).unwrap()
+        }
+    }
+
+    fn generated_files() -> impl Strategy<Value = Vec<GeneratedFile>> {
+        let file = (
+            prop::collection::vec("[a-c]{1,2}", 0..3),
+            "[a-c]{1,2}(\\.tmp)?",
+            "[a-z]{0,16}",
+            prop_oneof![
+                Just(ReferenceState::Missing),
+                Just(ReferenceState::Same),
+                Just(ReferenceState::Changed)
+            ],
+        )
+            .prop_map(|(directories, name, content, reference)| GeneratedFile {
+                path: directories.iter().collect::<PathBuf>().join(name),
+                content,
+                reference,
+            });
+
+        prop::collection::vec(file, 1..12).prop_map(|files| {
+            // NOTE: a path cannot be both a file and a directory
+            let mut result = Vec::<GeneratedFile>::new();
+            for file in files {
+                if !result.iter().any(|other| {
+                    other.path.starts_with(&file.path) || file.path.starts_with(&other.path)
+                }) {
+                    result.push(file);
+                }
+            }
+            result
+        })
+    }
+
+    /// Create the source and reference and expect the target to contain all
+    /// files not ignored and the reference to be unchanged
+    fn generated_spec(files: &[GeneratedFile]) -> Result<Spec> {
+        let mut source = Tree::default();
+        let mut reference = Tree::default();
+        let mut target = Tree::default();
+
+        for (idx, generated) in files.iter().enumerate() {
+            let path = &generated.path;
+            source = source.with(path, file(&generated.content).mtime(-60));
+
+            let (reference_node, target_group) = match generated.reference {
+                ReferenceState::Missing => (None, format!("copy{}", idx)),
+                ReferenceState::Same => (
+                    Some(
+                        file(&generated.content)
+                            .mtime(0)
+                            .hard_link(&format!("same{}", idx)),
+                    ),
+                    format!("same{}", idx),
+                ),
+                ReferenceState::Changed => (
+                    Some(
+                        file(&format!("old {}", generated.content))
+                            .mtime(-3600)
+                            .hard_link(&format!("old{}", idx)),
+                    ),
+                    format!("copy{}", idx),
+                ),
+            };
+            if let Some(node) = reference_node {
+                reference = reference.with(path, node);
+            }
+
+            if !generated.is_ignored() {
+                let node = file(&generated.content).hard_link(&target_group);
+                target = target.with(path, node);
+            } else if let Some(parent) = path.parent().filter(|p| p != &Path::new("")) {
+                // NOTE: directories are backed up, even if all items are ignored
+                target = target.with(parent, dir());
+            }
+        }
+
+        let spec = Spec::new()?
+            .with_tree("source", source)?
+            .with_tree("reference", reference.clone())?;
+        Ok(spec
+            .expect_tree("target", target)
+            .expect_tree("reference", reference))
+    }
+
+    fn backup_generated(spec: &Spec) -> Result<BackupReport> {
+        // NOTE: a (practically unlimited) throttle copies in chunks, such that
+        // faults can be injected after partial writes
+        let options = BackupOptions {
+            throttle: Throttle::new().with_read_limit(1 << 40),
+            ..BackupOptions::default()
+        };
+        run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Some(spec.path("reference")),
+            &TmpIgnoreSpec,
+            &options,
+        )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn generated_trees_are_backed_up_completely(files in generated_files()) {
+            let spec = generated_spec(&files).unwrap();
+            let report = backup_generated(&spec).unwrap();
+
+            let ignored = files.iter().filter(|file| file.is_ignored()).count() as u64;
+            prop_assert_eq!(report.ignored, ignored);
+            prop_assert_eq!(
+                report.copied_files + report.linked_files,
+                files.len() as u64 - ignored
+            );
+            spec.assert().unwrap();
+        }
+    }
+
+    #[test]
+    fn interrupted_backups_can_be_resumed() -> Result<()> {
+        let files = vec![
+            GeneratedFile::new("same.txt", "same", ReferenceState::Same),
+            GeneratedFile::new("docs/changed.txt", "new", ReferenceState::Changed),
+            GeneratedFile::new("docs/new.txt", "new", ReferenceState::Missing),
+            GeneratedFile::new("cache/a.tmp", "tmp", ReferenceState::Missing),
+            // NOTE: larger than a single chunk of the throttled copy
+            GeneratedFile::new(
+                "large.bin",
+                &"x".repeat(150 * 1024),
+                ReferenceState::Missing,
+            ),
+        ];
+        let (steps, result) = faults::count_steps(|| {
+            let spec = generated_spec(&files)?;
+            backup_generated(&spec)
+        });
+        result?;
+        assert!(steps > 0);
+
+        for step in 0..steps {
+            let spec = generated_spec(&files)?;
+            faults::fail_at(Some(step));
+            let result = backup_generated(&spec);
+            faults::fail_at(None);
+            assert!(result.is_err(), "step {} did not fail", step);
+
+            // NOTE: a killed backup leaves its partial copies behind
+            for directory in &[spec.path("target"), spec.path(("target", "docs"))] {
+                if directory.is_dir() {
+                    fs::write(directory.join(format!("{}killed", PARTIAL_PREFIX)), "x")
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+
+            // NOTE: items stored before the failure are skipped
+            backup_generated(&spec)?;
+            spec.assert()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn items_of_source_snapshots_are_reported_under_the_source() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("snapshot", "docs", "foo.txt"), Some("foo"), None)?
+            .expect_file(("target", "docs", "foo.txt"), Some("foo"), None);
+        let options = BackupOptions {
+            display_source: Some(PathBuf::from("/home/user")),
+            ..BackupOptions::default()
+        };
+
+        run_backup(
+            spec.path("snapshot"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
         )?;
 
         spec.assert()?;
+        let events = read_file(spec.path(("target", METADATA_DIR, "events.jsonl")))?;
+        let sources = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|event| PathBuf::from(event["source"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            vec![
+                Path::new("/home/user").join("docs"),
+                Path::new("/home/user").join("docs").join("foo.txt"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn colliding_names_are_renamed_and_linked() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "Readme.md"), Some("lower"), None)?
+            .with_file(("source", "README.md"), Some("upper"), None)?
+            .with_directory(("repo", "first"))?
+            .with_directory(("repo", "second"))?;
+        let options = BackupOptions {
+            fold_names: true,
+            ..BackupOptions::default()
+        };
 
+        let report = run_backup(
+            spec.path("source"),
+            spec.path(("repo", "first")),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        assert_eq!(report.renamed, 1);
+        assert_eq!(
+            read_file(spec.path(("repo", "first", "Readme~1.md")))?,
+            "lower"
+        );
+
+        let report = run_backup(
+            spec.path("source"),
+            spec.path(("repo", "second")),
+            Some(spec.path(("repo", "first"))),
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        assert_eq!(report.linked_files, 2);
+        assert_eq!(
+            read_file(spec.path(("repo", "second", "Readme~1.md")))?,
+            "lower"
+        );
+
+        let entry = super::super::snapshot::find_entry(spec.path(("repo", "second")), "Readme.md")?;
+        assert_eq!(
+            entry.map(|e| e.data_path),
+            Some(spec.path(("repo", "second", "Readme~1.md")))
+        );
         Ok(())
     }
 
+    #[test]
+    fn children_of_renamed_directories_are_not_counted_as_renamed() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "DOCS", "a.txt"), Some("a"), None)?
+            .with_file(("source", "docs", "b.txt"), Some("b"), None)?
+            .with_file(("source", "docs", "c.txt"), Some("c"), None)?
+            .expect_file(("target", "DOCS", "a.txt"), Some("a"), None)
+            .expect_file(("target", "docs~1", "b.txt"), Some("b"), None)
+            .expect_file(("target", "docs~1", "c.txt"), Some("c"), None);
+        let options = BackupOptions {
+            fold_names: true,
+            ..BackupOptions::default()
+        };
+
+        let report = run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        spec.assert()?;
+        assert_eq!(report.renamed, 1);
+
+        let events = read_file(spec.path(("target", METADATA_DIR, "events.jsonl")))?;
+        let renamed = events
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["detail"] == "renamed due to a name collision")
+            .map(|event| PathBuf::from(event["target"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(renamed, vec![spec.path(("target", "docs~1"))]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn items_with_invalid_names_are_linked() -> Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let spec = Spec::new()?
+            .with_file(("source", "DOCS", "a.txt"), Some("a"), Some(0))?
+            .with_directory(("source", "docs"))?
+            .with_directory(("repo", "first"))?
+            .with_directory(("repo", "second"))?;
+        let invalid = OsStr::from_bytes(b"invalid-\xff.txt");
+        let lossy = "invalid-\u{FFFD}.txt";
+        fs::write(spec.path(("source", "docs")).join(invalid), "invalid").unwrap();
+        fs::write(spec.path(("source", "docs", lossy)), "lossy").unwrap();
+        utime::set_file_times(spec.path(("source", "docs")).join(invalid), 0, 0).unwrap();
+        let options = BackupOptions {
+            fold_names: true,
+            ..BackupOptions::default()
+        };
+
+        run_backup(
+            spec.path("source"),
+            spec.path(("repo", "first")),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        let report = run_backup(
+            spec.path("source"),
+            spec.path(("repo", "second")),
+            Some(spec.path(("repo", "first"))),
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        // NOTE: the valid name with a replacement character is always copied
+        assert_eq!((report.linked_files, report.copied_files), (2, 1));
+        assert_eq!(
+            read_file(spec.path(("repo", "second", "docs~1")).join(invalid))?,
+            "invalid"
+        );
+        assert_eq!(
+            read_file(spec.path(("repo", "second", "docs~1", lossy)))?,
+            "lossy"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_links_errors_are_detected() {
+        #[cfg(any(unix, windows))]
+        assert!(is_too_many_links(&io::Error::from_raw_os_error(
+            TOO_MANY_LINKS
+        )));
+
+        assert!(!is_too_many_links(&io::Error::new(
+            io::ErrorKind::NotFound,
+            "not found"
+        )));
+    }
+
     #[test]
     fn backup_directory_example() -> Result<()> {
         let spec = Spec::new()?
@@ -302,6 +1504,7 @@ mod tests {
             spec.path("target"),
             Option::<&Path>::None,
             &NoOpIgnoreSpec,
+            &BackupOptions::default(),
         )?;
         spec.assert()?;
         Ok(())
@@ -321,6 +1524,7 @@ mod tests {
             spec.path("target"),
             Option::<&Path>::None,
             &NoOpIgnoreSpec,
+            &BackupOptions::default(),
         )?;
         spec.assert()?;
         Ok(())
@@ -339,13 +1543,19 @@ mod tests {
             .expect_file(("target", "bar", "baz"), Some("curr"), None)
             .expect_file(("target", "hello", "world"), Some("curr"), None);
 
-        run_backup(
+        let report = run_backup(
             spec.path("source"),
             spec.path("target"),
             Some(spec.path("prev")),
             &NoOpIgnoreSpec,
+            &BackupOptions::default(),
         )?;
         spec.assert()?;
+
+        assert_eq!(report.copied_files, 2);
+        assert_eq!(report.copied_bytes, 8);
+        assert_eq!(report.linked_files, 1);
+        assert_eq!(report.directories, 2);
         Ok(())
     }
 }
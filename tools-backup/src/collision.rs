@@ -0,0 +1,228 @@
+//! Resolve names that collide on case-insensitive targets
+//!
+//! Filesystems such as NTFS or exFAT treat `Readme.md` and `README.md` as the
+//! same name, some also do not distinguish between the NFC and NFD forms of a
+//! Unicode name. When such a target is used, the names of each source
+//! directory are checked before any of its items is backed up. The first name
+//! in byte order keeps its name, the others are stored with a `~N` suffix in
+//! front of their extension, e.g., `README~1.md`. The stored name is recorded
+//! as `stored_path` in the manifest.
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::Result;
+use unicode_normalization::UnicodeNormalization;
+
+use super::manifest::METADATA_DIR;
+
+/// Map source paths to the paths used inside the snapshot
+#[derive(Debug)]
+pub struct NameResolver {
+    source: PathBuf,
+    fold_names: bool,
+    /// The stored path of each source directory, relative to the root
+    directories: HashMap<PathBuf, PathBuf>,
+    /// The stored names of the items of each source directory
+    names: HashMap<PathBuf, HashMap<OsString, OsString>>,
+}
+
+impl NameResolver {
+    /// If `fold_names` is false, paths are stored unchanged
+    pub fn new(source: impl AsRef<Path>, fold_names: bool) -> Self {
+        let mut directories = HashMap::new();
+        directories.insert(PathBuf::new(), PathBuf::new());
+        Self {
+            source: source.as_ref().to_owned(),
+            fold_names,
+            directories,
+            names: HashMap::new(),
+        }
+    }
+
+    /// Determine the stored path of an item given relative to the source
+    ///
+    /// Items must be resolved after their parent directory.
+    pub fn stored_path(&mut self, rel_item: &Path, is_dir: bool) -> Result<PathBuf> {
+        if !self.fold_names {
+            return Ok(rel_item.to_owned());
+        }
+        let name = match rel_item.file_name() {
+            Some(name) => name,
+            None => return Ok(rel_item.to_owned()),
+        };
+        let parent = rel_item.parent().unwrap_or_else(|| Path::new(""));
+
+        if !self.names.contains_key(parent) {
+            let names = resolve_names(&self.source.join(parent), parent.as_os_str().is_empty())?;
+            self.names.insert(parent.to_owned(), names);
+        }
+        let stored_name = self.names[parent]
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_owned());
+        let stored_parent = self
+            .directories
+            .get(parent)
+            .cloned()
+            .unwrap_or_else(|| parent.to_owned());
+
+        let result = stored_parent.join(stored_name);
+        if is_dir {
+            self.directories.insert(rel_item.to_owned(), result.clone());
+        }
+        Ok(result)
+    }
+}
+
+/// Check whether the target treats names differing only in case as equal
+pub fn is_case_insensitive(target: impl AsRef<Path>) -> Result<bool> {
+    let probe = tempfile::Builder::new()
+        .prefix(".tools-backup-probe-")
+        .tempfile_in(target.as_ref())
+        .map_err(|e| format!("is_case_insensitive: cannot create probe file: {}", e))?;
+    let name = probe
+        .path()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_uppercase())
+        .unwrap_or_default();
+    Ok(probe.path().with_file_name(name).exists())
+}
+
+/// The key under which names are compared
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum FoldedName {
+    Folded(String),
+    /// Names that are not valid unicode are compared as is
+    Raw(OsString),
+}
+
+fn fold_name(name: &OsStr) -> FoldedName {
+    match name.to_str() {
+        Some(name) => FoldedName::Folded(name.nfc().collect::<String>().to_lowercase()),
+        None => FoldedName::Raw(name.to_owned()),
+    }
+}
+
+/// Compute the stored names of all colliding items of a directory
+fn resolve_names(directory: &Path, is_root: bool) -> Result<HashMap<OsString, OsString>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(directory).map_err(|e| {
+        format!(
+            "resolve_names: cannot read directory {:?}: {}",
+            directory, e
+        )
+    })? {
+        let entry = entry.map_err(|e| format!("resolve_names: cannot read entry: {}", e))?;
+        names.push(entry.file_name());
+    }
+    names.sort();
+
+    let mut used = names
+        .iter()
+        .map(|name| fold_name(name))
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    if is_root {
+        // NOTE: the metadata directory itself is skipped by the backup
+        seen.insert(fold_name(OsStr::new(METADATA_DIR)));
+    }
+
+    let mut result = HashMap::new();
+    for name in names {
+        if is_root && name == METADATA_DIR {
+            continue;
+        }
+        if seen.insert(fold_name(&name)) {
+            continue;
+        }
+        let renamed = (1..)
+            .map(|idx| renamed(&name, idx))
+            .find(|candidate| !used.contains(&fold_name(candidate)))
+            .unwrap();
+        used.insert(fold_name(&renamed));
+        seen.insert(fold_name(&renamed));
+        result.insert(name, renamed);
+    }
+    Ok(result)
+}
+
+/// Insert `~idx` in front of the extension
+fn renamed(name: &OsStr, idx: usize) -> OsString {
+    let path = Path::new(name);
+    let mut result = path
+        .file_stem()
+        .map(OsStr::to_owned)
+        .unwrap_or_else(|| name.to_owned());
+    result.push(format!("~{}", idx));
+    if let Some(extension) = path.extension() {
+        result.push(".");
+        result.push(extension);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn colliding_names_are_renamed_deterministically() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "Readme.md"), None, None)?
+            .with_file(("source", "README.md"), None, None)?
+            .with_file(("source", "README~1.md"), None, None)?
+            .with_file(("source", "Docs", "a.txt"), None, None)?
+            .with_file(("source", "docs", "A.txt"), None, None)?
+            .with_file(("source", "caf\u{e9}"), None, None)?
+            .with_file(("source", "cafe\u{301}"), None, None)?;
+
+        let mut resolver = NameResolver::new(spec.path("source"), true);
+        let mut stored = |path: &Path, is_dir| resolver.stored_path(path, is_dir).unwrap();
+
+        assert_eq!(
+            stored(Path::new("README.md"), false),
+            Path::new("README.md")
+        );
+        assert_eq!(
+            stored(Path::new("README~1.md"), false),
+            Path::new("README~1.md")
+        );
+        assert_eq!(
+            stored(Path::new("Readme.md"), false),
+            Path::new("Readme~2.md")
+        );
+        assert_eq!(
+            stored(Path::new("cafe\u{301}"), false),
+            Path::new("cafe\u{301}")
+        );
+        assert_eq!(
+            stored(Path::new("caf\u{e9}"), false),
+            Path::new("caf\u{e9}~1")
+        );
+
+        assert_eq!(stored(Path::new("docs"), true), Path::new("docs~1"));
+        assert_eq!(
+            stored(&Path::new("docs").join("A.txt"), false),
+            Path::new("docs~1").join("A.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn names_are_unchanged_without_folding() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "Readme.md"), None, None)?
+            .with_file(("source", "README.md"), None, None)?;
+
+        let mut resolver = NameResolver::new(spec.path("source"), false);
+        assert_eq!(
+            resolver.stored_path(Path::new("Readme.md"), false)?,
+            Path::new("Readme.md")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+//! A structured log of all actions performed during a backup
+//!
+//! Each action is recorded as one JSON object per line in the metadata
+//! directory of the target. The events are also printed to stdout, either in
+//! a short human readable form or as JSON.
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+use tools_utils::{Error, Result};
+
+use super::backup::{ensure_directory_exists, BackupAction};
+use super::faults;
+use super::manifest::METADATA_DIR;
+
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// How events are printed to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EventFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl EventFormat {
+    /// Print a message that is not an event
+    ///
+    /// With JSON events, stdout only contains the events and all other
+    /// messages are printed to stderr.
+    pub fn print_message(self, message: impl std::fmt::Display) {
+        match self {
+            EventFormat::Text => println!("{}", message),
+            EventFormat::Json => eprintln!("{}", message),
+        }
+    }
+}
+
+/// A single action of the backup
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    /// The time of the event in RFC 3339 format
+    pub timestamp: String,
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Additional information, e.g., why an item was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Event {
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            action,
+            source: None,
+            target: None,
+            reference: None,
+            bytes: None,
+            error: None,
+            detail: None,
+        }
+    }
+
+    /// The event describing the backup of a single item
+    pub fn from_action(action: BackupAction) -> Self {
+        match action {
+            BackupAction::Copied {
+                bytes,
+                unstable,
+                link_limit,
+            } => {
+                let mut result = Self::new("copy");
+                result.bytes = Some(bytes);
+                if unstable {
+                    result.detail = Some(String::from("modified during copy"));
+                } else if link_limit {
+                    result.detail = Some(String::from("link limit reached"));
+                }
+                result
+            }
+            BackupAction::Linked => Self::new("link"),
+            BackupAction::Directory => Self::new("directory"),
+            BackupAction::Symlink => Self::new("symlink"),
+            BackupAction::Special { kind, recreated } => {
+                Self::new("special").with_detail(if recreated {
+                    kind.name().to_owned()
+                } else {
+                    format!("{}, metadata only", kind.name())
+                })
+            }
+            BackupAction::Unsupported => Self::new("skip").with_detail("unsupported file type"),
+        }
+    }
+
+    pub fn with_source(mut self, path: impl AsRef<Path>) -> Self {
+        self.source = Some(path.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn with_target(mut self, path: impl AsRef<Path>) -> Self {
+        self.target = Some(path.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn with_reference(mut self, path: Option<impl AsRef<Path>>) -> Self {
+        self.reference = path.map(|p| p.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn with_error(mut self, error: &Error) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// The short human readable form, e.g., `COPY "foo.txt"`
+    pub fn to_text(&self) -> String {
+        let tag = match self.action {
+            "copy" => "COPY",
+            "link" => "LINK",
+            "directory" => "DIR",
+            "symlink" => "SYM",
+            "special" => "SPECIAL",
+            "skip" => "SKIP",
+            "ignore" => "IGNORE",
+            "delete" => "DEL",
+            "trash" => "TRASH",
+            "error" => "ERROR",
+            other => other,
+        };
+        // NOTE: links are identified by their reference
+        let path = match self.action {
+            "link" => self.reference.as_ref(),
+            "skip" | "ignore" | "error" => self.source.as_ref(),
+            _ => self.target.as_ref(),
+        };
+
+        let mut result = format!("{:4}", tag);
+        if let Some(path) = path.or(self.source.as_ref()) {
+            result.push_str(&format!(" {:?}", path));
+        }
+        if let Some(detail) = &self.detail {
+            result.push_str(&format!(" [{}]", detail));
+        }
+        if let Some(error) = &self.error {
+            result.push_str(&format!(": {}", error));
+        }
+        result
+    }
+}
+
+/// Write events into the log file of a target and to stdout
+pub struct EventLog {
+    file: Option<BufWriter<File>>,
+    format: EventFormat,
+}
+
+impl EventLog {
+    /// Append events to the log file inside the metadata directory of the
+    /// target
+    pub fn open(target: impl AsRef<Path>, format: EventFormat) -> Result<Self> {
+        let directory = target.as_ref().join(METADATA_DIR);
+        ensure_directory_exists(&directory)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(EVENTS_FILE))
+            .map_err(|e| format!("EventLog: cannot open log file: {}", e))?;
+        Ok(Self {
+            file: Some(BufWriter::new(file)),
+            format,
+        })
+    }
+
+    /// Only print the events, e.g., for commands that do not create a target
+    pub fn print_only(format: EventFormat) -> Self {
+        Self { file: None, format }
+    }
+
+    pub fn format(&self) -> EventFormat {
+        self.format
+    }
+
+    pub fn record(&mut self, event: Event) -> Result<()> {
+        faults::check("write event")?;
+        let line = serde_json::to_string(&event)
+            .map_err(|e| format!("EventLog: cannot serialize event: {}", e))?;
+        match self.format {
+            EventFormat::Text => println!("{}", event.to_text()),
+            EventFormat::Json => println!("{}", line),
+        }
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", line)
+                .map_err(|e| format!("EventLog: cannot write log file: {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()
+                .map_err(|e| format!("EventLog: cannot write log file: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn events_are_written_to_the_log_file() -> Result<()> {
+        let spec = Spec::new()?.with_directory("target")?;
+        let mut log = EventLog::open(spec.path("target"), EventFormat::Json)?;
+        log.record(Event::new("ignore").with_source("foo"))?;
+        log.record(Event::new("directory").with_target("bar"))?;
+        log.flush()?;
+
+        let content = read_file(spec.path(("target", METADATA_DIR, EVENTS_FILE)))?;
+        let actions = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|event| event["action"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(actions, vec!["ignore", "directory"]);
+        Ok(())
+    }
+
+    #[test]
+    fn events_are_rendered_as_text_and_json() {
+        let event = Event::from_action(BackupAction::Copied {
+            bytes: 5,
+            unstable: false,
+            link_limit: true,
+        })
+        .with_source("source/foo.txt")
+        .with_target("target/foo.txt");
+
+        assert_eq!(
+            event.to_text(),
+            r#"COPY "target/foo.txt" [link limit reached]"#
+        );
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["action"], "copy");
+        assert_eq!(json["bytes"], 5);
+        assert_eq!(json["source"], "source/foo.txt");
+        assert!(json.get("error").is_none());
+    }
+}
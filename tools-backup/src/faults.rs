@@ -0,0 +1,107 @@
+//! Inject failures into the individual IO steps of a backup
+//!
+//! Each step that modifies the target calls `check` before it is performed.
+//! Chunked copies also call it after each written chunk, to simulate
+//! interruptions with partially written files. In tests, the step with a given
+//! number can be made to fail, simulating an IO error or an interruption at
+//! this point. Tests can also register a callback, that is called
+//! with the name of each step, e.g., to modify the source at a precise point.
+//! Steps checked with `check_io` can be made to fail with a specific OS error,
+//! e.g., to exercise the handling of full link counts. Outside of tests,
+//! `check` and `check_io` do nothing.
+#[cfg(not(test))]
+use std::io;
+#[cfg(not(test))]
+use tools_utils::Result;
+
+#[cfg(not(test))]
+#[inline(always)]
+pub fn check(_step: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub fn check_io(_step: &str) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub use self::injection::{check, check_io, count_steps, fail_at, fail_with_os_error, on_step};
+
+#[cfg(test)]
+mod injection {
+    use std::{
+        cell::{Cell, RefCell},
+        io,
+    };
+    use tools_utils::{Error, Result};
+
+    /// Called with the name of each step
+    pub type StepCallback = Box<dyn FnMut(&str)>;
+
+    // NOTE: tests run in separate threads, therefore the state is per thread
+    thread_local! {
+        static STEPS: Cell<u64> = const { Cell::new(0) };
+        static FAIL_AT: Cell<Option<u64>> = const { Cell::new(None) };
+        static ON_STEP: RefCell<Option<StepCallback>> = const { RefCell::new(None) };
+        static OS_ERROR: RefCell<Option<(String, i32)>> = const { RefCell::new(None) };
+    }
+
+    pub fn check(step: &str) -> Result<()> {
+        ON_STEP.with(|on_step| {
+            if let Some(callback) = on_step.borrow_mut().as_mut() {
+                callback(step);
+            }
+        });
+        let current = STEPS.with(|steps| {
+            let current = steps.get();
+            steps.set(current + 1);
+            current
+        });
+        if FAIL_AT.with(Cell::get) == Some(current) {
+            return Err(Error::from(format!(
+                "injected fault at step {} ({})",
+                current, step
+            )));
+        }
+        Ok(())
+    }
+
+    /// Like `check`, but fail with an IO error
+    pub fn check_io(step: &str) -> io::Result<()> {
+        check(step).map_err(|e| io::Error::other(e.to_string()))?;
+        let code = OS_ERROR.with(|os_error| match &*os_error.borrow() {
+            Some((name, code)) if name == step => Some(*code),
+            _ => None,
+        });
+        match code {
+            Some(code) => Err(io::Error::from_raw_os_error(code)),
+            None => Ok(()),
+        }
+    }
+
+    /// Let all steps with the given name fail with the OS error, until replaced
+    pub fn fail_with_os_error(step: Option<(&str, i32)>) {
+        let step = step.map(|(name, code)| (name.to_owned(), code));
+        OS_ERROR.with(|os_error| *os_error.borrow_mut() = step);
+    }
+
+    /// Let the step with the given number fail, counting from zero
+    pub fn fail_at(step: Option<u64>) {
+        STEPS.with(|steps| steps.set(0));
+        FAIL_AT.with(|fail_at| fail_at.set(step));
+    }
+
+    /// Call the callback before each step, until it is replaced
+    pub fn on_step(callback: Option<StepCallback>) {
+        ON_STEP.with(|on_step| *on_step.borrow_mut() = callback);
+    }
+
+    /// Count the steps performed by the function without injecting faults
+    pub fn count_steps<T>(func: impl FnOnce() -> T) -> (u64, T) {
+        fail_at(None);
+        let result = func();
+        (STEPS.with(Cell::get), result)
+    }
+}
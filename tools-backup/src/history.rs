@@ -0,0 +1,270 @@
+//! Find all versions of a single item across the snapshots of a repository
+//!
+//! Snapshots share unchanged files via hard-links. Therefore, versions are
+//! first deduplicated by their inode. Items with different inodes, but
+//! identical content, e.g., after the item was copied again, are detected by
+//! comparing SHA-256 digests of their content.
+use chrono::{Local, TimeZone};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+
+use super::manifest::EntryKind;
+use super::snapshot::{self, SnapshotEntry};
+
+/// A distinct version of an item
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub kind: EntryKind,
+    pub size: u64,
+    /// The modification time in seconds since the epoch
+    pub mtime: i64,
+    pub link_target: Option<PathBuf>,
+    /// The stored item of the first snapshot containing this version
+    pub data_path: PathBuf,
+    /// The names of all snapshots that contain this version
+    pub snapshots: Vec<String>,
+}
+
+/// Find all distinct versions of the item, sorted by their first occurrence
+pub fn find_versions(repository: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<Vec<Version>> {
+    let path = path.as_ref();
+    let mut versions = Vec::<Version>::new();
+    let mut by_identity = HashMap::<Identity, usize>::new();
+
+    for snapshot in snapshot::list_snapshots(repository)? {
+        let entry = match snapshot::find_entry(&snapshot, path)? {
+            Some(entry) if matches!(entry.kind, EntryKind::File | EntryKind::Symlink) => entry,
+            _ => continue,
+        };
+        let name = snapshot
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let inode = inode_identity(&entry.data_path)?;
+        if let Some(&idx) = inode.as_ref().and_then(|id| by_identity.get(id)) {
+            versions[idx].snapshots.push(name);
+            continue;
+        }
+
+        let content = content_identity(&entry)?;
+        let idx = match by_identity.get(&content) {
+            Some(&idx) => {
+                versions[idx].snapshots.push(name);
+                idx
+            }
+            None => {
+                versions.push(Version {
+                    kind: entry.kind,
+                    size: entry.size,
+                    mtime: entry.mtime,
+                    link_target: entry.link_target.clone(),
+                    data_path: entry.data_path.clone(),
+                    snapshots: vec![name],
+                });
+                by_identity.insert(content, versions.len() - 1);
+                versions.len() - 1
+            }
+        };
+        if let Some(inode) = inode {
+            by_identity.insert(inode, idx);
+        }
+    }
+    Ok(versions)
+}
+
+/// Restore a version of the item
+///
+/// If `output` is an existing directory, the item is restored inside it using
+/// the given file name. Existing files are never overwritten.
+pub fn restore_version(version: &Version, file_name: &Path, output: &Path) -> Result<PathBuf> {
+    let output = if output.is_dir() {
+        output.join(
+            file_name
+                .file_name()
+                .ok_or_else(|| Error::from("restore_version: invalid file name"))?,
+        )
+    } else {
+        output.to_owned()
+    };
+
+    // NOTE: the output is created exclusively, so existing files, even if
+    // created concurrently, are never overwritten
+    match (version.kind, &version.link_target) {
+        (EntryKind::Symlink, Some(link_target)) => restore_symlink(link_target, &output)?,
+        _ => {
+            let mut source = File::open(&version.data_path)
+                .map_err(|e| format!("restore_version: cannot open file: {}", e))?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&output)
+                .map_err(|e| create_error(&output, e))?;
+            io::copy(&mut source, &mut file)
+                .map_err(|e| format!("restore_version: could not copy file: {}", e))?;
+            drop(file);
+            let permissions = source
+                .metadata()
+                .map_err(|e| format!("restore_version: could not retrieve metadata: {}", e))?
+                .permissions();
+            fs::set_permissions(&output, permissions)
+                .map_err(|e| format!("restore_version: could not set permissions: {}", e))?;
+            let mtime = version.mtime.max(0) as u64;
+            utime::set_file_times(&output, mtime, mtime)
+                .map_err(|e| format!("restore_version: could not set mtime: {}", e))?;
+        }
+    }
+    Ok(output)
+}
+
+fn create_error(output: &Path, error: io::Error) -> Error {
+    if error.kind() == ErrorKind::AlreadyExists {
+        Error::from(format!("restore_version: {:?} already exists", output))
+    } else {
+        Error::from(format!(
+            "restore_version: cannot create {:?}: {}",
+            output, error
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn restore_symlink(link_target: &Path, output: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(link_target, output).map_err(|e| create_error(output, e))
+}
+
+#[cfg(not(unix))]
+fn restore_symlink(link_target: &Path, _output: &Path) -> Result<()> {
+    Err(Error::from(format!(
+        "restore_version: cannot restore symlink to {:?} on this platform",
+        link_target
+    )))
+}
+
+/// Print the versions as a table
+pub fn print_versions(versions: &[Version]) {
+    println!(
+        "{:>7}  {:>12}  {:19}  {:20}  {:20}  {:>9}",
+        "VERSION", "SIZE", "MTIME", "FIRST", "LAST", "SNAPSHOTS"
+    );
+    for (idx, version) in versions.iter().enumerate() {
+        println!(
+            "{:>7}  {:>12}  {:19}  {:20}  {:20}  {:>9}",
+            idx + 1,
+            version.size,
+            format_time(version.mtime),
+            version.snapshots.first().map(String::as_str).unwrap_or(""),
+            version.snapshots.last().map(String::as_str).unwrap_or(""),
+            version.snapshots.len(),
+        );
+    }
+}
+
+pub fn format_time(seconds: i64) -> String {
+    Local
+        .timestamp_opt(seconds, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| seconds.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Identity {
+    Inode(u64, u64),
+    Content(EntryKind, u64, [u8; 32]),
+}
+
+#[cfg(unix)]
+fn inode_identity(path: &Path) -> Result<Option<Identity>> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = path
+        .metadata()
+        .map_err(|e| format!("inode_identity: could not retrieve metadata: {}", e))?;
+    Ok(Some(Identity::Inode(metadata.dev(), metadata.ino())))
+}
+
+#[cfg(not(unix))]
+fn inode_identity(_path: &Path) -> Result<Option<Identity>> {
+    Ok(None)
+}
+
+fn content_identity(entry: &SnapshotEntry) -> Result<Identity> {
+    let mut hasher = Sha256::new();
+    if let Some(link_target) = &entry.link_target {
+        hasher.update(link_target.to_string_lossy().as_bytes());
+        return Ok(Identity::Content(entry.kind, 0, hasher.finalize().into()));
+    }
+
+    let mut file = File::open(&entry.data_path)
+        .map_err(|e| format!("content_identity: cannot open file: {}", e))?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .map_err(|e| format!("content_identity: cannot read file: {}", e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok(Identity::Content(
+        entry.kind,
+        size,
+        hasher.finalize().into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn find_versions_deduplicates_by_inode_and_content() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("repo", "2020-01", "report.txt"), Some("v1"), None)?
+            .with_file(("repo", "2020-03", "report.txt"), Some("v1"), None)?
+            .with_file(("repo", "2020-04", "report.txt"), Some("v2"), None)?
+            .with_directory(("repo", "2020-05"))?
+            .with_directory(("repo", "2020-06"))?;
+        fs::hard_link(
+            spec.path(("repo", "2020-04", "report.txt")),
+            spec.path(("repo", "2020-06", "report.txt")),
+        )
+        .unwrap();
+
+        let versions = find_versions(spec.path("repo"), "report.txt")?;
+
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].snapshots, vec!["2020-01", "2020-03"]);
+        assert_eq!(versions[1].snapshots, vec!["2020-04", "2020-06"]);
+        assert_eq!(versions[1].size, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_version_copies_the_file() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("repo", "2020-01", "docs", "report.txt"), Some("v1"), None)?
+            .with_directory("out")?;
+
+        let versions = find_versions(spec.path("repo"), Path::new("docs").join("report.txt"))?;
+        let restored = restore_version(&versions[0], Path::new("report.txt"), &spec.path("out"))?;
+
+        assert_eq!(restored, spec.path(("out", "report.txt")));
+        assert_eq!(read_file(&restored)?, "v1");
+        let err = restore_version(&versions[0], Path::new("report.txt"), &spec.path("out"))
+            .expect_err("existing files must not be overwritten");
+        assert!(err.to_string().contains("already exists"));
+        assert_eq!(read_file(&restored)?, "v1");
+        Ok(())
+    }
+}
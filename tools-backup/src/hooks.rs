@@ -0,0 +1,258 @@
+//! Commands executed before and after a backup
+//!
+//! The commands are executed via the system shell. Information about the
+//! backup is passed via environment variables prefixed with `TOOLS_BACKUP_`.
+use std::{
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+use tools_utils::{Error, Result};
+
+use super::backup::BackupReport;
+use super::events::EventFormat;
+
+/// The point of the backup at which a hook is executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookStage {
+    PreBackup,
+    PostBackup,
+}
+
+impl HookStage {
+    fn name(self) -> &'static str {
+        match self {
+            HookStage::PreBackup => "pre-backup",
+            HookStage::PostBackup => "post-backup",
+        }
+    }
+}
+
+/// The configured hooks of a backup job
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub pre_backup: Vec<String>,
+    pub post_backup: Vec<String>,
+    /// If true, a failing hook aborts the backup, otherwise only a warning is
+    /// printed
+    pub abort_on_failure: bool,
+    /// With JSON events, the output of the hooks is redirected to stderr
+    pub event_format: EventFormat,
+}
+
+/// Information about the backup passed to the hooks
+pub struct HookContext<'a> {
+    pub source: &'a Path,
+    pub target: &'a Path,
+    pub reference: Option<&'a Path>,
+    /// The result of the backup, only available for post-backup hooks
+    pub result: Option<&'a Result<BackupReport>>,
+}
+
+impl Hooks {
+    /// Execute all hooks of the given stage
+    pub fn run(&self, stage: HookStage, context: &HookContext) -> Result<()> {
+        let commands = match stage {
+            HookStage::PreBackup => &self.pre_backup,
+            HookStage::PostBackup => &self.post_backup,
+        };
+        for command in commands {
+            self.event_format
+                .print_message(format!("HOOK {} {:?}", stage.name(), command));
+            if let Err(e) = run_hook(stage, command, context, self.event_format) {
+                if self.abort_on_failure {
+                    return Err(e);
+                }
+                eprintln!("Ignore failed hook: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the post-backup hooks and return the result of the backup
+    ///
+    /// The hooks are executed even if the backup failed. If both fail, the
+    /// returned error contains both messages.
+    pub fn run_post_backup(
+        &self,
+        context: HookContext,
+        result: Result<BackupReport>,
+    ) -> Result<BackupReport> {
+        let context = HookContext {
+            result: Some(&result),
+            ..context
+        };
+        let hook_result = self.run(HookStage::PostBackup, &context);
+        match (result, hook_result) {
+            (Err(e), Err(hook_error)) => {
+                Err(Error::from(format!("{} (in addition, {})", e, hook_error)))
+            }
+            (Ok(_), Err(hook_error)) => Err(hook_error),
+            (result, Ok(())) => result,
+        }
+    }
+}
+
+fn run_hook(
+    stage: HookStage,
+    command: &str,
+    context: &HookContext,
+    event_format: EventFormat,
+) -> Result<()> {
+    let mut process = shell_command(command);
+    for (key, value) in hook_environment(stage, context) {
+        process.env(key, value);
+    }
+    if event_format == EventFormat::Json {
+        process.stdout(Stdio::from(io::stderr()));
+    }
+
+    let status = process
+        .status()
+        .map_err(|e| format!("run_hook: could not execute {:?}: {}", command, e))?;
+    if !status.success() {
+        return Err(Error::from(format!(
+            "run_hook: {} hook {:?} failed with {}",
+            stage.name(),
+            command,
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut result = Command::new("sh");
+    result.arg("-c").arg(command);
+    result
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> Command {
+    let mut result = Command::new("cmd");
+    result.arg("/C").arg(command);
+    result
+}
+
+fn hook_environment(stage: HookStage, context: &HookContext) -> Vec<(&'static str, String)> {
+    let path = |p: &Path| p.to_string_lossy().into_owned();
+
+    let mut result = vec![
+        ("TOOLS_BACKUP_STAGE", stage.name().to_owned()),
+        ("TOOLS_BACKUP_SOURCE", path(context.source)),
+        ("TOOLS_BACKUP_TARGET", path(context.target)),
+        (
+            "TOOLS_BACKUP_REFERENCE",
+            context.reference.map(path).unwrap_or_default(),
+        ),
+    ];
+
+    match context.result {
+        None => {}
+        Some(Err(e)) => {
+            result.push(("TOOLS_BACKUP_STATUS", String::from("failure")));
+            result.push(("TOOLS_BACKUP_ERROR", e.to_string()));
+        }
+        Some(Ok(report)) => {
+            result.push(("TOOLS_BACKUP_STATUS", String::from("success")));
+            result.push(("TOOLS_BACKUP_COPIED_FILES", report.copied_files.to_string()));
+            result.push(("TOOLS_BACKUP_COPIED_BYTES", report.copied_bytes.to_string()));
+            result.push(("TOOLS_BACKUP_LINKED_FILES", report.linked_files.to_string()));
+            result.push(("TOOLS_BACKUP_DIRECTORIES", report.directories.to_string()));
+            result.push(("TOOLS_BACKUP_SYMLINKS", report.symlinks.to_string()));
+            result.push(("TOOLS_BACKUP_SKIPPED", report.skipped.to_string()));
+            result.push(("TOOLS_BACKUP_IGNORED", report.ignored.to_string()));
+            result.push(("TOOLS_BACKUP_DELETED", report.deleted.to_string()));
+            result.push((
+                "TOOLS_BACKUP_SPECIAL_FILES",
+                report.special_files.len().to_string(),
+            ));
+            result.push((
+                "TOOLS_BACKUP_UNSTABLE_FILES",
+                report.unstable_files.len().to_string(),
+            ));
+        }
+    }
+    result
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn hooks_receive_backup_information() -> Result<()> {
+        let spec = Spec::new()?.with_directory("out")?;
+        let out = spec.path(("out", "env.txt"));
+
+        let hooks = Hooks {
+            post_backup: vec![format!(
+                "echo $TOOLS_BACKUP_STAGE $TOOLS_BACKUP_STATUS $TOOLS_BACKUP_COPIED_FILES > {:?}",
+                out
+            )],
+            ..Hooks::default()
+        };
+        let result = Ok(BackupReport {
+            copied_files: 3,
+            ..BackupReport::default()
+        });
+        let context = HookContext {
+            source: Path::new("source"),
+            target: Path::new("target"),
+            reference: None,
+            result: Some(&result),
+        };
+        hooks.run(HookStage::PostBackup, &context)?;
+
+        assert_eq!(read_file(&out)?, "post-backup success 3\n");
+        Ok(())
+    }
+
+    #[test]
+    fn failing_hooks_abort_only_if_configured() -> Result<()> {
+        let context = HookContext {
+            source: Path::new("source"),
+            target: Path::new("target"),
+            reference: None,
+            result: None,
+        };
+        let mut hooks = Hooks {
+            pre_backup: vec![String::from("exit 1")],
+            ..Hooks::default()
+        };
+        assert!(hooks.run(HookStage::PreBackup, &context).is_ok());
+
+        hooks.abort_on_failure = true;
+        assert!(hooks.run(HookStage::PreBackup, &context).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn backup_errors_are_kept_if_post_backup_hooks_fail() {
+        let context = || HookContext {
+            source: Path::new("source"),
+            target: Path::new("target"),
+            reference: None,
+            result: None,
+        };
+        let hooks = Hooks {
+            post_backup: vec![String::from("exit 1")],
+            abort_on_failure: true,
+            ..Hooks::default()
+        };
+
+        let error = hooks
+            .run_post_backup(context(), Err(Error::from("backup failed")))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("backup failed"));
+        assert!(error.contains("post-backup hook"));
+
+        let error = hooks
+            .run_post_backup(context(), Ok(BackupReport::default()))
+            .unwrap_err();
+        assert!(error.to_string().contains("post-backup hook"));
+    }
+}
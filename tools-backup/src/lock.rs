@@ -0,0 +1,535 @@
+//! Advisory locks to prevent concurrent backups into the same repository
+//!
+//! The repository is the directory that contains the individual snapshots. The
+//! lock is a file inside the repository that records which process holds it.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tools_utils::{Error, Result};
+
+use super::faults;
+
+pub const LOCK_FILE_NAME: &str = ".tools-backup.lock";
+
+/// Locks held by processes on other hosts are considered stale after this time
+const STALE_LOCK_AGE_SECONDS: u64 = 2 * 24 * 60 * 60;
+
+/// Lock files that cannot be parsed are considered stale after this time
+///
+/// Lock files are moved into place completely written, but may be damaged,
+/// e.g., by a crash of the filesystem.
+const INVALID_LOCK_AGE_SECONDS: u64 = 60;
+
+/// A lock on a backup repository, released when dropped
+pub struct RepositoryLock {
+    path: PathBuf,
+}
+
+impl RepositoryLock {
+    /// Acquire the lock on the repository
+    ///
+    /// Stale locks, i.e., locks of processes that are no longer running, are
+    /// removed automatically.
+    pub fn acquire(repository: impl AsRef<Path>) -> Result<Self> {
+        let path = repository.as_ref().join(LOCK_FILE_NAME);
+        let info = LockInfo::current()?;
+
+        // NOTE: the lock is written completely before it is linked into place,
+        // so other processes never see a partial lock file
+        let content = info.format();
+        let temp_path = unique_path(&path, &info, "tmp");
+        fs::write(&temp_path, &content)
+            .map_err(|e| format!("RepositoryLock: cannot write lock file: {}", e))?;
+        let result = Self::link_lock(&path, &temp_path, &content, &info);
+        if let Err(e) = fs::remove_file(&temp_path) {
+            eprintln!("Could not remove temporary lock {:?}: {}", temp_path, e);
+        }
+        result
+    }
+
+    fn link_lock(path: &Path, temp_path: &Path, content: &str, info: &LockInfo) -> Result<Self> {
+        for _ in 0..3 {
+            match place_lock(path, temp_path, content) {
+                Ok(()) => {
+                    return Ok(Self {
+                        path: path.to_owned(),
+                    })
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let content = match read_lock_file(path)? {
+                        Some(content) => content,
+                        // NOTE: released in the meantime
+                        None => continue,
+                    };
+                    check_stale(path, &content, modified_age(path), info)?;
+                    remove_stale_lock(path, &content, info)?;
+                }
+                Err(e) => {
+                    return Err(Error::from(format!(
+                        "RepositoryLock: cannot create lock file {:?}: {}",
+                        path, e
+                    )))
+                }
+            }
+        }
+        Err(Error::from(format!(
+            "RepositoryLock: could not acquire lock {:?}",
+            path
+        )))
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Could not release lock {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Place the lock file with the content of `source`, failing if a lock exists
+///
+/// The lock is hard-linked into place. Filesystems without hard-links, e.g.,
+/// FAT, get the lock written in place instead. Other processes may then see a
+/// partial lock, which is treated as invalid and only removed after a grace
+/// period.
+fn place_lock(path: &Path, source: &Path, content: &str) -> io::Result<()> {
+    match faults::check_io("link lock").and_then(|()| fs::hard_link(source, path)) {
+        Err(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::Unsupported | ErrorKind::PermissionDenied
+            ) =>
+        {
+            let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+            file.write_all(content.as_bytes())
+        }
+        result => result,
+    }
+}
+
+/// Return an error, unless the lock with the given content is stale
+///
+/// Used for lock files that are not accessed via the local filesystem. The
+/// age is the time since the lock file was modified.
+pub fn check_stale_lock(path: impl AsRef<Path>, content: &str, age: Duration) -> Result<()> {
+    check_stale(path.as_ref(), content, age, &LockInfo::current()?)
+}
+
+/// Return an error, unless the existing lock is stale
+fn check_stale(path: &Path, content: &str, age: Duration, current: &LockInfo) -> Result<()> {
+    match LockInfo::parse(content) {
+        Some(existing) if existing.is_stale(current) => {
+            eprintln!("Remove stale lock {:?}", path);
+            Ok(())
+        }
+        Some(existing) => Err(existing.locked_error(path)),
+        None if age > Duration::from_secs(INVALID_LOCK_AGE_SECONDS) => {
+            eprintln!("Remove invalid lock {:?}", path);
+            Ok(())
+        }
+        None => Err(Error::from(format!(
+            "RepositoryLock: invalid lock file {:?}. If no backup is running, remove it",
+            path
+        ))),
+    }
+}
+
+/// The time since the file was modified, zero if unknown
+fn modified_age(path: &Path) -> Duration {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .unwrap_or_default()
+}
+
+/// A path next to the lock, unique to the current process
+fn unique_path(path: &Path, current: &LockInfo, extension: &str) -> PathBuf {
+    let mut result = path.as_os_str().to_owned();
+    result.push(format!(
+        ".{}-{}.{}",
+        current.hostname, current.pid, extension
+    ));
+    PathBuf::from(result)
+}
+
+/// Read the lock file, if it exists
+fn read_lock_file(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::from(format!(
+            "RepositoryLock: cannot read lock file {:?}: {}",
+            path, e
+        ))),
+    }
+}
+
+/// Remove the stale lock, unless it was replaced in the meantime
+///
+/// Another process may have judged the same lock stale and already replaced
+/// it with its own lock. Therefore the lock is first moved to a name unique to
+/// this process, which is atomic, and only removed if it is still the stale
+/// lock. Otherwise it is moved back. Returns whether the stale lock was
+/// removed.
+fn remove_stale_lock(path: &Path, stale: &str, current: &LockInfo) -> Result<bool> {
+    let claimed = unique_path(path, current, "stale");
+
+    match fs::rename(path, &claimed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => {
+            return Err(Error::from(format!(
+                "RepositoryLock: cannot move stale lock file: {}",
+                e
+            )))
+        }
+    }
+
+    let claimed_content = read_lock_file(&claimed)?.unwrap_or_default();
+    if claimed_content == stale {
+        fs::remove_file(&claimed)
+            .map_err(|e| format!("RepositoryLock: cannot remove stale lock file: {}", e))?;
+        return Ok(true);
+    }
+
+    // NOTE: placing the lock fails instead of replacing a lock created meanwhile
+    let restored = place_lock(path, &claimed, &claimed_content);
+    fs::remove_file(&claimed)
+        .map_err(|e| format!("RepositoryLock: cannot remove moved lock file: {}", e))?;
+    if let Err(e) = restored {
+        if e.kind() != ErrorKind::AlreadyExists {
+            return Err(Error::from(format!(
+                "RepositoryLock: cannot restore lock file {:?}: {}",
+                path, e
+            )));
+        }
+    }
+    Ok(false)
+}
+
+/// Return an error if the repository is locked by another running process
+pub fn ensure_unlocked(repository: impl AsRef<Path>) -> Result<()> {
+    let path = repository.as_ref().join(LOCK_FILE_NAME);
+    let content = match read_lock_file(&path)? {
+        Some(content) => content,
+        None => return Ok(()),
+    };
+    let current = LockInfo::current()?;
+    match LockInfo::parse(&content) {
+        Some(existing) if existing.pid == current.pid && existing.hostname == current.hostname => {
+            Ok(())
+        }
+        Some(existing) if !existing.is_stale(&current) => Err(existing.locked_error(&path)),
+        Some(_) => Ok(()),
+        None => check_stale(&path, &content, modified_age(&path), &current),
+    }
+}
+
+/// The content of a lock file held by the current process
+///
+/// Used to lock repositories that are not accessed via the local filesystem.
+pub fn lock_file_content() -> Result<String> {
+    Ok(LockInfo::current()?.format())
+}
+
+/// The repository of a snapshot, i.e., its parent directory
+pub fn repository_of(snapshot: &Path) -> &Path {
+    match snapshot.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => snapshot,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct LockInfo {
+    pid: u32,
+    hostname: String,
+    timestamp: u64,
+}
+
+impl LockInfo {
+    fn current() -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("LockInfo: could not determine time since epoch: {}", e))?
+            .as_secs();
+        Ok(Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            timestamp,
+        })
+    }
+
+    #[cfg(test)]
+    fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("LockInfo: cannot read lock file {:?}: {}", path, e))?;
+        Self::parse(&content)
+            .ok_or_else(|| Error::from(format!("LockInfo: invalid lock file {:?}", path)))
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut hostname = None;
+        let mut timestamp = None;
+
+        for line in s.lines() {
+            let (key, value) = match line.find(':') {
+                Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+                None => continue,
+            };
+            match key {
+                "pid" => pid = value.parse().ok(),
+                "hostname" => hostname = Some(value.to_owned()),
+                "timestamp" => timestamp = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            pid: pid?,
+            hostname: hostname?,
+            timestamp: timestamp?,
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "pid: {}\nhostname: {}\ntimestamp: {}\n",
+            self.pid, self.hostname, self.timestamp
+        )
+    }
+
+    /// Check whether the lock is stale, as seen from the current process
+    fn is_stale(&self, current: &LockInfo) -> bool {
+        if self.hostname == current.hostname {
+            !is_process_running(self.pid)
+        } else {
+            current.timestamp.saturating_sub(self.timestamp) > STALE_LOCK_AGE_SECONDS
+        }
+    }
+
+    fn locked_error(&self, path: &Path) -> Error {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(self.timestamp);
+        let age = now.saturating_sub(self.timestamp);
+        Error::from(format!(
+            concat!(
+                "The repository is locked by process {} on host {:?} (since {}h {}m). ",
+                "If no backup is running, remove the lock file {:?}"
+            ),
+            self.pid,
+            self.hostname,
+            age / 3600,
+            (age % 3600) / 60,
+            path,
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn is_process_running(pid: u32) -> bool {
+    if pid == 0 || pid > i32::MAX as u32 {
+        return false;
+    }
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM: the process exists, but belongs to a different user
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn is_process_running(pid: u32) -> bool {
+    use winapi::shared::winerror::ERROR_ACCESS_DENIED;
+    use winapi::um::{
+        handleapi::CloseHandle,
+        minwinbase::STILL_ACTIVE,
+        processthreadsapi::{GetExitCodeProcess, OpenProcess},
+        winnt::PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    if pid == 0 {
+        return false;
+    }
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        // ERROR_ACCESS_DENIED: the process exists, but belongs to a different user
+        return std::io::Error::last_os_error().raw_os_error() == Some(ERROR_ACCESS_DENIED as i32);
+    }
+    let mut exit_code = 0;
+    let result = unsafe { GetExitCodeProcess(handle, &mut exit_code) };
+    unsafe { CloseHandle(handle) };
+    result == 0 || exit_code == STILL_ACTIVE
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_process_running(pid: u32) -> bool {
+    // NOTE: without a cheap way to check, assume the process is running
+    pid != 0
+}
+
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return String::from("unknown");
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive_and_released_on_drop() -> Result<()> {
+        let spec = Spec::new()?.with_directory("repo")?;
+
+        let lock = RepositoryLock::acquire(spec.path("repo"))?;
+        let err = RepositoryLock::acquire(spec.path("repo"))
+            .err()
+            .expect("second lock must fail");
+        assert!(err.to_string().contains("is locked by process"));
+        assert!(ensure_unlocked(spec.path("repo")).is_ok());
+
+        drop(lock);
+        assert!(!spec.path(("repo", LOCK_FILE_NAME)).exists());
+        RepositoryLock::acquire(spec.path("repo"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn stale_locks_are_replaced() -> Result<()> {
+        let stale = LockInfo {
+            pid: 999_999_999,
+            hostname: hostname(),
+            timestamp: 0,
+        };
+        let spec = Spec::new()?.with_file(("repo", LOCK_FILE_NAME), Some(&stale.format()), None)?;
+
+        assert!(ensure_unlocked(spec.path("repo")).is_ok());
+        let _lock = RepositoryLock::acquire(spec.path("repo"))?;
+        assert_eq!(
+            LockInfo::read(&spec.path(("repo", LOCK_FILE_NAME)))?.pid,
+            std::process::id()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replaced_stale_locks_are_kept() -> Result<()> {
+        let stale = LockInfo {
+            pid: 999_999_999,
+            hostname: hostname(),
+            timestamp: 0,
+        };
+        let current = LockInfo::current()?;
+        let fresh = LockInfo {
+            pid: current.pid + 1,
+            ..LockInfo::current()?
+        };
+
+        // NOTE: another process replaced the stale lock after it was read
+        let spec = Spec::new()?.with_file(("repo", LOCK_FILE_NAME), Some(&fresh.format()), None)?;
+        let path = spec.path(("repo", LOCK_FILE_NAME));
+        let stale = stale.format();
+        assert!(!remove_stale_lock(&path, &stale, &current)?);
+        assert_eq!(LockInfo::read(&path)?, fresh);
+        assert_eq!(fs::read_dir(spec.path("repo")).unwrap().count(), 1);
+
+        fs::write(&path, &stale).unwrap();
+        assert!(remove_stale_lock(&path, &stale, &current)?);
+        assert!(!path.exists());
+        assert!(!remove_stale_lock(&path, &stale, &current)?);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_locks_are_stale_after_a_grace_period() -> Result<()> {
+        // NOTE: e.g., left behind empty by a crash
+        let spec = Spec::new()?.with_directory("fresh")?.with_file(
+            ("old", LOCK_FILE_NAME),
+            Some(""),
+            Some(0),
+        )?;
+        fs::write(spec.path(("fresh", LOCK_FILE_NAME)), "").unwrap();
+
+        let err = RepositoryLock::acquire(spec.path("fresh"))
+            .err()
+            .expect("fresh invalid locks must not be removed");
+        assert!(err.to_string().contains("invalid lock file"));
+        assert!(ensure_unlocked(spec.path("fresh")).is_err());
+
+        assert!(ensure_unlocked(spec.path("old")).is_ok());
+        let _lock = RepositoryLock::acquire(spec.path("old"))?;
+        assert_eq!(
+            LockInfo::read(&spec.path(("old", LOCK_FILE_NAME)))?.pid,
+            std::process::id()
+        );
+        // NOTE: only the lock itself is left in the repository
+        assert_eq!(fs::read_dir(spec.path("old")).unwrap().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn locks_are_written_in_place_without_hard_links() -> Result<()> {
+        #[cfg(unix)]
+        let not_supported = libc::EOPNOTSUPP;
+        #[cfg(windows)]
+        let not_supported = 50; // ERROR_NOT_SUPPORTED
+
+        let spec = Spec::new()?.with_directory("repo")?;
+        faults::fail_with_os_error(Some(("link lock", not_supported)));
+        let lock = RepositoryLock::acquire(spec.path("repo"));
+        let second = RepositoryLock::acquire(spec.path("repo"));
+        faults::fail_with_os_error(None);
+
+        let lock = lock?;
+        assert_eq!(
+            LockInfo::read(&spec.path(("repo", LOCK_FILE_NAME)))?.pid,
+            std::process::id()
+        );
+        let err = second.err().expect("second lock must fail");
+        assert!(err.to_string().contains("is locked by process"));
+        // NOTE: only the lock itself is left in the repository
+        assert_eq!(fs::read_dir(spec.path("repo")).unwrap().count(), 1);
+
+        drop(lock);
+        assert!(!spec.path(("repo", LOCK_FILE_NAME)).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn running_processes_are_detected() {
+        assert!(is_process_running(std::process::id()));
+        assert!(!is_process_running(999_999_999));
+        assert!(!is_process_running(0));
+    }
+
+    #[test]
+    fn lock_info_roundtrip() {
+        let info = LockInfo {
+            pid: 42,
+            hostname: String::from("host"),
+            timestamp: 1_600_000_000,
+        };
+        assert_eq!(LockInfo::parse(&info.format()), Some(info));
+    }
+}
@@ -1,45 +1,443 @@
 /// Helper to handle backups in windows
+mod archive;
 mod backup;
+mod collision;
+mod events;
+mod faults;
+mod history;
+mod hooks;
+mod lock;
+mod manifest;
+mod mirror;
+#[cfg(target_os = "linux")]
+mod mount;
+mod prune;
+mod push;
 mod sanitize_path;
+mod sftp;
+mod snapshot;
+mod source_snapshot;
+mod stats;
+mod tags;
+#[cfg(test)]
 mod test_spec;
+mod throttle;
+mod watch;
 
-use clap::{App, Arg};
-use std::path::{Path, PathBuf};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tools_utils::{run_main, Result};
 
-use backup::{GlobIgnoreSpec, IgnoreSpec, NoOpIgnoreSpec};
+use backup::{BackupOptions, GlobIgnoreSpec, IgnoreSpec, MarkerIgnoreSpec, NoOpIgnoreSpec};
+use events::EventFormat;
+use hooks::{HookContext, HookStage, Hooks};
+use lock::RepositoryLock;
+use push::RemotePath;
+use sftp::SftpClient;
+use source_snapshot::{SnapshotMethod, SourceSnapshot};
+use throttle::{IoPriority, Throttle};
 
 fn main() {
     run_main(main_impl);
 }
 
 fn main_impl() -> Result<i32> {
-    let arguments = parse_args()?;
+    match parse_args()? {
+        Command::Backup(arguments) => backup_main(arguments),
+        Command::Mount(arguments) => mount_main(arguments),
+        Command::History(arguments) => history_main(arguments),
+        Command::Export(arguments) => export_main(arguments),
+        Command::Import(arguments) => import_main(arguments),
+        Command::Push(arguments) => push_main(arguments),
+        Command::Watch(arguments) => watch_main(arguments),
+        Command::Stats(arguments) => stats_main(arguments),
+        Command::Tag(arguments) => tag_main(arguments),
+        Command::List(arguments) => list_main(arguments),
+        Command::Prune(arguments) => prune_main(arguments),
+    }
+}
+
+/// With JSON events, stdout only contains the events
+fn event_format(log_json: bool) -> EventFormat {
+    if log_json {
+        EventFormat::Json
+    } else {
+        EventFormat::Text
+    }
+}
+
+fn backup_main(arguments: BackupArguments) -> Result<i32> {
+    let event_format = event_format(arguments.options.log_json);
+    macro_rules! info {
+        ($($arg:tt)*) => {
+            match event_format {
+                EventFormat::Text => println!($($arg)*),
+                EventFormat::Json => eprintln!($($arg)*),
+            }
+        };
+    }
 
-    println!("Run backup");
-    println!("Source: {:?}", arguments.source);
-    println!("Target: {:?}", arguments.target);
+    if arguments.mirror {
+        info!("Run mirror");
+    } else {
+        info!("Run backup");
+    }
+    info!("Source: {:?}", arguments.source);
+    info!("Target: {:?}", arguments.target);
     if let Some(reference) = &arguments.reference {
-        println!("With reference: {:?}", reference);
+        info!("With reference: {:?}", reference);
     } else {
-        println!("Without reference");
+        info!("Without reference");
     }
 
-    let ignore_file = arguments.source.join("wbck-ignore.txt");
+    // NOTE: the lock is released when dropped at the end of the backup
+    let _lock = RepositoryLock::acquire(lock::repository_of(&arguments.target))?;
+    if let Some(reference) = &arguments.reference {
+        lock::ensure_unlocked(lock::repository_of(reference))?;
+    }
+
+    let options = BackupOptions {
+        display_source: arguments
+            .fs_snapshot
+            .as_ref()
+            .map(|_| arguments.source.clone()),
+        ..create_backup_options(&arguments.options, &arguments.target)?
+    };
+
+    let hooks = Hooks {
+        pre_backup: arguments.pre_hooks.clone(),
+        post_backup: arguments.post_hooks.clone(),
+        abort_on_failure: arguments.abort_on_hook_failure,
+        event_format,
+    };
+    let context = HookContext {
+        source: &arguments.source,
+        target: &arguments.target,
+        reference: arguments.reference.as_deref(),
+        result: None,
+    };
+
+    // run the actual backup
+    let result = hooks.run(HookStage::PreBackup, &context).and_then(|_| {
+        // NOTE: the snapshot is taken after the pre-backup hooks, e.g., a
+        // database dump, and removed before the post-backup hooks
+        let snapshot = match &arguments.fs_snapshot {
+            Some(method) => {
+                info!("Back up from a {} snapshot of the source", method.name());
+                Some(SourceSnapshot::create(&arguments.source, method)?)
+            }
+            None => None,
+        };
+        let source = snapshot
+            .as_ref()
+            .map_or(arguments.source.as_path(), SourceSnapshot::path);
+
+        let ignore_spec = create_ignore_spec(source, &arguments.options)?;
+        let result = if arguments.mirror {
+            mirror::run_mirror(
+                source,
+                &arguments.target,
+                arguments.trash.as_ref(),
+                &ignore_spec,
+                &options,
+            )
+        } else {
+            backup::run_backup(
+                source,
+                &arguments.target,
+                arguments.reference.as_ref(),
+                &ignore_spec,
+                &options,
+            )
+        };
+
+        if let Some(snapshot) = snapshot {
+            if let Err(e) = snapshot.remove() {
+                if result.is_ok() {
+                    return Err(e);
+                }
+                eprintln!("Could not remove the snapshot of the source: {}", e);
+            }
+        }
+        result
+    });
+
+    // NOTE: post-backup hooks are executed even if the backup failed
+    let report = hooks.run_post_backup(context, result)?;
+    info!(
+        "Copied {} files ({} bytes), linked {} files",
+        report.copied_files, report.copied_bytes, report.linked_files
+    );
+    if arguments.mirror {
+        info!("Deleted {} items", report.deleted);
+    }
+    if report.renamed > 0 {
+        info!("Renamed {} items due to name collisions", report.renamed);
+    }
+    if !report.special_files.is_empty() {
+        info!(
+            "Recorded {} special files (FIFOs, sockets, devices):",
+            report.special_files.len()
+        );
+        for path in &report.special_files {
+            info!("  {:?}", path);
+        }
+    }
+    if !report.unstable_files.is_empty() {
+        info!(
+            "{} files were modified during the backup and may be inconsistent:",
+            report.unstable_files.len()
+        );
+        for path in &report.unstable_files {
+            info!("  {:?}", path);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Set the IO priority and create the options shared by backups and watching
+fn create_backup_options(arguments: &OptionArguments, target: &Path) -> Result<BackupOptions> {
+    let event_format = event_format(arguments.log_json);
+    throttle::set_io_priority(arguments.io_priority)?;
+
+    let mut throttle = Throttle::new().with_event_format(event_format);
+    if let Some(read_limit) = arguments.read_limit {
+        event_format.print_message(format!("Limit reads to {} bytes/s", read_limit));
+        throttle = throttle.with_read_limit(read_limit);
+    }
+    if let Some(write_limit) = arguments.write_limit {
+        event_format.print_message(format!("Limit writes to {} bytes/s", write_limit));
+        throttle = throttle.with_write_limit(write_limit);
+    }
+    if arguments.pause_signals {
+        event_format.print_message("Pause with SIGUSR1, resume with SIGUSR2");
+        throttle = throttle.with_pause_signals()?;
+    }
+    let fold_names = arguments.fold_names || collision::is_case_insensitive(target)?;
+    if fold_names {
+        event_format
+            .print_message("Rename items with names differing only in case or normalization");
+    }
+    Ok(BackupOptions {
+        throttle,
+        max_links: arguments.max_links,
+        copy_retries: arguments.copy_retries,
+        fold_names,
+        event_format,
+        display_source: None,
+    })
+}
+
+fn create_ignore_spec(
+    source: &Path,
+    arguments: &OptionArguments,
+) -> Result<(Box<dyn IgnoreSpec>, MarkerIgnoreSpec)> {
+    let event_format = event_format(arguments.log_json);
+    let ignore_file = source.join("wbck-ignore.txt");
     let ignore_spec: Box<dyn IgnoreSpec> = if ignore_file.exists() {
-        println!("Read ignore spec from {:?}", ignore_file);
-        Box::new(GlobIgnoreSpec::from_file(&arguments.source, &ignore_file)?)
+        event_format.print_message(format!("Read ignore spec from {:?}", ignore_file));
+        Box::new(GlobIgnoreSpec::from_file(source, &ignore_file)?)
     } else {
         Box::new(NoOpIgnoreSpec)
     };
-    // run the actual backup
-    backup::run_backup(
+    let mut marker_spec = MarkerIgnoreSpec::new(!arguments.no_cachedir_tag);
+    for marker in &arguments.exclude_markers {
+        event_format.print_message(format!("Skip directories containing {:?}", marker));
+        marker_spec = marker_spec.with_marker(marker.as_str());
+    }
+    Ok((ignore_spec, marker_spec))
+}
+
+#[cfg(target_os = "linux")]
+fn mount_main(arguments: MountArguments) -> Result<i32> {
+    mount::mount(&arguments.source, arguments.all, &arguments.mountpoint)?;
+    Ok(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_main(_arguments: MountArguments) -> Result<i32> {
+    Err(String::from("Mounting snapshots is only supported on Linux").into())
+}
+
+#[cfg(target_os = "linux")]
+fn watch_main(arguments: WatchArguments) -> Result<i32> {
+    let event_format = event_format(arguments.options.log_json);
+    event_format.print_message(format!("Watch {:?}", arguments.source));
+    event_format.print_message(format!("Create snapshots in {:?}", arguments.repository));
+    event_format.print_message(format!(
+        "Check for changes every {} seconds",
+        arguments.interval.as_secs()
+    ));
+
+    let options = create_backup_options(&arguments.options, &arguments.repository)?;
+    let ignore_spec = create_ignore_spec(&arguments.source, &arguments.options)?;
+    watch::watch(
         &arguments.source,
+        &arguments.repository,
+        &ignore_spec,
+        &options,
+        arguments.interval,
+    )?;
+    Ok(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_main(_arguments: WatchArguments) -> Result<i32> {
+    Err(String::from("Watching the source is only supported on Linux").into())
+}
+
+fn export_main(arguments: ExportArguments) -> Result<i32> {
+    let event_format = event_format(arguments.log_json);
+    lock::ensure_unlocked(lock::repository_of(&arguments.snapshot))?;
+    let count = archive::export_snapshot(&arguments.snapshot, &arguments.output, event_format)?;
+    event_format.print_message(format!(
+        "Exported {} items to {:?}",
+        count, arguments.output
+    ));
+    Ok(0)
+}
+
+fn import_main(arguments: ImportArguments) -> Result<i32> {
+    let _lock = RepositoryLock::acquire(lock::repository_of(&arguments.target))?;
+    if let Some(reference) = &arguments.reference {
+        lock::ensure_unlocked(lock::repository_of(reference))?;
+    }
+    let event_format = event_format(arguments.log_json);
+    let report = archive::import_snapshot(
+        &arguments.archive,
         &arguments.target,
         arguments.reference.as_ref(),
-        &ignore_spec,
+        event_format,
+    )?;
+    event_format.print_message(format!(
+        "Copied {} files ({} bytes), linked {} files",
+        report.copied_files, report.copied_bytes, report.linked_files
+    ));
+    if report.special_files > 0 {
+        event_format.print_message(format!("Recorded {} special files", report.special_files));
+    }
+    Ok(0)
+}
+
+fn push_main(arguments: PushArguments) -> Result<i32> {
+    let event_format = event_format(arguments.log_json);
+    // NOTE: the lock is held during the upload, to prevent changes to the snapshot
+    let _lock = RepositoryLock::acquire(lock::repository_of(&arguments.snapshot))?;
+    event_format.print_message(format!("Push {:?}", arguments.snapshot));
+    event_format.print_message(format!(
+        "To {:?} on {}",
+        arguments.target.path, arguments.target.host
+    ));
+    if let Some(reference) = &arguments.reference {
+        event_format.print_message(format!("With remote reference: {:?}", reference));
+    }
+
+    let mut client = SftpClient::connect(&arguments.ssh_command, &arguments.target.host)?;
+    let report = push::push_snapshot(
+        &mut client,
+        &arguments.snapshot,
+        &arguments.target.path,
+        arguments.reference.as_deref(),
+        event_format,
     )?;
+    event_format.print_message(format!(
+        "Uploaded {} files ({} bytes), linked {} files",
+        report.uploaded_files, report.uploaded_bytes, report.linked_files
+    ));
+    if report.skipped > 0 {
+        event_format.print_message(format!(
+            "Skipped {} symlinks and special files",
+            report.skipped
+        ));
+    }
+    Ok(0)
+}
 
+fn stats_main(arguments: StatsArguments) -> Result<i32> {
+    let stats = stats::repository_stats(&arguments.repository)?;
+    if arguments.json {
+        let json = serde_json::to_string_pretty(&stats)
+            .map_err(|e| format!("Cannot serialize statistics: {}", e))?;
+        println!("{}", json);
+    } else {
+        stats::print_stats(&stats);
+    }
+    Ok(0)
+}
+
+fn tag_main(arguments: TagArguments) -> Result<i32> {
+    let _lock = RepositoryLock::acquire(lock::repository_of(&arguments.snapshot))?;
+    let mut tags = tags::read_tags(&arguments.snapshot)?;
+    for tag in &arguments.add {
+        tags.add_tag(tag)?;
+    }
+    for tag in &arguments.remove {
+        tags.remove_tag(tag);
+    }
+    if arguments.clear_note {
+        tags.note = None;
+    }
+    if let Some(note) = &arguments.note {
+        tags.note = Some(note.clone());
+    }
+    tags::write_tags(&arguments.snapshot, &tags)?;
+
+    println!("Tags: {}", tags.tags.join(", "));
+    if let Some(note) = &tags.note {
+        println!("Note: {}", note);
+    }
+    Ok(0)
+}
+
+fn list_main(arguments: ListArguments) -> Result<i32> {
+    let snapshots = tags::find_snapshots(&arguments.repository, &arguments.tags)?;
+    if arguments.json {
+        let json = serde_json::to_string_pretty(&snapshots)
+            .map_err(|e| format!("Cannot serialize snapshots: {}", e))?;
+        println!("{}", json);
+    } else {
+        tags::print_snapshots(&snapshots);
+    }
+    Ok(0)
+}
+
+fn prune_main(arguments: PruneArguments) -> Result<i32> {
+    let _lock = RepositoryLock::acquire(&arguments.repository)?;
+    let removed = prune::prune(
+        &arguments.repository,
+        arguments.keep_last,
+        arguments.dry_run,
+    )?;
+    if arguments.dry_run {
+        println!("Would remove {} snapshots", removed.len());
+    } else {
+        println!("Removed {} snapshots", removed.len());
+    }
+    Ok(0)
+}
+
+fn history_main(arguments: HistoryArguments) -> Result<i32> {
+    let versions = history::find_versions(&arguments.repository, &arguments.path)?;
+    if versions.is_empty() {
+        println!("No versions of {:?} found", arguments.path);
+        return Ok(1);
+    }
+
+    match arguments.restore {
+        None => history::print_versions(&versions),
+        Some(number) => {
+            let version = number
+                .checked_sub(1)
+                .and_then(|idx| versions.get(idx))
+                .ok_or_else(|| format!("Unknown version {}", number))?;
+            let restored = history::restore_version(version, &arguments.path, &arguments.output)?;
+            println!("Restored version {} to {:?}", number, restored);
+        }
+    }
     Ok(0)
 }
 
@@ -50,13 +448,375 @@ impl IgnoreSpec for Box<dyn IgnoreSpec> {
     }
 }
 
-fn parse_args() -> Result<Arguments> {
+/// The arguments of `backup` and `watch` that configure the backups
+fn option_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("read-limit")
+            .long("read-limit")
+            .takes_value(true)
+            .help("Maximum bytes read per second, e.g., 10M"),
+        Arg::with_name("write-limit")
+            .long("write-limit")
+            .takes_value(true)
+            .help("Maximum bytes written per second, e.g., 10M"),
+        Arg::with_name("io-priority")
+            .long("io-priority")
+            .takes_value(true)
+            .possible_values(&["normal", "low", "idle"])
+            .help("The IO scheduling priority (Linux only)"),
+        Arg::with_name("pause-signals")
+            .long("pause-signals")
+            .help("Pause on SIGUSR1 and resume on SIGUSR2"),
+        Arg::with_name("exclude-marker")
+            .long("exclude-marker")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Skip directories containing a file with this name, e.g., .nobackup"),
+        Arg::with_name("no-cachedir-tag")
+            .long("no-cachedir-tag")
+            .help("Back up directories tagged with CACHEDIR.TAG"),
+        Arg::with_name("fold-names").long("fold-names").help(
+            "Rename items whose names differ only in case or Unicode normalization, \
+             enabled automatically for case-insensitive targets",
+        ),
+        Arg::with_name("log-json")
+            .long("log-json")
+            .help("Print the events of the backup as JSON objects, one per line"),
+        Arg::with_name("max-links")
+            .long("max-links")
+            .takes_value(true)
+            .help("Copy files instead of linking them, if they have this many links"),
+        Arg::with_name("copy-retries")
+            .long("copy-retries")
+            .takes_value(true)
+            .default_value("3")
+            .help("How often to copy files again, that were modified during the copy"),
+    ]
+}
+
+fn parse_args() -> Result<Command> {
     let matches = App::new("tools-backup")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("reference").long("ref").takes_value(true))
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .conflicts_with("reference")
+                .help("Keep the target in sync with the source, including deletions"),
+        )
+        .arg(
+            Arg::with_name("trash")
+                .long("trash")
+                .takes_value(true)
+                .requires("mirror")
+                .help("Move items removed in mirror mode into a dated directory of this path"),
+        )
+        .arg(
+            Arg::with_name("fs-snapshot")
+                .long("fs-snapshot")
+                .takes_value(true)
+                .possible_values(&["btrfs", "lvm", "bind"])
+                .help("Back up from a read-only snapshot of the source (Linux only)"),
+        )
+        .arg(
+            Arg::with_name("lvm-snapshot-size")
+                .long("lvm-snapshot-size")
+                .takes_value(true)
+                .default_value("1G")
+                .help("The space reserved for changes to the source during an LVM snapshot"),
+        )
+        .arg(
+            Arg::with_name("pre-hook")
+                .long("pre-hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A shell command to execute before the backup"),
+        )
+        .arg(
+            Arg::with_name("post-hook")
+                .long("post-hook")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A shell command to execute after the backup"),
+        )
+        .arg(
+            Arg::with_name("abort-on-hook-failure")
+                .long("abort-on-hook-failure")
+                .help("Abort the backup if a hook fails"),
+        )
+        .args(&option_args())
         .arg(Arg::with_name("source").required(true))
         .arg(Arg::with_name("target").required(true))
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount snapshots as a read-only filesystem (Linux only)")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Mount all snapshots of the repository given as source"),
+                )
+                .arg(Arg::with_name("source").required(true))
+                .arg(Arg::with_name("mountpoint").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List all versions of an item across the snapshots of a repository")
+                .arg(
+                    Arg::with_name("restore")
+                        .long("restore")
+                        .takes_value(true)
+                        .help("Restore the version with the given number"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("The file or directory to restore to (default: .)"),
+                )
+                .arg(Arg::with_name("repository").required(true))
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("The path of the item relative to the backup root"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show the space used by each snapshot of a repository")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the statistics as JSON"),
+                )
+                .arg(Arg::with_name("repository").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("tag")
+                .about("Add or remove tags and set the note of a snapshot")
+                .arg(
+                    Arg::with_name("add")
+                        .long("add")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Add a tag, e.g., keep"),
+                )
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Remove a tag"),
+                )
+                .arg(
+                    Arg::with_name("note")
+                        .long("note")
+                        .takes_value(true)
+                        .help("Set the note of the snapshot"),
+                )
+                .arg(
+                    Arg::with_name("clear-note")
+                        .long("clear-note")
+                        .conflicts_with("note")
+                        .help("Remove the note of the snapshot"),
+                )
+                .arg(Arg::with_name("snapshot").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the snapshots of a repository with their tags and notes")
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only list snapshots with this tag"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the snapshots as JSON"),
+                )
+                .arg(Arg::with_name("repository").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Remove old snapshots, snapshots tagged with keep are never removed")
+                .arg(
+                    Arg::with_name("keep-last")
+                        .long("keep-last")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The number of most recent snapshots to keep"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only print the snapshots that would be removed"),
+                )
+                .arg(Arg::with_name("repository").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export a snapshot into a tar archive, compressed if it ends in .zst")
+                .arg(
+                    Arg::with_name("log-json")
+                        .long("log-json")
+                        .help("Print the events of the export as JSON objects, one per line"),
+                )
+                .arg(Arg::with_name("snapshot").required(true))
+                .arg(Arg::with_name("output").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Create a snapshot from a tar archive")
+                .arg(
+                    Arg::with_name("reference")
+                        .long("ref")
+                        .takes_value(true)
+                        .help("Link unchanged files to this snapshot"),
+                )
+                .arg(
+                    Arg::with_name("log-json")
+                        .long("log-json")
+                        .help("Print the events of the import as JSON objects, one per line"),
+                )
+                .arg(Arg::with_name("archive").required(true))
+                .arg(Arg::with_name("target").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("push")
+                .about("Upload a snapshot to a remote host via SFTP")
+                .arg(
+                    Arg::with_name("reference")
+                        .long("ref")
+                        .takes_value(true)
+                        .help("A snapshot on the remote host to link unchanged files to"),
+                )
+                .arg(
+                    Arg::with_name("ssh-command")
+                        .long("ssh-command")
+                        .takes_value(true)
+                        .default_value("ssh")
+                        .help("The command used to connect, e.g., \"ssh -p 2222\""),
+                )
+                .arg(
+                    Arg::with_name("log-json")
+                        .long("log-json")
+                        .help("Print the events of the push as JSON objects, one per line"),
+                )
+                .arg(Arg::with_name("snapshot").required(true))
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("The remote snapshot to create, as [user@]host:path"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Watch the source and create snapshots of its changes (Linux only)")
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .default_value("60")
+                        .help("The seconds between two snapshots"),
+                )
+                .args(&option_args())
+                .arg(Arg::with_name("source").required(true))
+                .arg(Arg::with_name("repository").required(true)),
+        )
         .get_matches();
+
+    match matches.subcommand() {
+        ("mount", Some(matches)) => parse_mount_args(matches).map(Command::Mount),
+        ("history", Some(matches)) => parse_history_args(matches).map(Command::History),
+        ("stats", Some(matches)) => parse_stats_args(matches).map(Command::Stats),
+        ("tag", Some(matches)) => parse_tag_args(matches).map(Command::Tag),
+        ("list", Some(matches)) => parse_list_args(matches).map(Command::List),
+        ("prune", Some(matches)) => parse_prune_args(matches).map(Command::Prune),
+        ("export", Some(matches)) => parse_export_args(matches).map(Command::Export),
+        ("import", Some(matches)) => parse_import_args(matches).map(Command::Import),
+        ("push", Some(matches)) => parse_push_args(matches).map(Command::Push),
+        ("watch", Some(matches)) => parse_watch_args(matches).map(Command::Watch),
+        _ => parse_backup_args(&matches).map(Command::Backup),
+    }
+}
+
+fn parse_option_args(matches: &ArgMatches) -> Result<OptionArguments> {
+    let read_limit = matches
+        .value_of("read-limit")
+        .map(throttle::parse_rate)
+        .transpose()?;
+    let write_limit = matches
+        .value_of("write-limit")
+        .map(throttle::parse_rate)
+        .transpose()?;
+    let io_priority = matches
+        .value_of("io-priority")
+        .map(IoPriority::parse)
+        .transpose()?
+        .unwrap_or(IoPriority::Normal);
+    let pause_signals = matches.is_present("pause-signals");
+    let max_links = matches
+        .value_of("max-links")
+        .map(|s| {
+            s.parse::<u64>()
+                .map_err(|e| format!("Invalid maximum number of links {:?}: {}", s, e))
+        })
+        .transpose()?;
+    let copy_retries = matches
+        .value_of("copy-retries")
+        .map(|s| {
+            s.parse::<u32>()
+                .map_err(|e| format!("Invalid number of copy retries {:?}: {}", s, e))
+        })
+        .transpose()?
+        .unwrap_or(3);
+    let exclude_markers = matches
+        .values_of("exclude-marker")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let no_cachedir_tag = matches.is_present("no-cachedir-tag");
+    let fold_names = matches.is_present("fold-names");
+    let log_json = matches.is_present("log-json");
+
+    Ok(OptionArguments {
+        exclude_markers,
+        no_cachedir_tag,
+        fold_names,
+        log_json,
+        read_limit,
+        write_limit,
+        io_priority,
+        pause_signals,
+        max_links,
+        copy_retries,
+    })
+}
+
+fn parse_backup_args(matches: &ArgMatches) -> Result<BackupArguments> {
     let reference = matches.value_of_os("reference").map(PathBuf::from);
+    let fs_snapshot = matches
+        .value_of("fs-snapshot")
+        .map(|s| SnapshotMethod::parse(s, matches.value_of("lvm-snapshot-size").unwrap_or("1G")))
+        .transpose()?;
+    let mirror = matches.is_present("mirror");
+    let trash = matches.value_of_os("trash").map(PathBuf::from);
+    let pre_hooks = matches
+        .values_of("pre-hook")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let post_hooks = matches
+        .values_of("post-hook")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    let abort_on_hook_failure = matches.is_present("abort-on-hook-failure");
     let source = matches
         .value_of_os("source")
         .ok_or_else(|| String::from("Missing argument source"))?
@@ -66,10 +826,17 @@ fn parse_args() -> Result<Arguments> {
         .ok_or_else(|| String::from("Missing argument target"))?
         .into();
 
-    let result = Arguments {
+    let result = BackupArguments {
         source,
         target,
         reference,
+        mirror,
+        trash,
+        fs_snapshot,
+        options: parse_option_args(matches)?,
+        pre_hooks,
+        post_hooks,
+        abort_on_hook_failure,
     };
 
     if !result.source.exists() {
@@ -87,8 +854,356 @@ fn parse_args() -> Result<Arguments> {
     Ok(result)
 }
 
-struct Arguments {
+fn parse_mount_args(matches: &ArgMatches) -> Result<MountArguments> {
+    let result = MountArguments {
+        all: matches.is_present("all"),
+        source: matches
+            .value_of_os("source")
+            .ok_or_else(|| String::from("Missing argument source"))?
+            .into(),
+        mountpoint: matches
+            .value_of_os("mountpoint")
+            .ok_or_else(|| String::from("Missing argument mountpoint"))?
+            .into(),
+    };
+
+    if !result.source.is_dir() {
+        return Err(format!("Source path {:?} must be a directory", result.source).into());
+    }
+    if !result.mountpoint.is_dir() {
+        return Err(format!("Mountpoint {:?} must be a directory", result.mountpoint).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_history_args(matches: &ArgMatches) -> Result<HistoryArguments> {
+    let restore = matches
+        .value_of("restore")
+        .map(|s| {
+            s.parse::<usize>()
+                .map_err(|e| format!("Invalid version {:?}: {}", s, e))
+        })
+        .transpose()?;
+    let result = HistoryArguments {
+        repository: matches
+            .value_of_os("repository")
+            .ok_or_else(|| String::from("Missing argument repository"))?
+            .into(),
+        path: matches
+            .value_of_os("path")
+            .ok_or_else(|| String::from("Missing argument path"))?
+            .into(),
+        restore,
+        output: matches
+            .value_of_os("output")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(".")),
+    };
+
+    if !result.repository.is_dir() {
+        return Err(format!("Repository {:?} must be a directory", result.repository).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_stats_args(matches: &ArgMatches) -> Result<StatsArguments> {
+    let result = StatsArguments {
+        repository: matches
+            .value_of_os("repository")
+            .ok_or_else(|| String::from("Missing argument repository"))?
+            .into(),
+        json: matches.is_present("json"),
+    };
+
+    if !result.repository.is_dir() {
+        return Err(format!("Repository {:?} must be a directory", result.repository).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_tag_args(matches: &ArgMatches) -> Result<TagArguments> {
+    let values = |name| {
+        matches
+            .values_of(name)
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default()
+    };
+    let result = TagArguments {
+        snapshot: matches
+            .value_of_os("snapshot")
+            .ok_or_else(|| String::from("Missing argument snapshot"))?
+            .into(),
+        add: values("add"),
+        remove: values("remove"),
+        note: matches.value_of("note").map(String::from),
+        clear_note: matches.is_present("clear-note"),
+    };
+
+    if !result.snapshot.is_dir() {
+        return Err(format!("Snapshot {:?} must be a directory", result.snapshot).into());
+    }
+    // NOTE: tagging other directories would turn them into snapshots for prune
+    if !snapshot::is_snapshot(&result.snapshot) {
+        return Err(format!("{:?} is not a snapshot", result.snapshot).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_prune_args(matches: &ArgMatches) -> Result<PruneArguments> {
+    let keep_last = matches
+        .value_of("keep-last")
+        .ok_or_else(|| String::from("Missing argument keep-last"))?;
+    let result = PruneArguments {
+        repository: matches
+            .value_of_os("repository")
+            .ok_or_else(|| String::from("Missing argument repository"))?
+            .into(),
+        keep_last: keep_last
+            .parse::<usize>()
+            .map_err(|e| format!("Invalid number of snapshots {:?}: {}", keep_last, e))?,
+        dry_run: matches.is_present("dry-run"),
+    };
+    Ok(result)
+}
+
+fn parse_list_args(matches: &ArgMatches) -> Result<ListArguments> {
+    let result = ListArguments {
+        repository: matches
+            .value_of_os("repository")
+            .ok_or_else(|| String::from("Missing argument repository"))?
+            .into(),
+        tags: matches
+            .values_of("tag")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        json: matches.is_present("json"),
+    };
+
+    if !result.repository.is_dir() {
+        return Err(format!("Repository {:?} must be a directory", result.repository).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_export_args(matches: &ArgMatches) -> Result<ExportArguments> {
+    let result = ExportArguments {
+        snapshot: matches
+            .value_of_os("snapshot")
+            .ok_or_else(|| String::from("Missing argument snapshot"))?
+            .into(),
+        output: matches
+            .value_of_os("output")
+            .ok_or_else(|| String::from("Missing argument output"))?
+            .into(),
+        log_json: matches.is_present("log-json"),
+    };
+
+    if !result.snapshot.is_dir() {
+        return Err(format!("Snapshot {:?} must be a directory", result.snapshot).into());
+    }
+    if result.output.exists() {
+        return Err(format!("Output {:?} must not exist", result.output).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_import_args(matches: &ArgMatches) -> Result<ImportArguments> {
+    let result = ImportArguments {
+        archive: matches
+            .value_of_os("archive")
+            .ok_or_else(|| String::from("Missing argument archive"))?
+            .into(),
+        target: matches
+            .value_of_os("target")
+            .ok_or_else(|| String::from("Missing argument target"))?
+            .into(),
+        reference: matches.value_of_os("reference").map(PathBuf::from),
+        log_json: matches.is_present("log-json"),
+    };
+
+    if !result.archive.is_file() {
+        return Err(format!("Archive {:?} must exist", result.archive).into());
+    }
+    if result.target.exists() {
+        return Err(format!("Target path {:?} must not exist", result.target).into());
+    }
+    if let Some(reference) = result.reference.as_ref() {
+        if !reference.is_dir() {
+            return Err(format!("If given reference path {:?} must exist", reference).into());
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_push_args(matches: &ArgMatches) -> Result<PushArguments> {
+    let target = matches
+        .value_of("target")
+        .ok_or_else(|| String::from("Missing argument target"))?;
+    let result = PushArguments {
+        snapshot: matches
+            .value_of_os("snapshot")
+            .ok_or_else(|| String::from("Missing argument snapshot"))?
+            .into(),
+        target: RemotePath::parse(target)?,
+        reference: matches.value_of("reference").map(String::from),
+        ssh_command: matches.value_of("ssh-command").unwrap_or("ssh").to_owned(),
+        log_json: matches.is_present("log-json"),
+    };
+
+    if !result.snapshot.is_dir() {
+        return Err(format!("Snapshot {:?} must be a directory", result.snapshot).into());
+    }
+
+    Ok(result)
+}
+
+fn parse_watch_args(matches: &ArgMatches) -> Result<WatchArguments> {
+    let interval = matches
+        .value_of("interval")
+        .map(|s| {
+            s.parse::<u64>()
+                .map_err(|e| format!("Invalid interval {:?}: {}", s, e))
+        })
+        .transpose()?
+        .unwrap_or(60);
+    let result = WatchArguments {
+        source: matches
+            .value_of_os("source")
+            .ok_or_else(|| String::from("Missing argument source"))?
+            .into(),
+        repository: matches
+            .value_of_os("repository")
+            .ok_or_else(|| String::from("Missing argument repository"))?
+            .into(),
+        interval: Duration::from_secs(interval),
+        options: parse_option_args(matches)?,
+    };
+
+    // NOTE: snapshots are named after the current second
+    if interval == 0 {
+        return Err(String::from("The interval must be at least one second").into());
+    }
+    if !result.source.is_dir() {
+        return Err(format!("Source path {:?} must be a directory", result.source).into());
+    }
+    if !result.repository.is_dir() {
+        return Err(format!("Repository {:?} must be a directory", result.repository).into());
+    }
+
+    Ok(result)
+}
+
+enum Command {
+    Backup(BackupArguments),
+    Mount(MountArguments),
+    History(HistoryArguments),
+    Export(ExportArguments),
+    Import(ImportArguments),
+    Push(PushArguments),
+    Watch(WatchArguments),
+    Stats(StatsArguments),
+    Tag(TagArguments),
+    List(ListArguments),
+    Prune(PruneArguments),
+}
+
+struct BackupArguments {
     source: PathBuf,
     target: PathBuf,
     reference: Option<PathBuf>,
+    mirror: bool,
+    trash: Option<PathBuf>,
+    fs_snapshot: Option<SnapshotMethod>,
+    options: OptionArguments,
+    pre_hooks: Vec<String>,
+    post_hooks: Vec<String>,
+    abort_on_hook_failure: bool,
+}
+
+/// The arguments shared by `backup` and `watch`
+struct OptionArguments {
+    exclude_markers: Vec<String>,
+    no_cachedir_tag: bool,
+    fold_names: bool,
+    log_json: bool,
+    read_limit: Option<u64>,
+    write_limit: Option<u64>,
+    io_priority: IoPriority,
+    pause_signals: bool,
+    max_links: Option<u64>,
+    copy_retries: u32,
+}
+
+struct StatsArguments {
+    repository: PathBuf,
+    json: bool,
+}
+
+struct TagArguments {
+    snapshot: PathBuf,
+    add: Vec<String>,
+    remove: Vec<String>,
+    note: Option<String>,
+    clear_note: bool,
+}
+
+struct ListArguments {
+    repository: PathBuf,
+    tags: Vec<String>,
+    json: bool,
+}
+
+struct PruneArguments {
+    repository: PathBuf,
+    keep_last: usize,
+    dry_run: bool,
+}
+
+struct ExportArguments {
+    snapshot: PathBuf,
+    output: PathBuf,
+    log_json: bool,
+}
+
+struct ImportArguments {
+    archive: PathBuf,
+    target: PathBuf,
+    reference: Option<PathBuf>,
+    log_json: bool,
+}
+
+struct PushArguments {
+    snapshot: PathBuf,
+    target: RemotePath,
+    /// A path on the remote host
+    reference: Option<String>,
+    ssh_command: String,
+    log_json: bool,
+}
+
+struct WatchArguments {
+    source: PathBuf,
+    repository: PathBuf,
+    interval: Duration,
+    options: OptionArguments,
+}
+
+struct MountArguments {
+    source: PathBuf,
+    mountpoint: PathBuf,
+    all: bool,
+}
+
+struct HistoryArguments {
+    repository: PathBuf,
+    path: PathBuf,
+    restore: Option<usize>,
+    output: PathBuf,
 }
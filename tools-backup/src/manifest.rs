@@ -0,0 +1,315 @@
+//! The manifest records the metadata of all items of a snapshot
+//!
+//! The backup stores some items in a modified form, e.g., symlinks as text
+//! files. The manifest keeps the information required to reconstruct the
+//! original items. It is stored as one JSON object per line in the metadata
+//! directory of the snapshot.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tools_utils::{Error, Result};
+
+use super::faults;
+
+/// The directory inside each snapshot that contains its metadata
+pub const METADATA_DIR: &str = ".tools-backup";
+
+const MANIFEST_FILE: &str = "manifest.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl EntryKind {
+    /// Determine the kind of an item, symlinks are not followed
+    pub fn of(file_type: &fs::FileType) -> Option<Self> {
+        if file_type.is_dir() {
+            Some(EntryKind::Directory)
+        } else if file_type.is_file() {
+            Some(EntryKind::File)
+        } else if file_type.is_symlink() {
+            Some(EntryKind::Symlink)
+        } else {
+            special_kind(file_type)
+        }
+    }
+
+    /// True for FIFOs, sockets, and device nodes
+    pub fn is_special(self) -> bool {
+        !matches!(
+            self,
+            EntryKind::File | EntryKind::Directory | EntryKind::Symlink
+        )
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Fifo => "fifo",
+            EntryKind::Socket => "socket",
+            EntryKind::BlockDevice => "block device",
+            EntryKind::CharDevice => "character device",
+        }
+    }
+}
+
+#[cfg(unix)]
+fn special_kind(file_type: &fs::FileType) -> Option<EntryKind> {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(EntryKind::Socket)
+    } else if file_type.is_block_device() {
+        Some(EntryKind::BlockDevice)
+    } else if file_type.is_char_device() {
+        Some(EntryKind::CharDevice)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_file_type: &fs::FileType) -> Option<EntryKind> {
+    None
+}
+
+/// The metadata of a single item of the snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The path relative to the backup root, using `/` as separator
+    pub path: String,
+    /// The path inside the snapshot, if it differs from `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_path: Option<String>,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// The modification time in seconds since the epoch
+    pub mtime: i64,
+    /// The unix permission bits, if available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The target of symlinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// The device number of block and character devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<u64>,
+}
+
+impl ManifestEntry {
+    /// Build the entry from the metadata of the source item
+    pub fn from_source(rel_path: &Path, source: &Path, kind: EntryKind) -> Result<Self> {
+        let metadata = source
+            .symlink_metadata()
+            .map_err(|e| format!("ManifestEntry: could not retrieve metadata: {}", e))?;
+
+        let link_target = if kind == EntryKind::Symlink {
+            let target = fs::read_link(source)
+                .map_err(|e| format!("ManifestEntry: cannot read link: {}", e))?;
+            Some(target.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path: manifest_path(rel_path),
+            stored_path: None,
+            kind,
+            size: if kind == EntryKind::File {
+                metadata.len()
+            } else {
+                0
+            },
+            mtime: mtime_seconds(&metadata),
+            mode: mode(&metadata),
+            link_target,
+            rdev: match kind {
+                EntryKind::BlockDevice | EntryKind::CharDevice => rdev(&metadata),
+                _ => None,
+            },
+        })
+    }
+
+    /// The path of the item inside the snapshot, relative to its root
+    pub fn stored_path(&self) -> &str {
+        self.stored_path.as_deref().unwrap_or(&self.path)
+    }
+}
+
+/// Convert a relative path into the `/` separated form used in the manifest
+pub fn manifest_path(path: &Path) -> String {
+    let mut result = String::new();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            if !result.is_empty() {
+                result.push('/');
+            }
+            result.push_str(&part.to_string_lossy());
+        }
+    }
+    result
+}
+
+/// Convert a `/` separated manifest path into a native relative path
+pub fn native_path(path: &str) -> PathBuf {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+pub fn manifest_file(snapshot: impl AsRef<Path>) -> PathBuf {
+    snapshot.as_ref().join(METADATA_DIR).join(MANIFEST_FILE)
+}
+
+pub fn write_manifest(snapshot: impl AsRef<Path>, entries: &[ManifestEntry]) -> Result<()> {
+    faults::check("write manifest")?;
+    let path = manifest_file(snapshot);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("write_manifest: could not create directory: {}", e))?;
+    }
+
+    // NOTE: replace the file, so interrupted writes never leave a truncated
+    // manifest behind
+    let temp_path = path.with_extension("jsonl.tmp");
+    let file = File::create(&temp_path)
+        .map_err(|e| format!("write_manifest: cannot create file: {}", e))?;
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        let line = serde_json::to_string(entry)
+            .map_err(|e| format!("write_manifest: cannot serialize entry: {}", e))?;
+        writeln!(writer, "{}", line)
+            .map_err(|e| format!("write_manifest: cannot write file: {}", e))?;
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("write_manifest: cannot write file: {}", e))?;
+    drop(writer);
+    fs::rename(&temp_path, &path)
+        .map_err(|e| format!("write_manifest: cannot replace file: {}", e))?;
+    Ok(())
+}
+
+/// Read the manifest of a snapshot, if it exists
+pub fn read_manifest(snapshot: impl AsRef<Path>) -> Result<Option<Vec<ManifestEntry>>> {
+    let path = manifest_file(snapshot);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let file = File::open(&path).map_err(|e| format!("read_manifest: cannot open file: {}", e))?;
+    let mut result = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("read_manifest: could not read line: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            Error::from(format!("read_manifest: invalid entry in {:?}: {}", path, e))
+        })?;
+        result.push(entry);
+    }
+    Ok(Some(result))
+}
+
+pub fn mtime_seconds(metadata: &fs::Metadata) -> i64 {
+    match metadata.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(duration)) => duration.as_secs() as i64,
+        Ok(Err(e)) => -(e.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn rdev(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.rdev())
+}
+
+#[cfg(not(unix))]
+fn rdev(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip() -> Result<()> {
+        let spec = Spec::new()?.with_directory("snapshot")?;
+        let entries = vec![
+            ManifestEntry {
+                path: String::from("foo/bar.txt"),
+                stored_path: None,
+                kind: EntryKind::File,
+                size: 5,
+                mtime: 1_600_000_000,
+                mode: Some(0o644),
+                link_target: None,
+                rdev: None,
+            },
+            ManifestEntry {
+                path: String::from("link"),
+                stored_path: Some(String::from("link~1")),
+                kind: EntryKind::Symlink,
+                size: 0,
+                mtime: 1_600_000_000,
+                mode: None,
+                link_target: Some(String::from("foo/bar.txt")),
+                rdev: None,
+            },
+            ManifestEntry {
+                path: String::from("fifo"),
+                stored_path: None,
+                kind: EntryKind::Fifo,
+                size: 0,
+                mtime: 1_600_000_000,
+                mode: Some(0o600),
+                link_target: None,
+                rdev: None,
+            },
+        ];
+
+        write_manifest(spec.path("snapshot"), &entries)?;
+        let actual = read_manifest(spec.path("snapshot"))?;
+
+        assert_eq!(actual, Some(entries));
+        assert_eq!(read_manifest(spec.path("missing"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_paths() {
+        assert_eq!(
+            manifest_path(Path::new("foo/bar/baz.txt")),
+            "foo/bar/baz.txt"
+        );
+        assert_eq!(native_path("foo/bar"), Path::new("foo").join("bar"));
+    }
+}
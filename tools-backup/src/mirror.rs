@@ -0,0 +1,467 @@
+//! Keep a single target directory in sync with the source
+//!
+//! In contrast to the snapshot backup, existing items of the target are
+//! updated, if they changed, and items that no longer exist in the source are
+//! removed from the target. Removed items are either deleted or moved into a
+//! dated directory of the trash, unique to each run.
+use chrono::Local;
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+use walkdir::WalkDir;
+
+use super::backup::{self, BackupOptions, BackupReport, IgnoreSpec};
+use super::collision::NameResolver;
+use super::events::{Event, EventLog};
+use super::manifest::{self, EntryKind, ManifestEntry, METADATA_DIR};
+use super::snapshot;
+
+/// Mirror the source into the target
+///
+/// If `trash` is given, removed and replaced items are moved into a
+/// subdirectory of it named after the current time. Otherwise, they are
+/// deleted. All actions are appended to the event log of the target.
+pub fn run_mirror(
+    source: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    trash: Option<impl AsRef<Path>>,
+    ignore_spec: &impl IgnoreSpec,
+    options: &BackupOptions,
+) -> Result<BackupReport> {
+    let source = source.as_ref();
+    let target = target.as_ref();
+    let trash = trash.as_ref().map(|p| p.as_ref());
+    let mut log = EventLog::open(target, options.event_format)?;
+
+    let result = mirror_items(source, target, trash, ignore_spec, options, &mut log);
+    if let Err(e) = &result {
+        let source = options.display_path(source, source);
+        log.record(Event::new("error").with_source(source).with_error(e))?;
+    }
+    log.flush()?;
+    result
+}
+
+fn mirror_items(
+    source: &Path,
+    target: &Path,
+    trash: Option<&Path>,
+    ignore_spec: &impl IgnoreSpec,
+    options: &BackupOptions,
+    log: &mut EventLog,
+) -> Result<BackupReport> {
+    // NOTE: the trash may be inside the target, e.g., given as a relative path
+    let canonical_target = target
+        .canonicalize()
+        .map_err(|e| format!("run_mirror: cannot resolve the target: {}", e))?;
+    let canonical_trash = match trash {
+        Some(trash) => {
+            backup::ensure_directory_exists(trash)?;
+            let trash = trash
+                .canonicalize()
+                .map_err(|e| format!("run_mirror: cannot resolve the trash: {}", e))?;
+            Some(trash)
+        }
+        None => None,
+    };
+    let mut remover = Remover::new(target, trash);
+    let mut resolver = NameResolver::new(source, options.fold_names);
+    let mut report = BackupReport::default();
+    let mut entries = Vec::<ManifestEntry>::new();
+    let mut kept = HashSet::<PathBuf>::new();
+
+    let mut walker = WalkDir::new(source).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry.map_err(|e| format!("run_mirror: Invalid directory entry: {}", e))?;
+        let item = entry.path();
+        let display_item = options.display_path(source, item);
+
+        if ignore_spec.is_ignored(item)? {
+            log.record(Event::new("ignore").with_source(&display_item))?;
+            report.ignored += 1;
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        let rel_item = item
+            .strip_prefix(source)
+            .map_err(|e| format!("Cannot determine relative path: {}", e))?;
+        if rel_item == Path::new(METADATA_DIR) {
+            log.record(
+                Event::new("skip")
+                    .with_source(&display_item)
+                    .with_detail("reserved name"),
+            )?;
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        let stored_item = resolver.stored_path(rel_item, entry.file_type().is_dir())?;
+        if stored_item != rel_item {
+            report.renamed += 1;
+        }
+        let target_item = target.join(&stored_item);
+        kept.insert(stored_item.clone());
+
+        let (kind, event) = match stored_kind(item, &target_item)? {
+            Some(kind) => {
+                report.skipped += 1;
+                (Some(kind), Event::new("skip").with_detail("unchanged"))
+            }
+            None => {
+                if target_item.symlink_metadata().is_ok() {
+                    log.record(remover.remove(&target_item, "changed")?)?;
+                }
+                let action =
+                    backup::backup_item(item, &target_item, Option::<&Path>::None, options)?;
+                report.record(&display_item, action);
+                (action.entry_kind(), Event::from_action(action))
+            }
+        };
+        log.record(event.with_source(&display_item).with_target(&target_item))?;
+
+        if let Some(kind) = kind {
+            let mut manifest_entry = ManifestEntry::from_source(rel_item, item, kind)?;
+            if stored_item != rel_item {
+                manifest_entry.stored_path = Some(manifest::manifest_path(&stored_item));
+            }
+            entries.push(manifest_entry);
+        }
+    }
+
+    let mut walker = WalkDir::new(target)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != METADATA_DIR);
+    while let Some(entry) = walker.next() {
+        let entry = entry.map_err(|e| format!("run_mirror: Invalid directory entry: {}", e))?;
+        let item = entry.path();
+        let rel_item = item
+            .strip_prefix(target)
+            .map_err(|e| format!("Cannot determine relative path: {}", e))?;
+        if canonical_trash.as_ref() == Some(&canonical_target.join(rel_item)) {
+            walker.skip_current_dir();
+            continue;
+        }
+        if kept.contains(rel_item) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+        log.record(remover.remove(item, "deleted")?)?;
+        report.deleted += 1;
+    }
+
+    manifest::write_manifest(target, &entries)?;
+    Ok(report)
+}
+
+/// The kind of the stored item, if it is up to date with the source
+fn stored_kind(source: &Path, target: &Path) -> Result<Option<EntryKind>> {
+    let stored = match target.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(None),
+    };
+    let metadata = source
+        .symlink_metadata()
+        .map_err(|e| format!("run_mirror: could not retrieve metadata: {}", e))?;
+
+    let kind = match EntryKind::of(&metadata.file_type()) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+    match kind {
+        EntryKind::Directory if stored.is_dir() => return Ok(Some(kind)),
+        EntryKind::Symlink if stored.is_file() => {
+            let link_target = fs::read_link(source)
+                .map_err(|e| format!("run_mirror: cannot read link: {}", e))?;
+            let stored_target = snapshot::read_link_placeholder(target, stored.len())?;
+            return Ok(if stored_target == Some(link_target) {
+                Some(kind)
+            } else {
+                None
+            });
+        }
+        EntryKind::File if stored.is_file() => {}
+        // NOTE: special files that could not be recreated are recorded again
+        _ if kind.is_special() && EntryKind::of(&stored.file_type()) == Some(kind) => {
+            return Ok(Some(kind))
+        }
+        _ => return Ok(None),
+    }
+
+    // NOTE: copies are newer than their source, see `backup::should_link`
+    let is_current = match (metadata.modified(), stored.modified()) {
+        (Ok(modified), Ok(stored_modified)) => stored_modified >= modified,
+        _ => false,
+    };
+    if is_current && metadata.len() == stored.len() {
+        Ok(Some(EntryKind::File))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Delete items or move them into the trash
+struct Remover<'a> {
+    target: &'a Path,
+    trash: Option<&'a Path>,
+    /// The directory of this run inside the trash, created on first use
+    trash_directory: Option<PathBuf>,
+}
+
+impl<'a> Remover<'a> {
+    fn new(target: &'a Path, trash: Option<&'a Path>) -> Self {
+        Self {
+            target,
+            trash,
+            trash_directory: None,
+        }
+    }
+
+    fn remove(&mut self, item: &Path, reason: &str) -> Result<Event> {
+        let trash = match self.trash {
+            Some(trash) => self.trash_directory(trash)?,
+            None => {
+                remove_item(item)?;
+                return Ok(Event::new("delete").with_target(item).with_detail(reason));
+            }
+        };
+
+        let rel_item = item
+            .strip_prefix(self.target)
+            .map_err(|e| format!("Cannot determine relative path: {}", e))?;
+        let trash_item = trash.join(rel_item);
+        // NOTE: items already in the trash are never replaced
+        if trash_item.symlink_metadata().is_ok() {
+            return Err(Error::from(format!(
+                "run_mirror: {:?} already exists in the trash",
+                trash_item
+            )));
+        }
+        if let Some(parent) = trash_item.parent() {
+            backup::ensure_directory_exists(parent)?;
+        }
+        fs::rename(item, &trash_item).map_err(|e| {
+            Error::from(format!(
+                "run_mirror: could not move {:?} into the trash: {}",
+                item, e
+            ))
+        })?;
+        Ok(Event::new("trash")
+            .with_target(item)
+            .with_detail(format!("{}, moved to {:?}", reason, trash_item)))
+    }
+
+    /// Create a directory named after the current time, with a counter for
+    /// runs started in the same second
+    fn trash_directory(&mut self, trash: &Path) -> Result<PathBuf> {
+        if let Some(directory) = &self.trash_directory {
+            return Ok(directory.clone());
+        }
+        let name = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+        let mut idx = 0;
+        loop {
+            let directory = match idx {
+                0 => trash.join(&name),
+                _ => trash.join(format!("{}-{}", name, idx)),
+            };
+            match fs::create_dir(&directory) {
+                Ok(()) => {
+                    self.trash_directory = Some(directory.clone());
+                    return Ok(directory);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => idx += 1,
+                Err(e) => {
+                    return Err(Error::from(format!(
+                        "run_mirror: cannot create directory {:?}: {}",
+                        directory, e
+                    )))
+                }
+            }
+        }
+    }
+}
+
+fn remove_item(item: &Path) -> Result<()> {
+    let result = if item.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+        fs::remove_dir_all(item)
+    } else {
+        fs::remove_file(item)
+    };
+    result.map_err(|e| Error::from(format!("run_mirror: could not remove {:?}: {}", item, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backup::NoOpIgnoreSpec;
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn mirror_updates_and_deletes_items() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "same.txt"), Some("same"), None)?
+            .with_file(("source", "changed.txt"), Some("new content"), None)?
+            .with_file(("source", "dir", "new.txt"), Some("new"), None)?
+            .with_file(("target", "changed.txt"), Some("old"), None)?
+            .with_file(("target", "removed", "old.txt"), Some("old"), None)?
+            .with_file(("target", "removed.txt"), Some("old"), None)?;
+        fs::copy(
+            spec.path(("source", "same.txt")),
+            spec.path(("target", "same.txt")),
+        )
+        .unwrap();
+
+        let report = run_mirror(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.copied_files, 2);
+        assert_eq!(report.deleted, 2);
+        assert_eq!(
+            read_file(spec.path(("target", "changed.txt")))?,
+            "new content"
+        );
+        assert_eq!(read_file(spec.path(("target", "dir", "new.txt")))?, "new");
+        assert!(!spec.path(("target", "removed")).exists());
+        assert!(!spec.path(("target", "removed.txt")).exists());
+        assert!(manifest::read_manifest(spec.path("target"))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn mirror_moves_removed_items_into_the_trash() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "changed.txt"), Some("new content"), None)?
+            .with_file(("target", "changed.txt"), Some("old"), None)?
+            .with_file(("target", "removed.txt"), Some("removed"), None)?
+            .with_directory("trash")?;
+
+        run_mirror(
+            spec.path("source"),
+            spec.path("target"),
+            Some(spec.path("trash")),
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        let trash = snapshot::list_snapshots(spec.path("trash"))?;
+        assert_eq!(trash.len(), 1);
+        assert_eq!(read_file(trash[0].join("changed.txt"))?, "old");
+        assert_eq!(read_file(trash[0].join("removed.txt"))?, "removed");
+        assert!(!spec.path(("target", "removed.txt")).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn mirror_runs_in_the_same_second_keep_all_trashed_versions() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "changed.txt"), Some("new content"), None)?
+            .with_file(("target", "changed.txt"), Some("old"), None)?
+            .with_directory("trash")?;
+        // NOTE: a trash directory named after the current second exists already
+        let name = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+        fs::create_dir(spec.path(("trash", name.as_str()))).unwrap();
+        fs::write(
+            spec.path(("trash", name.as_str(), "changed.txt")),
+            "earlier",
+        )
+        .unwrap();
+
+        run_mirror(
+            spec.path("source"),
+            spec.path("target"),
+            Some(spec.path("trash")),
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        let trash = snapshot::list_snapshots(spec.path("trash"))?;
+        let mut contents = trash
+            .iter()
+            .map(|directory| read_file(directory.join("changed.txt")))
+            .collect::<Result<Vec<_>>>()?;
+        contents.sort();
+        assert_eq!(contents, vec!["earlier", "old"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn mirror_keeps_a_trash_inside_the_target() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "kept.txt"), Some("kept"), None)?
+            .with_file(("target", "removed.txt"), Some("removed"), None)?;
+        // NOTE: the trash is given with a different spelling than the target
+        std::os::unix::fs::symlink(spec.path("target"), spec.path("link")).unwrap();
+        let trash = spec.path(("link", ".trash"));
+
+        for _ in 0..2 {
+            run_mirror(
+                spec.path("source"),
+                spec.path("target"),
+                Some(&trash),
+                &NoOpIgnoreSpec,
+                &BackupOptions::default(),
+            )?;
+        }
+
+        let trash = snapshot::list_snapshots(spec.path(("target", ".trash")))?;
+        assert_eq!(trash.len(), 1);
+        assert_eq!(read_file(trash[0].join("removed.txt"))?, "removed");
+        Ok(())
+    }
+
+    #[test]
+    fn mirror_renames_colliding_names() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "Readme.md"), Some("lower"), None)?
+            .with_file(("source", "README.md"), Some("upper"), None)?
+            .with_directory("target")?;
+        let options = BackupOptions {
+            fold_names: true,
+            ..BackupOptions::default()
+        };
+
+        let report = run_mirror(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        assert_eq!(report.renamed, 1);
+        assert_eq!(read_file(spec.path(("target", "README.md")))?, "upper");
+        assert_eq!(read_file(spec.path(("target", "Readme~1.md")))?, "lower");
+
+        let report = run_mirror(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.deleted, 0);
+        let entry = snapshot::find_entry(spec.path("target"), "Readme.md")?;
+        assert_eq!(
+            entry.map(|e| e.data_path),
+            Some(spec.path(("target", "Readme~1.md")))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+//! Expose snapshots as a read-only FUSE filesystem
+//!
+//! The original items are reconstructed from the snapshot, i.e., symlinks are
+//! exposed as symlinks and the recorded metadata is used for the attributes.
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs::File,
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tools_utils::{Error, Result};
+
+use super::manifest::EntryKind;
+use super::snapshot::{self, SnapshotEntry};
+
+const TTL: Duration = Duration::from_secs(1);
+
+/// Mount a single snapshot, or all snapshots of a repository
+///
+/// The call blocks until the filesystem is unmounted.
+pub fn mount(source: &Path, all_snapshots: bool, mountpoint: &Path) -> Result<()> {
+    let mut tree = SnapshotTree::new(mtime_seconds(source));
+    if all_snapshots {
+        for snapshot in snapshot::list_snapshots(source)? {
+            let name = snapshot
+                .file_name()
+                .ok_or_else(|| Error::from("mount: invalid snapshot name"))?;
+            let root = tree.add_directory(FUSE_ROOT_ID, name, mtime_seconds(&snapshot));
+            tree.add_snapshot(root, &snapshot)?;
+        }
+    } else {
+        tree.add_snapshot(FUSE_ROOT_ID, source)?;
+    }
+
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(String::from("tools-backup")),
+        MountOption::Subtype(String::from("tools-backup")),
+    ];
+    println!(
+        "Mount {:?} at {:?}, unmount with `fusermount -u {}`",
+        source,
+        mountpoint,
+        mountpoint.display()
+    );
+    fuser::mount2(SnapshotFs::new(tree), mountpoint, &options)
+        .map_err(|e| format!("mount: could not mount filesystem: {}", e))?;
+    Ok(())
+}
+
+fn mtime_seconds(path: &Path) -> i64 {
+    path.metadata()
+        .map(|m| super::manifest::mtime_seconds(&m))
+        .unwrap_or(0)
+}
+
+struct Node {
+    kind: EntryKind,
+    size: u64,
+    mtime: i64,
+    mode: Option<u32>,
+    data_path: Option<PathBuf>,
+    link_target: Option<PathBuf>,
+    rdev: u64,
+    parent: u64,
+    children: BTreeMap<OsString, u64>,
+}
+
+impl Node {
+    fn directory(parent: u64, mtime: i64) -> Self {
+        Self {
+            kind: EntryKind::Directory,
+            size: 0,
+            mtime,
+            mode: None,
+            data_path: None,
+            link_target: None,
+            rdev: 0,
+            parent,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+/// The in-memory representation of the mounted tree, inodes start at 1
+struct SnapshotTree {
+    nodes: Vec<Node>,
+}
+
+impl SnapshotTree {
+    fn new(mtime: i64) -> Self {
+        Self {
+            nodes: vec![Node::directory(FUSE_ROOT_ID, mtime)],
+        }
+    }
+
+    fn get(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn get_mut(&mut self, ino: u64) -> &mut Node {
+        &mut self.nodes[ino as usize - 1]
+    }
+
+    fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.get(parent)?.children.get(name).copied()
+    }
+
+    fn add_directory(&mut self, parent: u64, name: &OsStr, mtime: i64) -> u64 {
+        if let Some(ino) = self.lookup(parent, name) {
+            return ino;
+        }
+        self.add_node(parent, name, Node::directory(parent, mtime))
+    }
+
+    fn add_node(&mut self, parent: u64, name: &OsStr, node: Node) -> u64 {
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        self.get_mut(parent).children.insert(name.to_owned(), ino);
+        ino
+    }
+
+    fn add_snapshot(&mut self, root: u64, snapshot: &Path) -> Result<()> {
+        for entry in snapshot::read_snapshot(snapshot)? {
+            self.add_entry(root, entry)?;
+        }
+        Ok(())
+    }
+
+    fn add_entry(&mut self, root: u64, entry: SnapshotEntry) -> Result<()> {
+        let mut parent = root;
+        let mut components = entry.path.components().peekable();
+        while let Some(component) = components.next() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => return Err(Error::from(format!("Invalid entry path {:?}", entry.path))),
+            };
+            if components.peek().is_some() {
+                parent = self.add_directory(parent, name, entry.mtime);
+                continue;
+            }
+
+            if let Some(ino) = self.lookup(parent, name) {
+                // the directory may have been created implicitly
+                let node = self.get_mut(ino);
+                node.mtime = entry.mtime;
+                node.mode = entry.mode;
+                return Ok(());
+            }
+
+            let size = match entry.kind {
+                EntryKind::File => entry
+                    .data_path
+                    .metadata()
+                    .map(|m| m.len())
+                    .unwrap_or(entry.size),
+                EntryKind::Symlink => entry
+                    .link_target
+                    .as_ref()
+                    .map(|t| t.as_os_str().len() as u64)
+                    .unwrap_or_default(),
+                _ => 0,
+            };
+            let node = Node {
+                kind: entry.kind,
+                size,
+                mtime: entry.mtime,
+                mode: entry.mode,
+                data_path: Some(entry.data_path.clone()),
+                link_target: entry.link_target.clone(),
+                rdev: entry.rdev.unwrap_or_default(),
+                parent,
+                children: BTreeMap::new(),
+            };
+            self.add_node(parent, name, node);
+        }
+        Ok(())
+    }
+}
+
+fn file_type(kind: EntryKind) -> FileType {
+    match kind {
+        EntryKind::Directory => FileType::Directory,
+        EntryKind::File => FileType::RegularFile,
+        EntryKind::Symlink => FileType::Symlink,
+        EntryKind::Fifo => FileType::NamedPipe,
+        EntryKind::Socket => FileType::Socket,
+        EntryKind::BlockDevice => FileType::BlockDevice,
+        EntryKind::CharDevice => FileType::CharDevice,
+    }
+}
+
+struct SnapshotFs {
+    tree: SnapshotTree,
+    handles: HashMap<u64, File>,
+    next_handle: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl SnapshotFs {
+    fn new(tree: SnapshotTree) -> Self {
+        Self {
+            tree,
+            handles: HashMap::new(),
+            next_handle: 1,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        let time = if node.mtime >= 0 {
+            UNIX_EPOCH + Duration::from_secs(node.mtime as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs((-node.mtime) as u64)
+        };
+        let (default_mode, nlink) = match node.kind {
+            EntryKind::Directory => (0o755, 2),
+            EntryKind::Symlink => (0o777, 1),
+            _ => (0o644, 1),
+        };
+        FileAttr {
+            ino,
+            size: node.size,
+            blocks: node.size.div_ceil(512),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: file_type(node.kind),
+            perm: node.mode.unwrap_or(default_mode) as u16,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: node.rdev as u32,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for SnapshotFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.tree.lookup(parent, name) {
+            Some(ino) => {
+                let attr = self.attr(ino, self.tree.get(ino).unwrap());
+                reply.entry(&TTL, &attr, 0);
+            }
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.tree.get(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self
+            .tree
+            .get(ino)
+            .and_then(|node| node.link_target.as_ref())
+        {
+            Some(target) => reply.data(target.as_os_str().as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS);
+        }
+        let data_path = match self.tree.get(ino) {
+            Some(node) if node.kind == EntryKind::File => node.data_path.as_ref().unwrap(),
+            Some(_) => return reply.error(libc::EINVAL),
+            None => return reply.error(libc::ENOENT),
+        };
+        match File::open(data_path) {
+            Ok(file) => {
+                let fh = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(fh, file);
+                reply.opened(fh, 0);
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let file = match self.handles.get(&fh) {
+            Some(file) => file,
+            None => return reply.error(libc::EBADF),
+        };
+        let mut buffer = vec![0; size as usize];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read_at(&mut buffer[filled..], offset as u64 + filled as u64) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+            }
+        }
+        reply.data(&buffer[..filled]);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let node = match self.tree.get(ino) {
+            Some(node) if node.kind == EntryKind::Directory => node,
+            Some(_) => return reply.error(libc::ENOTDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let mut entries = vec![
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ];
+        for (name, &child) in &node.children {
+            let kind = self
+                .tree
+                .get(child)
+                .map(|c| file_type(c.kind))
+                .unwrap_or(FileType::RegularFile);
+            entries.push((child, kind, name.as_os_str()));
+        }
+
+        for (idx, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (idx + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn snapshot_tree_reconstructs_items() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("repo", "a", "foo", "bar.txt"), Some("hello"), None)?
+            .with_file(("repo", "a", "link"), Some("LINK foo/bar.txt"), None)?
+            .with_file(("repo", "b", "baz.txt"), Some("world"), None)?;
+
+        let mut tree = SnapshotTree::new(0);
+        for snapshot in snapshot::list_snapshots(spec.path("repo"))? {
+            let root = tree.add_directory(FUSE_ROOT_ID, snapshot.file_name().unwrap(), 0);
+            tree.add_snapshot(root, &snapshot)?;
+        }
+
+        let a = tree.lookup(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+        let foo = tree.lookup(a, OsStr::new("foo")).unwrap();
+        let bar = tree.lookup(foo, OsStr::new("bar.txt")).unwrap();
+        assert_eq!(tree.get(bar).unwrap().kind, EntryKind::File);
+        assert_eq!(tree.get(bar).unwrap().size, 5);
+        assert_eq!(tree.get(foo).unwrap().parent, a);
+
+        let link = tree.lookup(a, OsStr::new("link")).unwrap();
+        assert_eq!(tree.get(link).unwrap().kind, EntryKind::Symlink);
+        assert_eq!(
+            tree.get(link).unwrap().link_target,
+            Some(PathBuf::from("foo/bar.txt"))
+        );
+
+        let b = tree.lookup(FUSE_ROOT_ID, OsStr::new("b")).unwrap();
+        assert!(tree.lookup(b, OsStr::new("baz.txt")).is_some());
+        Ok(())
+    }
+}
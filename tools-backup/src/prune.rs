@@ -0,0 +1,124 @@
+//! Remove old snapshots of a repository
+//!
+//! The most recent snapshots are kept, all older ones are removed. Snapshots
+//! tagged with `keep` are never removed and do not count towards the number of
+//! snapshots kept. Only directories containing the metadata directory are
+//! considered snapshots, all others are skipped.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::Result;
+
+use super::snapshot;
+use super::tags;
+
+/// Snapshots with this tag are never pruned
+pub const KEEP_TAG: &str = "keep";
+
+/// The snapshots to remove, such that only the given number of snapshots is
+/// left besides the ones tagged with `keep`
+pub fn find_prunable(repository: impl AsRef<Path>, keep_last: usize) -> Result<Vec<PathBuf>> {
+    let mut candidates = Vec::new();
+    for path in snapshot::list_snapshots(repository)? {
+        if snapshot::is_snapshot(&path) && !tags::read_tags(&path)?.has_tag(KEEP_TAG) {
+            candidates.push(path);
+        }
+    }
+    let count = candidates.len().saturating_sub(keep_last);
+    candidates.truncate(count);
+    Ok(candidates)
+}
+
+/// Remove the snapshots returned by `find_prunable`
+pub fn prune(
+    repository: impl AsRef<Path>,
+    keep_last: usize,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>> {
+    let repository = repository.as_ref();
+    for path in snapshot::list_snapshots(repository)? {
+        if !snapshot::is_snapshot(&path) {
+            println!("SKIP {:?} [not a snapshot]", path);
+        }
+    }
+    let snapshots = find_prunable(repository, keep_last)?;
+    for snapshot in &snapshots {
+        println!("DEL  {:?}", snapshot);
+        if !dry_run {
+            fs::remove_dir_all(snapshot)
+                .map_err(|e| format!("prune: cannot remove snapshot {:?}: {}", snapshot, e))?;
+        }
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::manifest::METADATA_DIR;
+    use super::super::tags::SnapshotTags;
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn snapshots_tagged_keep_are_never_pruned() -> Result<()> {
+        let spec = Spec::new()?
+            .with_directory(("repo", "2020-01", METADATA_DIR))?
+            .with_directory(("repo", "2020-02", METADATA_DIR))?
+            .with_directory(("repo", "2020-03", METADATA_DIR))?
+            .with_directory(("repo", "2020-04", METADATA_DIR))?
+            .with_directory(("repo", "2020-05", METADATA_DIR))?;
+        let mut keep = SnapshotTags::default();
+        keep.add_tag(KEEP_TAG)?;
+        tags::write_tags(spec.path(("repo", "2020-01")), &keep)?;
+        tags::write_tags(spec.path(("repo", "2020-04")), &keep)?;
+
+        let removed = prune(spec.path("repo"), 1, true)?;
+        assert_eq!(
+            removed,
+            vec![
+                spec.path(("repo", "2020-02")),
+                spec.path(("repo", "2020-03"))
+            ]
+        );
+        assert!(spec.path(("repo", "2020-02")).exists());
+
+        prune(spec.path("repo"), 1, false)?;
+        let left = snapshot::list_snapshots(spec.path("repo"))?;
+        assert_eq!(
+            left,
+            vec![
+                spec.path(("repo", "2020-01")),
+                spec.path(("repo", "2020-04")),
+                spec.path(("repo", "2020-05")),
+            ]
+        );
+
+        // NOTE: even without any snapshot to keep, tagged ones remain
+        prune(spec.path("repo"), 0, false)?;
+        assert_eq!(
+            snapshot::list_snapshots(spec.path("repo"))?,
+            vec![
+                spec.path(("repo", "2020-01")),
+                spec.path(("repo", "2020-04"))
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn directories_without_metadata_are_never_pruned() -> Result<()> {
+        let spec = Spec::new()?
+            .with_directory(("repo", "2020-01", METADATA_DIR))?
+            .with_file(("repo", "photos", "image.jpg"), Some("image"), None)?
+            .with_directory(("repo", "old"))?;
+
+        let removed = prune(spec.path("repo"), 0, false)?;
+        assert_eq!(removed, vec![spec.path(("repo", "2020-01"))]);
+        assert_eq!(
+            snapshot::list_snapshots(spec.path("repo"))?,
+            vec![spec.path(("repo", "old")), spec.path(("repo", "photos"))]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,739 @@
+//! Push snapshots to a remote host via SFTP
+//!
+//! The stored items of the snapshot are uploaded as they are, including the
+//! snapshot metadata. Files with the same size and modification time as in the
+//! remote reference snapshot are hard-linked on the server instead of
+//! uploaded. The metadata is always uploaded, since it differs between
+//! snapshots even if size and modification time match. Since uploaded files
+//! keep the modification time of the stored file, files shared between local
+//! snapshots are also shared between the remote snapshots.
+//!
+//! The snapshot is uploaded under a temporary name inside the remote
+//! repository and only renamed to the target once complete. Partial uploads of
+//! failed or interrupted pushes are removed.
+use std::{
+    fs::{self, File},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tools_utils::{Error, Result};
+use walkdir::WalkDir;
+
+use super::backup::PARTIAL_PREFIX;
+use super::events::{Event, EventFormat, EventLog};
+use super::faults;
+use super::lock::{self, LOCK_FILE_NAME};
+use super::manifest::METADATA_DIR;
+use super::sftp::{Attributes, RequestResult, SftpClient, HARDLINK_EXTENSION};
+
+/// A path on a remote host, written as `[user@]host:path`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemotePath {
+    pub host: String,
+    pub path: String,
+}
+
+impl RemotePath {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (host, path) = match s.find(':') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => {
+                return Err(Error::from(format!(
+                    "Expected [user@]host:path, got {:?}",
+                    s
+                )))
+            }
+        };
+        if host.is_empty() || path.is_empty() {
+            return Err(Error::from(format!(
+                "Expected [user@]host:path, got {:?}",
+                s
+            )));
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// Statistics of a push
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PushReport {
+    pub uploaded_files: u64,
+    pub uploaded_bytes: u64,
+    pub linked_files: u64,
+    pub directories: u64,
+    /// Symlinks and special files, that cannot be uploaded
+    pub skipped: u64,
+}
+
+/// Upload the snapshot into the not yet existing remote `target`
+///
+/// The remote repository, i.e., the parent directory of the target, is locked
+/// while the snapshot is uploaded. The events are only printed, since the
+/// snapshot is not modified.
+pub fn push_snapshot(
+    client: &mut SftpClient,
+    snapshot: impl AsRef<Path>,
+    target: &str,
+    reference: Option<&str>,
+    format: EventFormat,
+) -> Result<PushReport> {
+    let lock_path = lock_remote_repository(client, remote_parent(target))?;
+    let mut log = EventLog::print_only(format);
+    let result = push_items(client, snapshot.as_ref(), target, reference, &mut log);
+
+    if let Err(e) = client.remove(&lock_path)? {
+        eprintln!("Could not release lock {:?}: {}", lock_path, e.message);
+    }
+    result
+}
+
+fn push_items(
+    client: &mut SftpClient,
+    snapshot: &Path,
+    target: &str,
+    reference: Option<&str>,
+    log: &mut EventLog,
+) -> Result<PushReport> {
+    match client.lstat(target)? {
+        Ok(_) => return Err(Error::from(format!("Remote target {:?} exists", target))),
+        Err(e) if e.is_not_found() => {}
+        Err(e) => {
+            return Err(Error::from(format!(
+                "push_snapshot: cannot check remote target {:?}: {}",
+                target, e.message
+            )))
+        }
+    }
+
+    if let Some(reference) = reference {
+        let reference_repository = remote_parent(reference);
+        if reference_repository != remote_parent(target) {
+            let lock_path = remote_join(reference_repository, LOCK_FILE_NAME);
+            if let Some(content) = read_remote_lock(client, &lock_path)? {
+                check_remote_lock(client, &lock_path, &content)?;
+            }
+        }
+    }
+    let reference = match reference {
+        Some(_) if !client.supports(HARDLINK_EXTENSION) => {
+            log.format().print_message(format!(
+                "The server does not support {}, all files are uploaded",
+                HARDLINK_EXTENSION
+            ));
+            None
+        }
+        reference => reference,
+    };
+
+    let partial = partial_path(target);
+    // NOTE: no other push can be running while the repository is locked
+    if client.lstat(&partial)?.is_ok() {
+        remove_tree(client, &partial)?;
+        log.record(
+            Event::new("delete")
+                .with_target(&partial)
+                .with_detail("partial upload"),
+        )?;
+    }
+    let report = match upload_items(client, snapshot, &partial, target, reference, log) {
+        Ok(report) => report,
+        Err(e) => {
+            if let Err(remove_error) = remove_tree(client, &partial) {
+                eprintln!(
+                    "Could not remove the partial upload {:?}: {}",
+                    partial, remove_error
+                );
+            }
+            return Err(e);
+        }
+    };
+    client.rename(&partial, target)?.map_err(|e| {
+        format!(
+            "push_snapshot: cannot rename {:?} to {:?}: {}",
+            partial, target, e.message
+        )
+    })?;
+    Ok(report)
+}
+
+/// Lock the remote repository like a local `RepositoryLock`
+///
+/// The lock is written under a name unique to this process and renamed into
+/// place, which fails if the repository is already locked. Stale locks are
+/// removed. Returns the path of the lock.
+fn lock_remote_repository(client: &mut SftpClient, repository: &str) -> Result<String> {
+    let path = remote_join(repository, LOCK_FILE_NAME);
+    let temp_path = unique_lock_path(&path, "tmp");
+    let content = lock::lock_file_content()?;
+    client
+        .upload(&temp_path, false, &mut content.as_bytes())?
+        .map_err(|e| {
+            format!(
+                "push_snapshot: cannot write lock file {:?}: {}",
+                temp_path, e.message
+            )
+        })?;
+
+    let result = move_remote_lock(client, &path, &temp_path);
+    if result.is_err() {
+        if let Err(e) = client.remove(&temp_path)? {
+            eprintln!(
+                "Could not remove temporary lock {:?}: {}",
+                temp_path, e.message
+            );
+        }
+    }
+    result.map(|_| path)
+}
+
+fn move_remote_lock(client: &mut SftpClient, path: &str, temp_path: &str) -> Result<()> {
+    let mut last_error = String::new();
+    for _ in 0..3 {
+        let error = match client.rename(temp_path, path)? {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let content = match read_remote_lock(client, path)? {
+            Some(content) => content,
+            // NOTE: released in the meantime
+            None => {
+                last_error = error.message;
+                continue;
+            }
+        };
+        check_remote_lock(client, path, &content)?;
+        remove_stale_remote_lock(client, path, &content)?;
+    }
+    Err(Error::from(format!(
+        "push_snapshot: could not acquire lock {:?}: {}",
+        path, last_error
+    )))
+}
+
+/// Return an error, unless the remote lock is stale
+fn check_remote_lock(client: &mut SftpClient, path: &str, content: &str) -> Result<()> {
+    let age = match client.lstat(path)? {
+        Ok(Attributes {
+            mtime: Some(mtime), ..
+        }) => SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(u64::from(mtime)))
+            .unwrap_or_default(),
+        _ => Duration::default(),
+    };
+    lock::check_stale_lock(path, content, age)
+}
+
+/// Read the remote lock file, if it exists
+fn read_remote_lock(client: &mut SftpClient, path: &str) -> Result<Option<String>> {
+    match client.read_file(path)? {
+        Ok(content) => Ok(Some(String::from_utf8_lossy(&content).into_owned())),
+        Err(e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(Error::from(format!(
+            "push_snapshot: cannot read lock file {:?}: {}",
+            path, e.message
+        ))),
+    }
+}
+
+/// Remove the stale remote lock, unless it was replaced in the meantime
+///
+/// Like a local stale lock, the lock is first moved to a name unique to this
+/// process and moved back, if it is no longer the stale lock.
+fn remove_stale_remote_lock(client: &mut SftpClient, path: &str, stale: &str) -> Result<()> {
+    let claimed = unique_lock_path(path, "stale");
+    match client.rename(path, &claimed)? {
+        Ok(()) => {}
+        Err(e) if e.is_not_found() => return Ok(()),
+        Err(e) => {
+            return Err(Error::from(format!(
+                "push_snapshot: cannot move stale lock file {:?}: {}",
+                path, e.message
+            )))
+        }
+    }
+
+    // NOTE: the rename fails instead of replacing a lock created meanwhile
+    let replaced = read_remote_lock(client, &claimed)?.as_deref() != Some(stale);
+    if replaced && client.rename(&claimed, path)?.is_ok() {
+        return Ok(());
+    }
+    client.remove(&claimed)?.map_err(|e| {
+        format!(
+            "push_snapshot: cannot remove stale lock file {:?}: {}",
+            claimed, e.message
+        )
+    })?;
+    Ok(())
+}
+
+/// A path next to the remote lock, unique to the current process
+fn unique_lock_path(path: &str, extension: &str) -> String {
+    format!(
+        "{}.{}-{}.{}",
+        path,
+        lock::hostname(),
+        std::process::id(),
+        extension
+    )
+}
+
+/// Upload the items of the snapshot into the not yet existing `partial`
+/// directory, which is renamed to `target` afterwards
+fn upload_items(
+    client: &mut SftpClient,
+    snapshot: &Path,
+    partial: &str,
+    target: &str,
+    reference: Option<&str>,
+    log: &mut EventLog,
+) -> Result<PushReport> {
+    let mut report = PushReport::default();
+    for entry in WalkDir::new(snapshot).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry.map_err(|e| format!("push_snapshot: invalid directory entry: {}", e))?;
+        let rel_path = entry
+            .path()
+            .strip_prefix(snapshot)
+            .map_err(|e| format!("push_snapshot: cannot compute relative path: {}", e))?;
+        let remote = remote_path(partial, rel_path)?;
+        let event = Event::new("directory")
+            .with_source(entry.path())
+            .with_target(remote_path(target, rel_path)?);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            faults::check("create directory")?;
+            client.mkdir(&remote)?.map_err(|e| {
+                format!(
+                    "push_snapshot: cannot create directory {:?}: {}",
+                    remote, e.message
+                )
+            })?;
+            report.directories += 1;
+            log.record(event)?;
+        } else if file_type.is_file() {
+            let metadata = entry
+                .metadata()
+                .map_err(|e| format!("push_snapshot: could not retrieve metadata: {}", e))?;
+            let attributes = file_attributes(&metadata);
+
+            let mut link_error = None;
+            let is_metadata = rel_path.starts_with(METADATA_DIR);
+            if let Some(reference) = reference.filter(|_| !is_metadata) {
+                let reference = remote_path(reference, rel_path)?;
+                match link_file(client, &reference, &remote, &attributes)? {
+                    Ok(true) => {
+                        report.linked_files += 1;
+                        log.record(
+                            Event {
+                                action: "link",
+                                ..event
+                            }
+                            .with_reference(Some(&reference)),
+                        )?;
+                        continue;
+                    }
+                    Ok(false) => {}
+                    // NOTE: e.g., if the maximum number of links is reached
+                    Err(e) => link_error = Some(e),
+                }
+            }
+
+            faults::check("upload file")?;
+            let mut file = File::open(entry.path())
+                .map_err(|e| format!("push_snapshot: cannot open file: {}", e))?;
+            let bytes = client
+                .upload(&remote, false, &mut file)?
+                .map_err(|e| format!("push_snapshot: cannot upload {:?}: {}", remote, e.message))?;
+            report.uploaded_bytes += bytes;
+            client
+                .setstat(
+                    &remote,
+                    &Attributes {
+                        size: None,
+                        ..attributes
+                    },
+                )?
+                .map_err(|e| {
+                    format!(
+                        "push_snapshot: cannot set attributes of {:?}: {}",
+                        remote, e.message
+                    )
+                })?;
+            report.uploaded_files += 1;
+
+            let mut event = Event {
+                action: "copy",
+                bytes: Some(bytes),
+                ..event
+            };
+            if let Some(e) = link_error {
+                event = event.with_detail(format!("cannot link: {}", e.message));
+            }
+            log.record(event)?;
+        } else {
+            // NOTE: the items are recorded in the uploaded manifest
+            report.skipped += 1;
+            log.record(
+                Event::new("skip")
+                    .with_source(entry.path())
+                    .with_detail("cannot upload special files"),
+            )?;
+        }
+    }
+    Ok(report)
+}
+
+/// Try to hard-link an unchanged file, the caller uploads the file otherwise
+///
+/// Returns whether the file was linked, or why linking an unchanged file
+/// failed.
+fn link_file(
+    client: &mut SftpClient,
+    reference: &str,
+    target: &str,
+    attributes: &Attributes,
+) -> RequestResult<bool> {
+    let unchanged = match client.lstat(reference)? {
+        Ok(existing) => {
+            !existing.is_dir()
+                && existing.size == attributes.size
+                && existing.mtime == attributes.mtime
+        }
+        Err(_) => false,
+    };
+    if !unchanged {
+        return Ok(Ok(false));
+    }
+
+    faults::check("link file")?;
+    Ok(client.hard_link(reference, target)?.map(|_| true))
+}
+
+/// Remove the remote item, directories with all their content
+fn remove_tree(client: &mut SftpClient, path: &str) -> Result<()> {
+    let attributes = client
+        .lstat(path)?
+        .map_err(|e| format!("remove_tree: cannot access {:?}: {}", path, e.message))?;
+    if !attributes.is_dir() {
+        return client.remove(path)?.map_err(|e| {
+            Error::from(format!(
+                "remove_tree: cannot remove {:?}: {}",
+                path, e.message
+            ))
+        });
+    }
+
+    let entries = client.read_dir(path)?.map_err(|e| {
+        format!(
+            "remove_tree: cannot read directory {:?}: {}",
+            path, e.message
+        )
+    })?;
+    for (name, _) in entries {
+        remove_tree(client, &remote_join(path, &name))?;
+    }
+    client.rmdir(path)?.map_err(|e| {
+        Error::from(format!(
+            "remove_tree: cannot remove {:?}: {}",
+            path, e.message
+        ))
+    })
+}
+
+fn file_attributes(metadata: &fs::Metadata) -> Attributes {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs().min(u64::from(u32::MAX)) as u32);
+    Attributes {
+        size: Some(metadata.len()),
+        permissions: file_permissions(metadata),
+        mtime,
+    }
+}
+
+#[cfg(unix)]
+fn file_permissions(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_permissions(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Append the relative local path to a remote path
+fn remote_path(base: &str, rel_path: &Path) -> Result<String> {
+    let mut result = String::from(base);
+    for component in rel_path.components() {
+        let component = component.as_os_str().to_str().ok_or_else(|| {
+            Error::from(format!(
+                "push_snapshot: cannot represent path as utf8: {:?}",
+                rel_path
+            ))
+        })?;
+        result = remote_join(&result, component);
+    }
+    Ok(result)
+}
+
+fn remote_join(base: &str, name: &str) -> String {
+    if base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+/// The hidden path in the remote repository the target is uploaded to
+fn partial_path(target: &str) -> String {
+    let target = target.trim_end_matches('/');
+    let name = target.rsplit('/').next().unwrap_or(target);
+    remote_join(
+        remote_parent(target),
+        &format!("{}{}", PARTIAL_PREFIX, name),
+    )
+}
+
+/// The parent directory of a remote path
+fn remote_parent(path: &str) -> &str {
+    match path.trim_end_matches('/').rfind('/') {
+        Some(0) => "/",
+        Some(idx) => &path[..idx],
+        None => ".",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backup::{run_backup, BackupOptions, NoOpIgnoreSpec};
+    #[cfg(unix)]
+    use super::super::sftp::test_server;
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn remote_paths_are_parsed() -> Result<()> {
+        assert_eq!(
+            RemotePath::parse("alice@backup.local:/backup/2020-01")?,
+            RemotePath {
+                host: String::from("alice@backup.local"),
+                path: String::from("/backup/2020-01"),
+            }
+        );
+        assert!(RemotePath::parse("/backup/2020-01").is_err());
+        assert!(RemotePath::parse("backup.local:").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn remote_paths_are_joined() -> Result<()> {
+        assert_eq!(
+            remote_path("/backup/2020-01", &Path::new("foo").join("bar.txt"))?,
+            "/backup/2020-01/foo/bar.txt"
+        );
+        assert_eq!(remote_path("/backup/", Path::new(""))?, "/backup/");
+        assert_eq!(remote_parent("/backup/2020-01"), "/backup");
+        assert_eq!(remote_parent("/backup/2020-01/"), "/backup");
+        assert_eq!(remote_parent("/backup"), "/");
+        assert_eq!(remote_parent("2020-01"), ".");
+        assert_eq!(
+            partial_path("/backup/2020-01/"),
+            "/backup/.tools-backup-partial-2020-01"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn push_links_unchanged_files() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), None)?
+            .with_file(("source", "bar", "baz.txt"), Some("world"), None)?
+            .with_directory(("repo", "2020-01"))?
+            .with_directory(("repo", "2020-02"))?
+            .with_directory("remote")?;
+        for (snapshot, reference) in &[("2020-01", None), ("2020-02", Some("2020-01"))] {
+            run_backup(
+                spec.path("source"),
+                spec.path(("repo", *snapshot)),
+                reference.map(|reference| spec.path(("repo", reference))),
+                &NoOpIgnoreSpec,
+                &BackupOptions::default(),
+            )?;
+        }
+
+        let remote = |name: &str| spec.path(("remote", name)).to_string_lossy().into_owned();
+        let mut client = test_server::connect()?;
+
+        let report = push_snapshot(
+            &mut client,
+            spec.path(("repo", "2020-01")),
+            &remote("2020-01"),
+            None,
+            EventFormat::Text,
+        )?;
+        assert_eq!(report.linked_files, 0);
+        assert!(report.uploaded_files >= 2);
+        assert_eq!(
+            read_file(spec.path(("remote", "2020-01", "foo.txt")))?,
+            "hello"
+        );
+
+        let report = push_snapshot(
+            &mut client,
+            spec.path(("repo", "2020-02")),
+            &remote("2020-02"),
+            Some(&remote("2020-01")),
+            EventFormat::Text,
+        )?;
+        assert_eq!(report.linked_files, 2);
+        assert_eq!(
+            read_file(spec.path(("remote", "2020-02", "bar", "baz.txt")))?,
+            "world"
+        );
+        assert_eq!(
+            fs::metadata(spec.path(("remote", "2020-02", "foo.txt")))
+                .unwrap()
+                .ino(),
+            fs::metadata(spec.path(("remote", "2020-01", "foo.txt")))
+                .unwrap()
+                .ino()
+        );
+        assert!(!spec.path(("remote", LOCK_FILE_NAME)).exists());
+
+        assert!(push_snapshot(
+            &mut client,
+            spec.path(("repo", "2020-02")),
+            &remote("2020-02"),
+            None,
+            EventFormat::Text
+        )
+        .is_err());
+        assert!(!spec.path(("remote", LOCK_FILE_NAME)).exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_pushes_leave_no_partial_target() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("snapshot", "foo.txt"), Some("hello"), None)?
+            .with_file(("snapshot", "bar", "baz.txt"), Some("world"), None)?
+            .with_directory("remote")?;
+        let target = spec.path(("remote", "2020-01"));
+        let target = target.to_string_lossy();
+        let mut client = test_server::connect()?;
+
+        let (steps, result) = faults::count_steps(|| {
+            push_snapshot(
+                &mut client,
+                spec.path("snapshot"),
+                &target,
+                None,
+                EventFormat::Text,
+            )
+        });
+        result?;
+        fs::remove_dir_all(spec.path(("remote", "2020-01"))).unwrap();
+
+        for step in 0..steps {
+            // NOTE: simulates a killed push, whose partial upload is removed
+            // by the next one
+            fs::create_dir(partial_path(&target)).unwrap();
+            faults::fail_at(Some(step));
+            let result = push_snapshot(
+                &mut client,
+                spec.path("snapshot"),
+                &target,
+                None,
+                EventFormat::Text,
+            );
+            faults::fail_at(None);
+            assert!(result.is_err());
+
+            let names = fs::read_dir(spec.path("remote"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert!(names.is_empty(), "step {}: {:?}", step, names);
+        }
+
+        push_snapshot(
+            &mut client,
+            spec.path("snapshot"),
+            &target,
+            None,
+            EventFormat::Text,
+        )?;
+        assert_eq!(
+            read_file(spec.path(("remote", "2020-01", "bar", "baz.txt")))?,
+            "world"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn push_fails_while_the_remote_repository_is_locked() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("snapshot", "foo.txt"), Some("hello"), None)?
+            .with_directory("remote")?;
+        let lock = lock::lock_file_content()?;
+        let spec = spec.with_file(("remote", LOCK_FILE_NAME), Some(&lock), None)?;
+        let target = spec.path(("remote", "2020-01"));
+        let mut client = test_server::connect()?;
+
+        let result = push_snapshot(
+            &mut client,
+            spec.path("snapshot"),
+            &target.to_string_lossy(),
+            None,
+            EventFormat::Text,
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            fs::read_dir(spec.path("remote")).unwrap().count(),
+            1,
+            "only the lock must be left"
+        );
+        assert_eq!(read_file(spec.path(("remote", LOCK_FILE_NAME)))?, lock);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_remote_locks_are_removed() -> Result<()> {
+        let stale = format!(
+            "pid: 999999999\nhostname: {}\ntimestamp: 0\n",
+            lock::hostname()
+        );
+        let spec = Spec::new()?
+            .with_file(("snapshot", "foo.txt"), Some("hello"), None)?
+            .with_file(("remote", LOCK_FILE_NAME), Some(&stale), None)?;
+        let target = spec.path(("remote", "2020-01"));
+        let mut client = test_server::connect()?;
+
+        push_snapshot(
+            &mut client,
+            spec.path("snapshot"),
+            &target.to_string_lossy(),
+            None,
+            EventFormat::Text,
+        )?;
+        assert_eq!(read_file(target.join("foo.txt"))?, "hello");
+        assert_eq!(
+            fs::read_dir(spec.path("remote")).unwrap().count(),
+            1,
+            "only the target must be left"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1060 @@
+//! A minimal SFTP client (protocol version 3)
+//!
+//! The client talks to the `sftp` subsystem of a remote `ssh` process via its
+//! stdin and stdout, i.e., authentication and the connection itself are
+//! handled by the `ssh` command. Only the requests required to upload
+//! snapshots are implemented. Server-side hard links use the OpenSSH extension
+//! `hardlink@openssh.com`.
+//!
+//! See: <https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02>
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    process::{Child, Command, Stdio},
+};
+use tools_utils::{Error, Result};
+
+const SFTP_VERSION: u32 = 3;
+
+/// The maximum size of the data sent with a single write request
+const WRITE_CHUNK_SIZE: usize = 32 * 1024;
+
+/// The maximum number of write requests awaiting a response
+const MAX_PENDING_WRITES: usize = 16;
+
+/// The maximum size of a packet received from the server
+const MAX_PACKET_SIZE: u32 = 256 * 1024;
+
+pub const HARDLINK_EXTENSION: &str = "hardlink@openssh.com";
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+
+/// The file attributes transferred with SFTP
+///
+/// Only the attributes present in the flags of the packet are set.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Attributes {
+    pub size: Option<u64>,
+    pub permissions: Option<u32>,
+    /// The modification time in seconds since the epoch
+    pub mtime: Option<u32>,
+}
+
+impl Attributes {
+    pub fn is_dir(&self) -> bool {
+        self.permissions
+            .map(|permissions| permissions & 0o170000 == 0o040000)
+            .unwrap_or(false)
+    }
+
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if self.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if self.mtime.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        put_u32(buffer, flags);
+        if let Some(size) = self.size {
+            put_u64(buffer, size);
+        }
+        if let Some(permissions) = self.permissions {
+            put_u32(buffer, permissions);
+        }
+        if let Some(mtime) = self.mtime {
+            // NOTE: the access time is set to the modification time
+            put_u32(buffer, mtime);
+            put_u32(buffer, mtime);
+        }
+    }
+
+    fn decode(packet: &mut Packet) -> Result<Self> {
+        let flags = packet.u32()?;
+        let mut result = Self::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            result.size = Some(packet.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            packet.u32()?;
+            packet.u32()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            result.permissions = Some(packet.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            packet.u32()?;
+            result.mtime = Some(packet.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..packet.u32()? {
+                packet.bytes()?;
+                packet.bytes()?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// The error returned by the server for a failed request
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl StatusError {
+    pub fn is_not_found(&self) -> bool {
+        self.code == SSH_FX_NO_SUCH_FILE
+    }
+}
+
+/// The result of a request, separating errors reported by the server from
+/// broken connections
+pub type RequestResult<T> = std::result::Result<std::result::Result<T, StatusError>, Error>;
+
+pub struct SftpClient {
+    reader: Box<dyn Read>,
+    writer: Box<dyn Write>,
+    child: Option<Child>,
+    next_id: u32,
+    extensions: Vec<String>,
+}
+
+impl SftpClient {
+    /// Start the SFTP subsystem on the remote host via ssh
+    ///
+    /// `ssh_command` is the command used to connect, e.g., `ssh -p 2222`, and
+    /// `host` the host to connect to, optionally with the user, e.g.,
+    /// `alice@backup.local`.
+    pub fn connect(ssh_command: &str, host: &str) -> Result<Self> {
+        let mut parts = ssh_command.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| Error::from("SftpClient: empty ssh command"))?;
+        let mut command = Command::new(program);
+        command.args(parts).arg("-s").arg(host).arg("sftp");
+        Self::spawn(command)
+    }
+
+    /// Start a process that speaks SFTP on its stdin and stdout
+    pub fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("SftpClient: cannot start {:?}: {}", command, e))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+
+        let mut result = Self::new(BufReader::new(stdout), BufWriter::new(stdin));
+        result.child = Some(child);
+        result.init()?;
+        Ok(result)
+    }
+
+    /// Wrap an existing channel, the caller has to call [`SftpClient::init`]
+    pub fn new(reader: impl Read + 'static, writer: impl Write + 'static) -> Self {
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            child: None,
+            next_id: 0,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Negotiate the protocol version and the supported extensions
+    pub fn init(&mut self) -> Result<()> {
+        let mut buffer = Vec::new();
+        put_u32(&mut buffer, SFTP_VERSION);
+        self.send(SSH_FXP_INIT, &buffer)?;
+
+        let mut packet = self.receive()?;
+        if packet.kind != SSH_FXP_VERSION {
+            return Err(Error::from(format!(
+                "SftpClient: expected version packet, got type {}",
+                packet.kind
+            )));
+        }
+        let version = packet.u32()?;
+        if version < SFTP_VERSION {
+            return Err(Error::from(format!(
+                "SftpClient: unsupported protocol version {}",
+                version
+            )));
+        }
+        while !packet.is_empty() {
+            let name = packet.string()?;
+            packet.bytes()?;
+            self.extensions.push(name);
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, extension: &str) -> bool {
+        self.extensions.iter().any(|name| name == extension)
+    }
+
+    /// The attributes of the item, without following symlinks
+    pub fn lstat(&mut self, path: &str) -> RequestResult<Attributes> {
+        let id = self.request(SSH_FXP_LSTAT, |buffer| put_str(buffer, path))?;
+        let mut packet = self.receive_response(id)?;
+        match packet.kind {
+            SSH_FXP_ATTRS => Ok(Ok(Attributes::decode(&mut packet)?)),
+            _ => Ok(Err(packet.status_error()?)),
+        }
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_MKDIR, |buffer| {
+            put_str(buffer, path);
+            Attributes::default().encode(buffer);
+        })?;
+        self.receive_status(id)
+    }
+
+    pub fn setstat(&mut self, path: &str, attributes: &Attributes) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_SETSTAT, |buffer| {
+            put_str(buffer, path);
+            attributes.encode(buffer);
+        })?;
+        self.receive_status(id)
+    }
+
+    pub fn remove(&mut self, path: &str) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_REMOVE, |buffer| put_str(buffer, path))?;
+        self.receive_status(id)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_RMDIR, |buffer| put_str(buffer, path))?;
+        self.receive_status(id)
+    }
+
+    /// Rename the item, fails if the new path exists
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_RENAME, |buffer| {
+            put_str(buffer, old_path);
+            put_str(buffer, new_path);
+        })?;
+        self.receive_status(id)
+    }
+
+    /// The names and attributes of the items inside the directory
+    ///
+    /// The entries `.` and `..` are omitted.
+    pub fn read_dir(&mut self, path: &str) -> RequestResult<Vec<(String, Attributes)>> {
+        let id = self.request(SSH_FXP_OPENDIR, |buffer| put_str(buffer, path))?;
+        let mut packet = self.receive_response(id)?;
+        if packet.kind != SSH_FXP_HANDLE {
+            return Ok(Err(packet.status_error()?));
+        }
+        let handle = packet.bytes()?;
+
+        let entries = self.read_entries(&handle);
+        let closed = self
+            .request(SSH_FXP_CLOSE, |buffer| put_bytes(buffer, &handle))
+            .and_then(|id| self.receive_status(id));
+        match (entries?, closed?) {
+            (Err(e), _) | (Ok(_), Err(e)) => Ok(Err(e)),
+            (Ok(entries), Ok(())) => Ok(Ok(entries)),
+        }
+    }
+
+    /// Read directory entries until the server reports the end
+    fn read_entries(&mut self, handle: &[u8]) -> RequestResult<Vec<(String, Attributes)>> {
+        let mut result = Vec::new();
+        loop {
+            let id = self.request(SSH_FXP_READDIR, |buffer| put_bytes(buffer, handle))?;
+            let mut packet = self.receive_response(id)?;
+            if packet.kind != SSH_FXP_NAME {
+                let error = packet.status_error()?;
+                if error.code == SSH_FX_EOF {
+                    return Ok(Ok(result));
+                }
+                return Ok(Err(error));
+            }
+            for _ in 0..packet.u32()? {
+                let name = packet.string()?;
+                // NOTE: the long name is meant for display only
+                packet.bytes()?;
+                let attributes = Attributes::decode(&mut packet)?;
+                if name != "." && name != ".." {
+                    result.push((name, attributes));
+                }
+            }
+        }
+    }
+
+    /// Create a hard-link `target` of the existing file `source`
+    pub fn hard_link(&mut self, source: &str, target: &str) -> RequestResult<()> {
+        let id = self.request(SSH_FXP_EXTENDED, |buffer| {
+            put_str(buffer, HARDLINK_EXTENSION);
+            put_str(buffer, source);
+            put_str(buffer, target);
+        })?;
+        self.receive_status(id)
+    }
+
+    /// Write the content of the reader into a new or truncated file
+    ///
+    /// With `exclusive`, the request fails if the file exists. Returns the
+    /// number of bytes written.
+    pub fn upload(
+        &mut self,
+        path: &str,
+        exclusive: bool,
+        content: &mut dyn Read,
+    ) -> RequestResult<u64> {
+        let flags = SSH_FXF_WRITE
+            | SSH_FXF_CREAT
+            | if exclusive {
+                SSH_FXF_EXCL
+            } else {
+                SSH_FXF_TRUNC
+            };
+        let id = self.request(SSH_FXP_OPEN, |buffer| {
+            put_str(buffer, path);
+            put_u32(buffer, flags);
+            Attributes::default().encode(buffer);
+        })?;
+        let mut packet = self.receive_response(id)?;
+        if packet.kind != SSH_FXP_HANDLE {
+            return Ok(Err(packet.status_error()?));
+        }
+        let handle = packet.bytes()?;
+
+        let written = self.write_all(&handle, content);
+        let closed = self
+            .request(SSH_FXP_CLOSE, |buffer| put_bytes(buffer, &handle))
+            .and_then(|id| self.receive_status(id));
+        match (written?, closed?) {
+            (Err(e), _) | (Ok(_), Err(e)) => Ok(Err(e)),
+            (Ok(bytes), Ok(())) => Ok(Ok(bytes)),
+        }
+    }
+
+    /// Read the whole content of a small file, e.g., a lock file
+    pub fn read_file(&mut self, path: &str) -> RequestResult<Vec<u8>> {
+        let id = self.request(SSH_FXP_OPEN, |buffer| {
+            put_str(buffer, path);
+            put_u32(buffer, SSH_FXF_READ);
+            Attributes::default().encode(buffer);
+        })?;
+        let mut packet = self.receive_response(id)?;
+        if packet.kind != SSH_FXP_HANDLE {
+            return Ok(Err(packet.status_error()?));
+        }
+        let handle = packet.bytes()?;
+
+        let content = self.read_chunks(&handle);
+        let closed = self
+            .request(SSH_FXP_CLOSE, |buffer| put_bytes(buffer, &handle))
+            .and_then(|id| self.receive_status(id));
+        match (content?, closed?) {
+            (Err(e), _) | (Ok(_), Err(e)) => Ok(Err(e)),
+            (Ok(content), Ok(())) => Ok(Ok(content)),
+        }
+    }
+
+    /// Read the file one chunk after the other until the server reports the end
+    fn read_chunks(&mut self, handle: &[u8]) -> RequestResult<Vec<u8>> {
+        let mut result = Vec::new();
+        loop {
+            let id = self.request(SSH_FXP_READ, |buffer| {
+                put_bytes(buffer, handle);
+                put_u64(buffer, result.len() as u64);
+                put_u32(buffer, WRITE_CHUNK_SIZE as u32);
+            })?;
+            let mut packet = self.receive_response(id)?;
+            if packet.kind != SSH_FXP_DATA {
+                let error = packet.status_error()?;
+                if error.code == SSH_FX_EOF {
+                    return Ok(Ok(result));
+                }
+                return Ok(Err(error));
+            }
+            result.extend(packet.bytes()?);
+        }
+    }
+
+    /// Send the content in chunks, with multiple requests in flight
+    fn write_all(&mut self, handle: &[u8], content: &mut dyn Read) -> RequestResult<u64> {
+        let mut chunk = vec![0; WRITE_CHUNK_SIZE];
+        let mut pending = VecDeque::new();
+        let mut offset = 0;
+        let mut result = Ok(());
+
+        loop {
+            let read = match content.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::from(format!("SftpClient: cannot read: {}", e))),
+            };
+            if read == 0 {
+                break;
+            }
+            let id = self.request(SSH_FXP_WRITE, |buffer| {
+                put_bytes(buffer, handle);
+                put_u64(buffer, offset);
+                put_bytes(buffer, &chunk[..read]);
+            })?;
+            pending.push_back(id);
+            offset += read as u64;
+
+            if pending.len() >= MAX_PENDING_WRITES {
+                let id = pending.pop_front().unwrap();
+                result = result.and(self.receive_status(id)?);
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        // NOTE: all responses have to be read to keep the channel in sync
+        for id in pending {
+            result = result.and(self.receive_status(id)?);
+        }
+        Ok(result.map(|_| offset))
+    }
+
+    fn request(&mut self, kind: u8, build: impl FnOnce(&mut Vec<u8>)) -> Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut buffer = Vec::new();
+        put_u32(&mut buffer, id);
+        build(&mut buffer);
+        self.send(kind, &buffer)?;
+        Ok(id)
+    }
+
+    fn send(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let length = (payload.len() + 1) as u32;
+        self.writer
+            .write_all(&length.to_be_bytes())
+            .and_then(|_| self.writer.write_all(&[kind]))
+            .and_then(|_| self.writer.write_all(payload))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| Error::from(format!("SftpClient: cannot send request: {}", e)))
+    }
+
+    fn receive(&mut self) -> Result<Packet> {
+        let mut header = [0; 4];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(|e| format!("SftpClient: cannot receive response: {}", e))?;
+        let length = u32::from_be_bytes(header);
+        if length == 0 || length > MAX_PACKET_SIZE {
+            return Err(Error::from(format!(
+                "SftpClient: invalid packet length {}",
+                length
+            )));
+        }
+        let mut data = vec![0; length as usize];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|e| format!("SftpClient: cannot receive response: {}", e))?;
+        Ok(Packet {
+            kind: data[0],
+            data,
+            offset: 1,
+        })
+    }
+
+    fn receive_response(&mut self, id: u32) -> Result<Packet> {
+        let mut packet = self.receive()?;
+        let received = packet.u32()?;
+        if received != id {
+            return Err(Error::from(format!(
+                "SftpClient: expected response {}, got {}",
+                id, received
+            )));
+        }
+        Ok(packet)
+    }
+
+    fn receive_status(&mut self, id: u32) -> RequestResult<()> {
+        let mut packet = self.receive_response(id)?;
+        let error = packet.status_error()?;
+        if error.code == SSH_FX_OK {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(error))
+        }
+    }
+}
+
+impl Drop for SftpClient {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // NOTE: closing stdin ends the session
+            self.writer = Box::new(io::sink());
+            let _ = child.wait();
+        }
+    }
+}
+
+/// A received packet with a cursor into its payload
+struct Packet {
+    kind: u8,
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl Packet {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.offset + n;
+        if end > self.data.len() {
+            return Err(Error::from("SftpClient: truncated packet"));
+        }
+        let result = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(result)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+
+    fn status_error(&mut self) -> Result<StatusError> {
+        if self.kind != SSH_FXP_STATUS {
+            return Err(Error::from(format!(
+                "SftpClient: unexpected packet type {}",
+                self.kind
+            )));
+        }
+        let code = self.u32()?;
+        // NOTE: servers before version 3 omit the message
+        let message = if self.is_empty() {
+            String::new()
+        } else {
+            self.string()?
+        };
+        Ok(StatusError { code, message })
+    }
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buffer, value.len() as u32);
+    buffer.extend_from_slice(value);
+}
+
+fn put_str(buffer: &mut Vec<u8>, value: &str) {
+    put_bytes(buffer, value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    /// Collect the requests sent by the client
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(kind: u8, build: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut payload = Vec::new();
+        build(&mut payload);
+        let mut result = Vec::new();
+        put_u32(&mut result, payload.len() as u32 + 1);
+        result.push(kind);
+        result.extend(payload);
+        result
+    }
+
+    fn status(id: u32, code: u32) -> Vec<u8> {
+        packet(SSH_FXP_STATUS, |buffer| {
+            put_u32(buffer, id);
+            put_u32(buffer, code);
+            put_str(buffer, "message");
+            put_str(buffer, "");
+        })
+    }
+
+    fn client(responses: Vec<Vec<u8>>) -> (SftpClient, SharedBuffer) {
+        let requests = SharedBuffer::default();
+        let responses = Cursor::new(responses.concat());
+        (SftpClient::new(responses, requests.clone()), requests)
+    }
+
+    #[test]
+    fn handshake_records_extensions() -> Result<()> {
+        let (mut client, requests) = client(vec![packet(SSH_FXP_VERSION, |buffer| {
+            put_u32(buffer, 3);
+            put_str(buffer, HARDLINK_EXTENSION);
+            put_str(buffer, "1");
+        })]);
+        client.init()?;
+
+        assert!(client.supports(HARDLINK_EXTENSION));
+        assert!(!client.supports("posix-rename@openssh.com"));
+        assert_eq!(
+            *requests.0.lock().unwrap(),
+            packet(SSH_FXP_INIT, |buffer| put_u32(buffer, 3))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn attributes_and_errors_are_decoded() -> Result<()> {
+        let (mut client, _) = client(vec![
+            packet(SSH_FXP_ATTRS, |buffer| {
+                put_u32(buffer, 0);
+                put_u32(
+                    buffer,
+                    SSH_FILEXFER_ATTR_SIZE
+                        | SSH_FILEXFER_ATTR_UIDGID
+                        | SSH_FILEXFER_ATTR_PERMISSIONS
+                        | SSH_FILEXFER_ATTR_ACMODTIME,
+                );
+                put_u64(buffer, 42);
+                put_u32(buffer, 1000);
+                put_u32(buffer, 1000);
+                put_u32(buffer, 0o100644);
+                put_u32(buffer, 10);
+                put_u32(buffer, 20);
+            }),
+            status(1, SSH_FX_NO_SUCH_FILE),
+        ]);
+
+        let attributes = client.lstat("foo.txt")?.unwrap();
+        assert_eq!(
+            attributes,
+            Attributes {
+                size: Some(42),
+                permissions: Some(0o100644),
+                mtime: Some(20),
+            }
+        );
+        assert!(!attributes.is_dir());
+
+        let error = client.lstat("bar.txt")?.unwrap_err();
+        assert!(error.is_not_found());
+        Ok(())
+    }
+
+    #[test]
+    fn uploads_are_split_into_chunks() -> Result<()> {
+        let (mut client, requests) = client(vec![
+            packet(SSH_FXP_HANDLE, |buffer| {
+                put_u32(buffer, 0);
+                put_str(buffer, "h");
+            }),
+            status(1, SSH_FX_OK),
+            status(2, SSH_FX_OK),
+            status(3, SSH_FX_OK),
+        ]);
+
+        let content = vec![1u8; WRITE_CHUNK_SIZE + 10];
+        let written = client.upload("foo.txt", false, &mut content.as_slice())?;
+        assert_eq!(written, Ok(content.len() as u64));
+
+        let requests = requests.0.lock().unwrap();
+        let expected = [
+            packet(SSH_FXP_OPEN, |buffer| {
+                put_u32(buffer, 0);
+                put_str(buffer, "foo.txt");
+                put_u32(buffer, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC);
+                put_u32(buffer, 0);
+            }),
+            packet(SSH_FXP_WRITE, |buffer| {
+                put_u32(buffer, 1);
+                put_str(buffer, "h");
+                put_u64(buffer, 0);
+                put_bytes(buffer, &content[..WRITE_CHUNK_SIZE]);
+            }),
+            packet(SSH_FXP_WRITE, |buffer| {
+                put_u32(buffer, 2);
+                put_str(buffer, "h");
+                put_u64(buffer, WRITE_CHUNK_SIZE as u64);
+                put_bytes(buffer, &content[WRITE_CHUNK_SIZE..]);
+            }),
+            packet(SSH_FXP_CLOSE, |buffer| {
+                put_u32(buffer, 3);
+                put_str(buffer, "h");
+            }),
+        ]
+        .concat();
+        assert_eq!(*requests, expected);
+        Ok(())
+    }
+}
+
+/// An in-process SFTP server serving the local filesystem
+///
+/// The server runs in its own thread and only supports the requests sent by
+/// the client. Paths are used as they are, i.e., tests pass absolute paths
+/// inside their temporary directory.
+#[cfg(all(test, unix))]
+pub mod test_server {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        fs::{self, File, OpenOptions},
+        io::{Seek, SeekFrom},
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        thread,
+        time::UNIX_EPOCH,
+    };
+
+    const SSH_FX_FAILURE: u32 = 4;
+    const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+    /// Start a server thread and return a connected client
+    ///
+    /// The server stops when the client is dropped.
+    pub fn connect() -> Result<SftpClient> {
+        let (client, server) = UnixStream::pair()
+            .map_err(|e| format!("test_server: cannot create socket pair: {}", e))?;
+        let reader = client
+            .try_clone()
+            .map_err(|e| format!("test_server: cannot clone socket: {}", e))?;
+        thread::spawn(move || {
+            let mut server = Server {
+                stream: server,
+                handles: HashMap::new(),
+                next_handle: 0,
+            };
+            // NOTE: the connection is closed when the client is dropped
+            while let Some(packet) = server.receive() {
+                server.handle(packet);
+            }
+        });
+
+        let mut result = SftpClient::new(BufReader::new(reader), BufWriter::new(client));
+        result.init()?;
+        Ok(result)
+    }
+
+    struct Server {
+        stream: UnixStream,
+        handles: HashMap<Vec<u8>, Handle>,
+        next_handle: u32,
+    }
+
+    enum Handle {
+        File(File),
+        /// The remaining entries of a directory
+        Directory(Vec<(String, Attributes)>),
+    }
+
+    fn attributes(metadata: &fs::Metadata) -> Attributes {
+        Attributes {
+            size: Some(metadata.len()),
+            permissions: Some(metadata.permissions().mode()),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as u32),
+        }
+    }
+
+    impl Server {
+        fn receive(&mut self) -> Option<Packet> {
+            let mut header = [0; 4];
+            self.stream.read_exact(&mut header).ok()?;
+            let mut data = vec![0; u32::from_be_bytes(header) as usize];
+            self.stream.read_exact(&mut data).ok()?;
+            Some(Packet {
+                kind: data[0],
+                data,
+                offset: 1,
+            })
+        }
+
+        fn send(&mut self, kind: u8, build: impl FnOnce(&mut Vec<u8>)) {
+            let mut payload = vec![kind];
+            build(&mut payload);
+            let mut buffer = Vec::new();
+            put_bytes(&mut buffer, &payload);
+            self.stream.write_all(&buffer).unwrap();
+        }
+
+        fn send_status(&mut self, id: u32, result: io::Result<()>) {
+            let (code, message) = match result {
+                Ok(()) => (SSH_FX_OK, String::new()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    (SSH_FX_NO_SUCH_FILE, e.to_string())
+                }
+                Err(e) => (SSH_FX_FAILURE, e.to_string()),
+            };
+            self.send(SSH_FXP_STATUS, |buffer| {
+                put_u32(buffer, id);
+                put_u32(buffer, code);
+                put_str(buffer, &message);
+                put_str(buffer, "");
+            });
+        }
+
+        fn send_handle(&mut self, id: u32, handle: io::Result<Handle>) {
+            match handle {
+                Ok(handle) => {
+                    let name = self.next_handle.to_string().into_bytes();
+                    self.next_handle += 1;
+                    self.handles.insert(name.clone(), handle);
+                    self.send(SSH_FXP_HANDLE, |buffer| {
+                        put_u32(buffer, id);
+                        put_bytes(buffer, &name);
+                    });
+                }
+                Err(e) => self.send_status(id, Err(e)),
+            }
+        }
+
+        fn handle(&mut self, mut packet: Packet) {
+            if packet.kind == SSH_FXP_INIT {
+                self.send(SSH_FXP_VERSION, |buffer| {
+                    put_u32(buffer, SFTP_VERSION);
+                    put_str(buffer, HARDLINK_EXTENSION);
+                    put_str(buffer, "1");
+                });
+                return;
+            }
+            let id = packet.u32().unwrap();
+            let kind = packet.kind;
+            match kind {
+                SSH_FXP_LSTAT => {
+                    let path = packet.string().unwrap();
+                    match fs::symlink_metadata(&path) {
+                        Ok(metadata) => {
+                            let attributes = attributes(&metadata);
+                            self.send(SSH_FXP_ATTRS, |buffer| {
+                                put_u32(buffer, id);
+                                attributes.encode(buffer);
+                            });
+                        }
+                        Err(e) => self.send_status(id, Err(e)),
+                    }
+                }
+                SSH_FXP_MKDIR => {
+                    let path = packet.string().unwrap();
+                    self.send_status(id, fs::create_dir(path));
+                }
+                SSH_FXP_OPEN => {
+                    let path = packet.string().unwrap();
+                    let flags = packet.u32().unwrap();
+                    let file = OpenOptions::new()
+                        .read(flags & SSH_FXF_READ != 0)
+                        .write(flags & SSH_FXF_WRITE != 0)
+                        .create(flags & SSH_FXF_CREAT != 0 && flags & SSH_FXF_EXCL == 0)
+                        .create_new(flags & SSH_FXF_EXCL != 0)
+                        .truncate(flags & SSH_FXF_TRUNC != 0)
+                        .open(path);
+                    self.send_handle(id, file.map(Handle::File));
+                }
+                SSH_FXP_OPENDIR => {
+                    let path = packet.string().unwrap();
+                    let entries = fs::read_dir(path).and_then(|entries| {
+                        let mut result = vec![(String::from("."), Attributes::default())];
+                        for entry in entries {
+                            let entry = entry?;
+                            let name = entry.file_name().to_string_lossy().into_owned();
+                            result.push((name, attributes(&entry.metadata()?)));
+                        }
+                        Ok(result)
+                    });
+                    self.send_handle(id, entries.map(Handle::Directory));
+                }
+                SSH_FXP_READDIR => {
+                    let handle = packet.bytes().unwrap();
+                    let entries = match self.handles.get_mut(&handle) {
+                        Some(Handle::Directory(entries)) => std::mem::take(entries),
+                        _ => panic!("invalid directory handle"),
+                    };
+                    if entries.is_empty() {
+                        self.send(SSH_FXP_STATUS, |buffer| {
+                            put_u32(buffer, id);
+                            put_u32(buffer, SSH_FX_EOF);
+                            put_str(buffer, "end of directory");
+                            put_str(buffer, "");
+                        });
+                    } else {
+                        self.send(SSH_FXP_NAME, |buffer| {
+                            put_u32(buffer, id);
+                            put_u32(buffer, entries.len() as u32);
+                            for (name, attributes) in &entries {
+                                put_str(buffer, name);
+                                put_str(buffer, name);
+                                attributes.encode(buffer);
+                            }
+                        });
+                    }
+                }
+                SSH_FXP_WRITE => {
+                    let handle = packet.bytes().unwrap();
+                    let offset = packet.u64().unwrap();
+                    let data = packet.bytes().unwrap();
+                    let file = match self.handles.get_mut(&handle) {
+                        Some(Handle::File(file)) => file,
+                        _ => panic!("invalid file handle"),
+                    };
+                    let result = file
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.write_all(&data));
+                    self.send_status(id, result);
+                }
+                SSH_FXP_READ => {
+                    let handle = packet.bytes().unwrap();
+                    let offset = packet.u64().unwrap();
+                    let length = packet.u32().unwrap();
+                    let file = match self.handles.get_mut(&handle) {
+                        Some(Handle::File(file)) => file,
+                        _ => panic!("invalid file handle"),
+                    };
+                    let mut data = vec![0; length as usize];
+                    let read = file
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.read(&mut data));
+                    match read {
+                        Ok(0) => self.send(SSH_FXP_STATUS, |buffer| {
+                            put_u32(buffer, id);
+                            put_u32(buffer, SSH_FX_EOF);
+                            put_str(buffer, "end of file");
+                            put_str(buffer, "");
+                        }),
+                        Ok(read) => self.send(SSH_FXP_DATA, |buffer| {
+                            put_u32(buffer, id);
+                            put_bytes(buffer, &data[..read]);
+                        }),
+                        Err(e) => self.send_status(id, Err(e)),
+                    }
+                }
+                SSH_FXP_CLOSE => {
+                    let handle = packet.bytes().unwrap();
+                    self.handles.remove(&handle);
+                    self.send_status(id, Ok(()));
+                }
+                SSH_FXP_SETSTAT => {
+                    let path = packet.string().unwrap();
+                    let attributes = Attributes::decode(&mut packet).unwrap();
+                    let mut result = Ok(());
+                    if let Some(permissions) = attributes.permissions {
+                        result = result.and_then(|_| {
+                            fs::set_permissions(&path, fs::Permissions::from_mode(permissions))
+                        });
+                    }
+                    if let Some(mtime) = attributes.mtime {
+                        let mtime = u64::from(mtime);
+                        result = result.and_then(|_| utime::set_file_times(&path, mtime, mtime));
+                    }
+                    self.send_status(id, result);
+                }
+                SSH_FXP_REMOVE => {
+                    let path = packet.string().unwrap();
+                    self.send_status(id, fs::remove_file(path));
+                }
+                SSH_FXP_RMDIR => {
+                    let path = packet.string().unwrap();
+                    self.send_status(id, fs::remove_dir(path));
+                }
+                SSH_FXP_RENAME => {
+                    let old_path = packet.string().unwrap();
+                    let new_path = packet.string().unwrap();
+                    // NOTE: unlike rename(2), SFTP does not replace existing items
+                    let result = match fs::symlink_metadata(&new_path) {
+                        Ok(_) => Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "the new path exists",
+                        )),
+                        Err(_) => fs::rename(old_path, new_path),
+                    };
+                    self.send_status(id, result);
+                }
+                SSH_FXP_EXTENDED if packet.string().unwrap() == HARDLINK_EXTENSION => {
+                    let source = packet.string().unwrap();
+                    let target = packet.string().unwrap();
+                    self.send_status(id, fs::hard_link(source, target));
+                }
+                _ => self.send(SSH_FXP_STATUS, |buffer| {
+                    put_u32(buffer, id);
+                    put_u32(buffer, SSH_FX_OP_UNSUPPORTED);
+                    put_str(buffer, "unsupported request");
+                    put_str(buffer, "");
+                }),
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Helpers to read existing snapshots
+//!
+//! Snapshots with a manifest are read using the recorded metadata. For older
+//! snapshots without manifest, the metadata is taken from the stored files and
+//! symlinks are detected by their `LINK ...` placeholder content.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+use walkdir::WalkDir;
+
+use super::manifest::{self, EntryKind, ManifestEntry, METADATA_DIR};
+
+/// Placeholders larger than this size are never interpreted as symlinks
+const MAX_PLACEHOLDER_SIZE: u64 = 4096;
+
+/// An item of a snapshot with its original metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    /// The original path relative to the backup root
+    pub path: PathBuf,
+    /// The path of the stored item
+    pub data_path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// The modification time in seconds since the epoch
+    pub mtime: i64,
+    pub mode: Option<u32>,
+    pub link_target: Option<PathBuf>,
+    /// The device number of block and character devices
+    pub rdev: Option<u64>,
+}
+
+/// List all snapshots of a repository sorted by name
+///
+/// Snapshots are all directories of the repository, that are not hidden.
+pub fn list_snapshots(repository: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let repository = repository.as_ref();
+    let mut result = Vec::new();
+    for entry in repository.read_dir().map_err(|e| {
+        format!(
+            "list_snapshots: cannot read directory {:?}: {}",
+            repository, e
+        )
+    })? {
+        let entry = entry.map_err(|e| format!("list_snapshots: cannot read entry: {}", e))?;
+        let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !is_hidden && entry.path().is_dir() {
+            result.push(entry.path());
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Check whether a directory was written by a backup, i.e., whether it
+/// contains the metadata directory
+///
+/// Snapshots of older versions without metadata directory are not recognized.
+pub fn is_snapshot(path: impl AsRef<Path>) -> bool {
+    path.as_ref().join(METADATA_DIR).is_dir()
+}
+
+/// Read all entries of a snapshot, sorted by path
+pub fn read_snapshot(snapshot: impl AsRef<Path>) -> Result<Vec<SnapshotEntry>> {
+    let snapshot = snapshot.as_ref();
+    let mut result = match manifest::read_manifest(snapshot)? {
+        Some(entries) => entries
+            .into_iter()
+            .map(|entry| from_manifest_entry(snapshot, entry))
+            .collect(),
+        None => read_snapshot_without_manifest(snapshot)?,
+    };
+    result.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+/// Find a single entry of a snapshot by its original relative path
+pub fn find_entry(
+    snapshot: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+) -> Result<Option<SnapshotEntry>> {
+    let snapshot = snapshot.as_ref();
+    let path = manifest::manifest_path(path.as_ref());
+
+    if let Some(entries) = manifest::read_manifest(snapshot)? {
+        let entry = entries
+            .into_iter()
+            .find(|entry| entry.path == path)
+            .map(|entry| from_manifest_entry(snapshot, entry));
+        return Ok(entry);
+    }
+
+    let data_path = snapshot.join(manifest::native_path(&path));
+    let metadata = match data_path.metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(from_stored_item(
+        manifest::native_path(&path),
+        data_path,
+        &metadata,
+    )?))
+}
+
+fn from_manifest_entry(snapshot: &Path, entry: ManifestEntry) -> SnapshotEntry {
+    SnapshotEntry {
+        path: manifest::native_path(&entry.path),
+        data_path: snapshot.join(manifest::native_path(entry.stored_path())),
+        kind: entry.kind,
+        size: entry.size,
+        mtime: entry.mtime,
+        mode: entry.mode,
+        link_target: entry.link_target.map(PathBuf::from),
+        rdev: entry.rdev,
+    }
+}
+
+fn read_snapshot_without_manifest(snapshot: &Path) -> Result<Vec<SnapshotEntry>> {
+    let mut result = Vec::new();
+    let walker = WalkDir::new(snapshot)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != METADATA_DIR);
+
+    for entry in walker {
+        let entry = entry.map_err(|e| format!("read_snapshot: Invalid directory entry: {}", e))?;
+        let metadata = entry
+            .metadata()
+            .map_err(|e| format!("read_snapshot: could not retrieve metadata: {}", e))?;
+        let path = entry
+            .path()
+            .strip_prefix(snapshot)
+            .map_err(|e| format!("Cannot determine relative path: {}", e))?
+            .to_owned();
+        result.push(from_stored_item(path, entry.path().to_owned(), &metadata)?);
+    }
+    Ok(result)
+}
+
+fn from_stored_item(
+    path: PathBuf,
+    data_path: PathBuf,
+    metadata: &fs::Metadata,
+) -> Result<SnapshotEntry> {
+    let (kind, link_target) = if metadata.is_dir() {
+        (EntryKind::Directory, None)
+    } else {
+        match read_link_placeholder(&data_path, metadata.len())? {
+            Some(target) => (EntryKind::Symlink, Some(target)),
+            None => (EntryKind::File, None),
+        }
+    };
+
+    Ok(SnapshotEntry {
+        path,
+        data_path,
+        kind,
+        size: if kind == EntryKind::File {
+            metadata.len()
+        } else {
+            0
+        },
+        mtime: manifest::mtime_seconds(metadata),
+        mode: None,
+        link_target,
+        rdev: None,
+    })
+}
+
+/// Read the target of a symlink stored as `LINK ...` placeholder
+pub fn read_link_placeholder(path: &Path, size: u64) -> Result<Option<PathBuf>> {
+    if size > MAX_PLACEHOLDER_SIZE {
+        return Ok(None);
+    }
+    let content = fs::read(path)
+        .map_err(|e| Error::from(format!("read_link_placeholder: cannot read file: {}", e)))?;
+    let result = std::str::from_utf8(&content)
+        .ok()
+        .and_then(|s| s.strip_prefix("LINK "))
+        .map(PathBuf::from);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backup::{run_backup, BackupOptions, NoOpIgnoreSpec};
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn read_snapshot_with_manifest() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "foo.txt"), Some("hello"), None)?
+            .with_file(("source", "bar", "baz.txt"), Some("world"), None)?;
+        run_backup(
+            spec.path("source"),
+            spec.path("target"),
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &BackupOptions::default(),
+        )?;
+
+        let entries = read_snapshot(spec.path("target"))?;
+        let paths = entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("bar"),
+                Path::new("bar").join("baz.txt"),
+                PathBuf::from("foo.txt"),
+            ]
+        );
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[2].kind, EntryKind::File);
+        assert_eq!(entries[2].size, 5);
+        assert_eq!(entries[2].data_path, spec.path(("target", "foo.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn read_snapshot_without_manifest_detects_symlinks() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("snapshot", "foo.txt"), Some("hello"), None)?
+            .with_file(("snapshot", "link"), Some("LINK foo.txt"), None)?;
+
+        let entries = read_snapshot(spec.path("snapshot"))?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].path, PathBuf::from("link"));
+        assert_eq!(entries[1].kind, EntryKind::Symlink);
+        assert_eq!(entries[1].link_target, Some(PathBuf::from("foo.txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn list_snapshots_skips_hidden_entries() -> Result<()> {
+        let spec = Spec::new()?
+            .with_directory(("repo", "2020-04-12"))?
+            .with_directory(("repo", "2020-03-09"))?
+            .with_directory(("repo", ".hidden"))?
+            .with_file(("repo", "file.txt"), None, None)?;
+
+        let snapshots = list_snapshots(spec.path("repo"))?;
+        assert_eq!(
+            snapshots,
+            vec![
+                spec.path(("repo", "2020-03-09")),
+                spec.path(("repo", "2020-04-12"))
+            ]
+        );
+        Ok(())
+    }
+}
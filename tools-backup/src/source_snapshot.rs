@@ -0,0 +1,494 @@
+//! Back up from a point-in-time snapshot of the source filesystem
+//!
+//! Copying a live tree item by item results in an inconsistent view, if it is
+//! modified during the backup. Therefore, a read-only snapshot of the source
+//! can be created before the backup, which then reads from the snapshot
+//! instead of the source. The snapshot is removed after the backup, also if
+//! it failed. Items are reported under their original source path, see
+//! `BackupOptions::display_source`.
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tools_utils::{Error, Result};
+
+/// How the snapshot of the source is created
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotMethod {
+    /// A read-only snapshot of the btrfs subvolume containing the source
+    Btrfs,
+    /// A snapshot of the LVM logical volume containing the source, with the
+    /// given size reserved for changes, e.g., `1G`
+    Lvm(String),
+    /// A read-only bind mount of the source. It protects the source against
+    /// modifications, but does not give a point-in-time view.
+    Bind,
+}
+
+impl SnapshotMethod {
+    pub fn parse(s: &str, lvm_size: &str) -> Result<Self> {
+        match s {
+            "btrfs" => Ok(Self::Btrfs),
+            "lvm" => Ok(Self::Lvm(lvm_size.to_owned())),
+            "bind" => Ok(Self::Bind),
+            _ => Err(Error::from(format!("Unknown snapshot method {:?}", s))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Btrfs => "btrfs",
+            Self::Lvm(_) => "lvm",
+            Self::Bind => "bind",
+        }
+    }
+}
+
+/// A snapshot of the source, that is removed when dropped
+pub struct SourceSnapshot {
+    /// The path of the source inside the snapshot
+    path: PathBuf,
+    /// The commands to remove the snapshot, executed in order
+    cleanup: Vec<Vec<OsString>>,
+    /// The directory the snapshot is mounted at, removed after the cleanup
+    mountpoint: Option<PathBuf>,
+}
+
+impl SourceSnapshot {
+    #[cfg(target_os = "linux")]
+    pub fn create(source: &Path, method: &SnapshotMethod) -> Result<Self> {
+        let source = source
+            .canonicalize()
+            .map_err(|e| format!("SourceSnapshot: cannot resolve source {:?}: {}", source, e))?;
+        match method {
+            SnapshotMethod::Btrfs => Self::create_btrfs(&source),
+            SnapshotMethod::Lvm(size) => Self::create_lvm(&source, size),
+            SnapshotMethod::Bind => Self::create_bind(&source),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn create(_source: &Path, method: &SnapshotMethod) -> Result<Self> {
+        Err(Error::from(format!(
+            "{} snapshots are only supported on Linux",
+            method.name()
+        )))
+    }
+
+    /// The path to back up instead of the source
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the snapshot and report any errors
+    pub fn remove(mut self) -> Result<()> {
+        self.cleanup()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_btrfs(source: &Path) -> Result<Self> {
+        let subvolume = btrfs_subvolume(source)?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .map_err(|e| format!("SourceSnapshot: cannot read mount table: {}", e))?;
+        let directory = btrfs_snapshot_directory(&parse_mountinfo(&mountinfo), &subvolume);
+        if directory.exists() {
+            remove_stale_btrfs_snapshots(&directory)?;
+        } else {
+            std::fs::create_dir(&directory).map_err(|e| {
+                format!(
+                    "SourceSnapshot: cannot create directory {:?}: {}",
+                    directory, e
+                )
+            })?;
+        }
+
+        let snapshot = directory.join(format!("snapshot-{}", std::process::id()));
+        run_command(&command(&[
+            "btrfs".as_ref(),
+            "subvolume".as_ref(),
+            "snapshot".as_ref(),
+            "-r".as_ref(),
+            subvolume.as_os_str(),
+            snapshot.as_os_str(),
+        ]))?;
+
+        Ok(Self {
+            path: snapshot.join(relative_to(source, &subvolume)?),
+            cleanup: vec![command(&[
+                "btrfs".as_ref(),
+                "subvolume".as_ref(),
+                "delete".as_ref(),
+                snapshot.as_os_str(),
+            ])],
+            mountpoint: None,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_lvm(source: &Path, size: &str) -> Result<Self> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .map_err(|e| format!("SourceSnapshot: cannot read mount table: {}", e))?;
+        let mounts = parse_mountinfo(&mountinfo);
+        let mount = find_mount(&mounts, source)
+            .ok_or_else(|| format!("SourceSnapshot: cannot find mount of {:?}", source))?;
+
+        let output = Command::new("lvs")
+            .args(["--noheadings", "-o", "vg_name,lv_name"])
+            .arg(&mount.device)
+            .output()
+            .map_err(|e| format!("SourceSnapshot: cannot execute lvs: {}", e))?;
+        let volume = String::from_utf8_lossy(&output.stdout);
+        let group = match volume.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [group, _] if output.status.success() => group.to_string(),
+            _ => {
+                return Err(Error::from(format!(
+                    "SourceSnapshot: {:?} is not a logical volume",
+                    mount.device
+                )))
+            }
+        };
+
+        let name = format!("tools-backup-snapshot-{}", std::process::id());
+        let mut result = Self {
+            path: PathBuf::new(),
+            cleanup: Vec::new(),
+            mountpoint: None,
+        };
+        run_command(&command(&[
+            "lvcreate".as_ref(),
+            "--snapshot".as_ref(),
+            "--size".as_ref(),
+            size.as_ref(),
+            "--name".as_ref(),
+            name.as_ref(),
+            mount.device.as_os_str(),
+        ]))?;
+        let lvm_path = format!("{}/{}", group, name);
+        result.cleanup.push(command(&[
+            "lvremove".as_ref(),
+            "--force".as_ref(),
+            lvm_path.as_ref(),
+        ]));
+
+        // NOTE: on drop, the volume is removed if the mount fails
+        let mountpoint = create_mountpoint()?;
+        result.mountpoint = Some(mountpoint.clone());
+        // NOTE: XFS refuses to mount a second filesystem with the same UUID
+        let options = if mount.fstype == "xfs" {
+            "ro,nouuid"
+        } else {
+            "ro"
+        };
+        let device = PathBuf::from("/dev").join(&group).join(&name);
+        run_command(&command(&[
+            "mount".as_ref(),
+            "-o".as_ref(),
+            options.as_ref(),
+            device.as_os_str(),
+            mountpoint.as_os_str(),
+        ]))?;
+        result
+            .cleanup
+            .insert(0, command(&["umount".as_ref(), mountpoint.as_os_str()]));
+
+        result.path = mountpoint
+            .join(relative_to(&mount.root, Path::new("/"))?)
+            .join(relative_to(source, &mount.mountpoint)?);
+        Ok(result)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_bind(source: &Path) -> Result<Self> {
+        let mountpoint = create_mountpoint()?;
+        let mut result = Self {
+            path: mountpoint.clone(),
+            cleanup: Vec::new(),
+            mountpoint: Some(mountpoint.clone()),
+        };
+        run_command(&command(&[
+            "mount".as_ref(),
+            "--bind".as_ref(),
+            source.as_os_str(),
+            mountpoint.as_os_str(),
+        ]))?;
+        result
+            .cleanup
+            .push(command(&["umount".as_ref(), mountpoint.as_os_str()]));
+        run_command(&command(&[
+            "mount".as_ref(),
+            "-o".as_ref(),
+            "remount,bind,ro".as_ref(),
+            mountpoint.as_os_str(),
+        ]))?;
+        Ok(result)
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for command in self.cleanup.drain(..) {
+            if let Err(e) = run_command(&command) {
+                result = result.and(Err(e));
+            }
+        }
+        if let Some(mountpoint) = self.mountpoint.take() {
+            if let Err(e) = std::fs::remove_dir(&mountpoint) {
+                result = result.and(Err(Error::from(format!(
+                    "SourceSnapshot: cannot remove mountpoint {:?}: {}",
+                    mountpoint, e
+                ))));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for SourceSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            eprintln!("Could not remove the snapshot of the source: {}", e);
+        }
+    }
+}
+
+fn command(args: &[&OsStr]) -> Vec<OsString> {
+    args.iter().map(|arg| arg.to_os_string()).collect()
+}
+
+fn run_command(command: &[OsString]) -> Result<()> {
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .output()
+        .map_err(|e| format!("SourceSnapshot: cannot execute {:?}: {}", command[0], e))?;
+    if !output.status.success() {
+        return Err(Error::from(format!(
+            "SourceSnapshot: {:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn relative_to(path: &Path, base: &Path) -> Result<PathBuf> {
+    path.strip_prefix(base)
+        .map(Path::to_owned)
+        .map_err(|e| Error::from(format!("Cannot determine relative path: {}", e)))
+}
+
+/// Create an empty directory with a unique name, removed in `cleanup`
+///
+/// Directories left behind by killed backups never collide with the new one.
+#[cfg(target_os = "linux")]
+fn create_mountpoint() -> Result<PathBuf> {
+    let mountpoint = tempfile::Builder::new()
+        .prefix("tools-backup-snapshot-")
+        .tempdir()
+        .map_err(|e| format!("SourceSnapshot: cannot create mountpoint: {}", e))?;
+    Ok(mountpoint.into_path())
+}
+
+/// The directory containing the btrfs snapshots of the subvolume
+///
+/// Snapshots are placed next to the subvolume, so they never show up in the
+/// source. If the subvolume is mounted directly, its parent is a different
+/// filesystem and the snapshots are placed inside the subvolume instead. Since
+/// nested subvolumes are not part of snapshots, they do not end up in the
+/// backup either way.
+fn btrfs_snapshot_directory(mounts: &[Mount], subvolume: &Path) -> PathBuf {
+    let mountpoint = |path: &Path| find_mount(mounts, path).map(|mount| &mount.mountpoint);
+    let base = match subvolume.parent() {
+        Some(parent) if mountpoint(parent) == mountpoint(subvolume) => parent,
+        _ => subvolume,
+    };
+    base.join(SNAPSHOT_DIR)
+}
+
+const SNAPSHOT_DIR: &str = ".tools-backup-snapshots";
+
+/// Remove the snapshots left behind by backups that were killed
+#[cfg(target_os = "linux")]
+fn remove_stale_btrfs_snapshots(directory: &Path) -> Result<()> {
+    let entries = directory
+        .read_dir()
+        .map_err(|e| format!("SourceSnapshot: cannot read directory: {}", e))?;
+    for entry in entries {
+        let entry =
+            entry.map_err(|e| format!("SourceSnapshot: cannot read item information: {}", e))?;
+        let pid = match snapshot_pid(&entry.file_name()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if Path::new("/proc").join(pid.to_string()).exists() {
+            continue;
+        }
+        eprintln!("Remove stale snapshot {:?}", entry.path());
+        run_command(&command(&[
+            "btrfs".as_ref(),
+            "subvolume".as_ref(),
+            "delete".as_ref(),
+            entry.path().as_os_str(),
+        ]))?;
+    }
+    Ok(())
+}
+
+/// The process that created the snapshot, e.g., `snapshot-1234`
+fn snapshot_pid(name: &OsStr) -> Option<u32> {
+    name.to_str()?.strip_prefix("snapshot-")?.parse().ok()
+}
+
+/// The root of the btrfs subvolume containing the path
+///
+/// The root directory of each subvolume has the inode number 256.
+#[cfg(target_os = "linux")]
+fn btrfs_subvolume(path: &Path) -> Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+    const SUBVOLUME_INODE: u64 = 256;
+
+    let device = path
+        .metadata()
+        .map_err(|e| format!("SourceSnapshot: cannot read metadata: {}", e))?
+        .dev();
+    for ancestor in path.ancestors() {
+        match ancestor.metadata() {
+            Ok(metadata) if metadata.dev() != device => break,
+            Ok(metadata) if metadata.ino() == SUBVOLUME_INODE => return Ok(ancestor.to_owned()),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Err(Error::from(format!(
+        "SourceSnapshot: {:?} is not on a btrfs subvolume",
+        path
+    )))
+}
+
+/// An entry of `/proc/self/mountinfo`
+#[derive(Debug, Clone, PartialEq)]
+struct Mount {
+    /// The directory of the filesystem mounted, `/` unless it is a bind mount
+    root: PathBuf,
+    mountpoint: PathBuf,
+    fstype: String,
+    device: PathBuf,
+}
+
+/// Parse the mount table, see `proc(5)`
+fn parse_mountinfo(content: &str) -> Vec<Mount> {
+    let mut result = Vec::new();
+    for line in content.lines() {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        // NOTE: the optional fields are terminated by a single hyphen
+        let separator = match fields.iter().position(|field| *field == "-") {
+            Some(separator) if separator >= 6 && fields.len() >= separator + 3 => separator,
+            _ => continue,
+        };
+        result.push(Mount {
+            root: PathBuf::from(unescape(fields[3])),
+            mountpoint: PathBuf::from(unescape(fields[4])),
+            fstype: fields[separator + 1].to_owned(),
+            device: PathBuf::from(unescape(fields[separator + 2])),
+        });
+    }
+    result
+}
+
+/// The mount containing the path, later mounts hide earlier ones
+fn find_mount<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mountpoint))
+        .max_by_key(|mount| mount.mountpoint.components().count())
+}
+
+/// Replace octal escapes, e.g., `\040` for spaces
+fn unescape(field: &str) -> String {
+    let mut result = Vec::new();
+    let bytes = field.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let escaped = bytes.get(idx + 1..idx + 4).and_then(|digits| {
+            std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        });
+        match escaped {
+            Some(value) if bytes[idx] == b'\\' => {
+                result.push(value);
+                idx += 4;
+            }
+            _ => {
+                result.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+35 22 253:1 / /home rw,relatime shared:2 - xfs /dev/mapper/vg-home rw
+36 35 253:1 /alice/my\\040data /srv/data rw,relatime - xfs /dev/mapper/vg-home rw
+";
+
+    #[test]
+    fn mounts_are_parsed_and_matched() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[2].root, Path::new("/alice/my data"));
+
+        let home = find_mount(&mounts, Path::new("/home/alice/docs")).unwrap();
+        assert_eq!(home.mountpoint, Path::new("/home"));
+        assert_eq!(home.fstype, "xfs");
+        assert_eq!(home.device, Path::new("/dev/mapper/vg-home"));
+
+        let data = find_mount(&mounts, Path::new("/srv/data/x")).unwrap();
+        assert_eq!(data.root, Path::new("/alice/my data"));
+        let root = find_mount(&mounts, Path::new("/homework")).unwrap();
+        assert_eq!(root.mountpoint, Path::new("/"));
+    }
+
+    #[test]
+    fn btrfs_snapshots_are_placed_outside_the_source() {
+        let mounts = parse_mountinfo(
+            "\
+22 1 0:30 /@ / rw,relatime shared:1 - btrfs /dev/sda2 rw
+35 22 0:30 /@home /home rw,relatime shared:2 - btrfs /dev/sda2 rw
+",
+        );
+        assert_eq!(
+            btrfs_snapshot_directory(&mounts, Path::new("/home/alice/projects")),
+            Path::new("/home/alice/.tools-backup-snapshots")
+        );
+        assert_eq!(
+            btrfs_snapshot_directory(&mounts, Path::new("/home")),
+            Path::new("/home/.tools-backup-snapshots")
+        );
+        assert_eq!(
+            btrfs_snapshot_directory(&mounts, Path::new("/")),
+            Path::new("/.tools-backup-snapshots")
+        );
+
+        assert_eq!(snapshot_pid(OsStr::new("snapshot-1234")), Some(1234));
+        assert_eq!(snapshot_pid(OsStr::new("snapshot-")), None);
+        assert_eq!(snapshot_pid(OsStr::new("photos")), None);
+    }
+
+    #[test]
+    fn snapshot_methods_are_parsed() -> Result<()> {
+        assert_eq!(SnapshotMethod::parse("btrfs", "1G")?, SnapshotMethod::Btrfs);
+        assert_eq!(
+            SnapshotMethod::parse("lvm", "5G")?,
+            SnapshotMethod::Lvm(String::from("5G"))
+        );
+        assert!(SnapshotMethod::parse("zfs", "1G").is_err());
+        Ok(())
+    }
+}
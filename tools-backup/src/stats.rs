@@ -0,0 +1,236 @@
+//! Account for the space used by the snapshots of a repository
+//!
+//! Since snapshots share unchanged files via hard-links, the size of a single
+//! snapshot says little about the space it requires. Stored items are
+//! therefore identified by their inode (on Windows by the volume serial number
+//! and file index): bytes of inodes only found in a single snapshot are unique
+//! to it, all others are shared with other snapshots.
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+};
+use tools_utils::Result;
+
+use super::manifest::EntryKind;
+use super::snapshot;
+
+/// The statistics of a single snapshot
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SnapshotStats {
+    pub name: String,
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// The size of all stored items, as reported by `du` for this snapshot
+    /// alone
+    pub total_bytes: u64,
+    /// Bytes not shared with any other snapshot, i.e., the space freed when
+    /// deleting this snapshot
+    pub unique_bytes: u64,
+    /// Bytes shared with at least one other snapshot
+    pub shared_bytes: u64,
+    /// Bytes not contained in any earlier snapshot, i.e., the space required
+    /// to create this snapshot
+    pub new_bytes: u64,
+    /// The size of the repository up to and including this snapshot
+    pub cumulative_bytes: u64,
+}
+
+/// The statistics of all snapshots of a repository
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RepositoryStats {
+    pub snapshots: Vec<SnapshotStats>,
+    /// The space used by all snapshots together
+    pub total_bytes: u64,
+}
+
+/// Identify stored items shared between snapshots by their device and inode
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StoredItem(u64, u64);
+
+/// Compute the statistics of all snapshots, sorted by their names
+pub fn repository_stats(repository: impl AsRef<Path>) -> Result<RepositoryStats> {
+    let mut result = RepositoryStats::default();
+    let mut stored_items = Vec::<HashMap<StoredItem, u64>>::new();
+    let mut snapshot_counts = HashMap::<StoredItem, usize>::new();
+
+    for snapshot in snapshot::list_snapshots(repository)? {
+        let mut stats = SnapshotStats {
+            name: snapshot
+                .file_name()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            ..SnapshotStats::default()
+        };
+        let mut items = HashMap::new();
+        for entry in snapshot::read_snapshot(&snapshot)? {
+            match entry.kind {
+                EntryKind::File => stats.files += 1,
+                EntryKind::Symlink => stats.symlinks += 1,
+                EntryKind::Directory => stats.directories += 1,
+                _ => {}
+            }
+            // NOTE: symlinks are stored as small placeholder files
+            if !matches!(entry.kind, EntryKind::File | EntryKind::Symlink) {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&entry.data_path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let item = stored_item(&entry.data_path, &metadata).map_err(|e| {
+                format!(
+                    "repository_stats: cannot identify {:?}: {}",
+                    entry.data_path, e
+                )
+            })?;
+            items.insert(item, metadata.len());
+        }
+
+        stats.total_bytes = items.values().sum();
+        for item in items.keys() {
+            *snapshot_counts.entry(item.clone()).or_default() += 1;
+        }
+        result.snapshots.push(stats);
+        stored_items.push(items);
+    }
+
+    let mut seen = HashSet::new();
+    for (stats, items) in result.snapshots.iter_mut().zip(&stored_items) {
+        for (item, &size) in items {
+            if snapshot_counts[item] == 1 {
+                stats.unique_bytes += size;
+            } else {
+                stats.shared_bytes += size;
+            }
+            if seen.insert(item) {
+                stats.new_bytes += size;
+            }
+        }
+        result.total_bytes += stats.new_bytes;
+        stats.cumulative_bytes = result.total_bytes;
+    }
+    Ok(result)
+}
+
+#[cfg(unix)]
+fn stored_item(_path: &Path, metadata: &fs::Metadata) -> io::Result<StoredItem> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(StoredItem(metadata.dev(), metadata.ino()))
+}
+
+#[cfg(windows)]
+fn stored_item(path: &Path, _metadata: &fs::Metadata) -> io::Result<StoredItem> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+    let file = fs::File::open(path)?;
+    let info = unsafe {
+        let mut info: BY_HANDLE_FILE_INFORMATION = std::mem::zeroed();
+        if GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        info
+    };
+    let index = (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow);
+    Ok(StoredItem(u64::from(info.dwVolumeSerialNumber), index))
+}
+
+/// Without a file identity shared and unique bytes cannot be told apart
+#[cfg(not(any(unix, windows)))]
+fn stored_item(_path: &Path, _metadata: &fs::Metadata) -> io::Result<StoredItem> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "hard-links cannot be detected on this platform",
+    ))
+}
+
+/// Print the statistics as a table
+pub fn print_stats(stats: &RepositoryStats) {
+    println!(
+        "{:20}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}",
+        "SNAPSHOT", "FILES", "TOTAL", "UNIQUE", "SHARED", "NEW", "CUMULATIVE"
+    );
+    for snapshot in &stats.snapshots {
+        println!(
+            "{:20}  {:>8}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}",
+            snapshot.name,
+            snapshot.files,
+            format_bytes(snapshot.total_bytes),
+            format_bytes(snapshot.unique_bytes),
+            format_bytes(snapshot.shared_bytes),
+            format_bytes(snapshot.new_bytes),
+            format_bytes(snapshot.cumulative_bytes),
+        );
+    }
+    println!(
+        "{} snapshots using {}",
+        stats.snapshots.len(),
+        format_bytes(stats.total_bytes)
+    );
+}
+
+/// Format a size with binary suffixes, e.g., `1.5K`
+pub fn format_bytes(bytes: u64) -> String {
+    let suffixes = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut value = bytes as f64;
+    let mut suffix = "";
+    for candidate in &suffixes {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        suffix = candidate;
+    }
+    format!("{:.1}{}", value, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn shared_and_unique_bytes_are_separated() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("repo", "2020-01", "same.txt"), Some("same"), None)?
+            .with_file(("repo", "2020-01", "old.txt"), Some("old"), None)?
+            .with_file(("repo", "2020-02", "new.txt"), Some("new file"), None)?
+            .with_directory(("repo", "2020-02", "dir"))?;
+        fs::hard_link(
+            spec.path(("repo", "2020-01", "same.txt")),
+            spec.path(("repo", "2020-02", "same.txt")),
+        )
+        .unwrap();
+
+        let stats = repository_stats(spec.path("repo"))?;
+        let first = &stats.snapshots[0];
+        let second = &stats.snapshots[1];
+
+        assert_eq!(first.name, "2020-01");
+        assert_eq!(first.files, 2);
+        assert_eq!(first.total_bytes, 7);
+        assert_eq!(second.files, 2);
+        assert_eq!(second.directories, 1);
+        assert_eq!(second.total_bytes, 12);
+        assert_eq!(first.new_bytes, 7);
+        assert_eq!(second.cumulative_bytes, 15);
+        assert_eq!(stats.total_bytes, 15);
+        assert_eq!((first.unique_bytes, first.shared_bytes), (3, 4));
+        assert_eq!((second.unique_bytes, second.shared_bytes), (8, 4));
+        assert_eq!(second.new_bytes, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn sizes_are_formatted_with_suffixes() {
+        assert_eq!(format_bytes(512), "512");
+        assert_eq!(format_bytes(1536), "1.5K");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0G");
+    }
+}
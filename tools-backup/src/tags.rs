@@ -0,0 +1,218 @@
+//! Labels and notes attached to snapshots
+//!
+//! Snapshots are identified by their name only, usually the date of the
+//! backup. Tags, e.g., `keep`, and a free-form note, e.g., `before OS
+//! upgrade`, are stored in the metadata directory of the snapshot.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+
+use super::manifest::METADATA_DIR;
+use super::snapshot;
+
+const TAGS_FILE: &str = "tags.json";
+
+/// The tags and the note of a single snapshot
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotTags {
+    /// Sorted and without duplicates
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl SnapshotTags {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn add_tag(&mut self, tag: &str) -> Result<()> {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return Err(Error::from("Tags must not be empty"));
+        }
+        if !self.has_tag(tag) {
+            self.tags.push(tag.to_owned());
+            self.tags.sort();
+        }
+        Ok(())
+    }
+
+    pub fn remove_tag(&mut self, tag: &str) {
+        let tag = tag.trim();
+        self.tags.retain(|t| t != tag);
+    }
+}
+
+/// A snapshot of a repository together with its tags
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaggedSnapshot {
+    pub name: String,
+    #[serde(flatten)]
+    pub tags: SnapshotTags,
+}
+
+fn tags_file(snapshot: impl AsRef<Path>) -> PathBuf {
+    snapshot.as_ref().join(METADATA_DIR).join(TAGS_FILE)
+}
+
+/// Read the tags of a snapshot, snapshots without tags file have no tags
+pub fn read_tags(snapshot: impl AsRef<Path>) -> Result<SnapshotTags> {
+    let path = tags_file(snapshot);
+    if !path.exists() {
+        return Ok(SnapshotTags::default());
+    }
+    let content =
+        fs::read_to_string(&path).map_err(|e| format!("read_tags: cannot read file: {}", e))?;
+    serde_json::from_str(&content)
+        .map_err(|e| Error::from(format!("read_tags: invalid tags in {:?}: {}", path, e)))
+}
+
+/// Write the tags of a snapshot
+///
+/// Directories without metadata directory are rejected, as tagging them would
+/// turn them into snapshots, e.g., for `prune`.
+pub fn write_tags(snapshot: impl AsRef<Path>, tags: &SnapshotTags) -> Result<()> {
+    let snapshot = snapshot.as_ref();
+    if !snapshot::is_snapshot(snapshot) {
+        return Err(Error::from(format!(
+            "write_tags: {:?} is not a snapshot",
+            snapshot
+        )));
+    }
+    let path = tags_file(snapshot);
+    let content = serde_json::to_string_pretty(tags)
+        .map_err(|e| format!("write_tags: cannot serialize tags: {}", e))?;
+
+    // NOTE: replace the file, so the tags are never left half-written
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, content).map_err(|e| format!("write_tags: cannot write file: {}", e))?;
+    fs::rename(&temp_path, &path).map_err(|e| format!("write_tags: cannot replace file: {}", e))?;
+    Ok(())
+}
+
+/// List the snapshots of a repository that have all of the given tags
+pub fn find_snapshots(
+    repository: impl AsRef<Path>,
+    required_tags: &[String],
+) -> Result<Vec<TaggedSnapshot>> {
+    let mut result = Vec::new();
+    for path in snapshot::list_snapshots(repository)? {
+        let tags = read_tags(&path)?;
+        if !required_tags.iter().all(|tag| tags.has_tag(tag.trim())) {
+            continue;
+        }
+        result.push(TaggedSnapshot {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            tags,
+        });
+    }
+    Ok(result)
+}
+
+/// Print the snapshots as a table
+pub fn print_snapshots(snapshots: &[TaggedSnapshot]) {
+    println!("{:20}  {:30}  NOTE", "SNAPSHOT", "TAGS");
+    for snapshot in snapshots {
+        println!(
+            "{:20}  {:30}  {}",
+            snapshot.name,
+            snapshot.tags.tags.join(", "),
+            snapshot.tags.note.as_deref().unwrap_or(""),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::Spec;
+    use super::*;
+
+    #[test]
+    fn tags_roundtrip() -> Result<()> {
+        let spec = Spec::new()?.with_directory(("snapshot", METADATA_DIR))?;
+        assert_eq!(read_tags(spec.path("snapshot"))?, SnapshotTags::default());
+
+        let mut tags = SnapshotTags::default();
+        tags.add_tag("keep")?;
+        tags.add_tag(" before-upgrade ")?;
+        tags.add_tag("keep")?;
+        assert!(tags.add_tag(" ").is_err());
+        tags.note = Some(String::from("before OS upgrade"));
+        write_tags(spec.path("snapshot"), &tags)?;
+
+        let actual = read_tags(spec.path("snapshot"))?;
+        assert_eq!(actual.tags, vec!["before-upgrade", "keep"]);
+        assert_eq!(actual.note.as_deref(), Some("before OS upgrade"));
+
+        tags.remove_tag("keep");
+        write_tags(spec.path("snapshot"), &tags)?;
+        assert_eq!(
+            read_tags(spec.path("snapshot"))?.tags,
+            vec!["before-upgrade"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_are_filtered_by_tags() -> Result<()> {
+        let spec = Spec::new()?
+            .with_directory(("repo", "2020-01", METADATA_DIR))?
+            .with_directory(("repo", "2020-02", METADATA_DIR))?
+            .with_directory(("repo", "2020-03", METADATA_DIR))?;
+        for (name, tags) in &[
+            ("2020-01", vec!["keep", "yearly"]),
+            ("2020-02", vec!["keep"]),
+        ] {
+            let mut snapshot_tags = SnapshotTags::default();
+            for tag in tags {
+                snapshot_tags.add_tag(tag)?;
+            }
+            write_tags(spec.path(("repo", *name)), &snapshot_tags)?;
+        }
+
+        let names = |tags: &[&str]| -> Result<Vec<String>> {
+            let tags = tags
+                .iter()
+                .map(|tag| String::from(*tag))
+                .collect::<Vec<_>>();
+            Ok(find_snapshots(spec.path("repo"), &tags)?
+                .into_iter()
+                .map(|snapshot| snapshot.name)
+                .collect())
+        };
+        assert_eq!(names(&[])?, vec!["2020-01", "2020-02", "2020-03"]);
+        assert_eq!(names(&["keep"])?, vec!["2020-01", "2020-02"]);
+        assert_eq!(names(&["keep", "yearly"])?, vec!["2020-01"]);
+        assert!(names(&["missing"])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn directories_without_metadata_cannot_be_tagged() -> Result<()> {
+        let spec = Spec::new()?
+            .with_directory(("repo", "2020-01", METADATA_DIR))?
+            .with_file(("repo", "photos", "image.jpg"), Some("image"), None)?;
+        let mut tags = SnapshotTags::default();
+        tags.add_tag("x")?;
+
+        assert!(write_tags(spec.path(("repo", "photos")), &tags).is_err());
+        assert!(write_tags(spec.path(("repo", "2020-13")), &tags).is_err());
+        assert!(!spec.path(("repo", "photos", METADATA_DIR)).exists());
+        assert!(!spec.path(("repo", "2020-13")).exists());
+
+        super::super::prune::prune(spec.path("repo"), 0, false)?;
+        assert_eq!(
+            snapshot::list_snapshots(spec.path("repo"))?,
+            vec![spec.path(("repo", "photos"))]
+        );
+        Ok(())
+    }
+}
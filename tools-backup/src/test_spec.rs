@@ -1,6 +1,27 @@
 //! Helpers to specify a the state of a directory tree in unit tests
-#![allow(dead_code)]
+//!
+//! Whole trees can be described as fixtures, e.g.,
+//!
+//! ```ignore
+//! let spec = Spec::new()?
+//!     .with_tree("source", tree([
+//!         ("foo.txt", file("hello").mtime(-3600)),
+//!         ("bin/run.sh", file("#!/bin/sh").mode(0o755)),
+//!         ("link", symlink("foo.txt")),
+//!     ]))?
+//!     .expect_tree("target", tree([
+//!         ("foo.txt", file("hello").hard_link("foo")),
+//!         ("bin/run.sh", file("#!/bin/sh").mode(0o755)),
+//!         ("link", link_placeholder("foo.txt")),
+//!     ]));
+//! ```
+//!
+//! Expected trees must match exactly, parent directories are implied. Items
+//! with the same hard-link group must share their inode, items of different
+//! groups must not. On failure, the expected and actual trees are printed as a
+//! diff.
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
     io::{Read, Write},
     path::{Path, PathBuf},
@@ -8,7 +29,9 @@ use std::{
 };
 use tempfile::TempDir;
 use tools_utils::{Error, Result};
-use utime;
+use walkdir::WalkDir;
+
+use super::manifest::{self, METADATA_DIR};
 
 /// Specification of how the file tree should look like after copying
 pub struct Spec {
@@ -17,6 +40,9 @@ pub struct Spec {
     now: u64,
     expected_files: Vec<FileSpec>,
     expected_directories: Vec<PathBuf>,
+    expected_trees: Vec<(PathBuf, Tree)>,
+    /// The first item created for each hard-link group
+    link_groups: HashMap<String, PathBuf>,
 }
 
 /// Specification for individial files
@@ -61,6 +87,131 @@ impl RelativePathLike for (&str, &str, &str, &str) {
     }
 }
 
+impl<const N: usize> RelativePathLike for [&str; N] {
+    fn to_path(self, root: impl AsRef<Path>) -> PathBuf {
+        self.iter()
+            .fold(root.as_ref().to_owned(), |path, part| path.join(part))
+    }
+}
+
+impl RelativePathLike for &Path {
+    fn to_path(self, root: impl AsRef<Path>) -> PathBuf {
+        root.as_ref().join(self)
+    }
+}
+
+/// An item of a tree fixture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    kind: NodeKind,
+    /// The modification time in seconds relative to the spec's base time
+    mtime: Option<i64>,
+    /// The permission bits, only used on unix
+    mode: Option<u32>,
+    link_group: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeKind {
+    /// A file, if the content is not given, it is not compared
+    File(Option<String>),
+    Directory,
+    Symlink(PathBuf),
+}
+
+/// A file with the given content
+pub fn file(content: &str) -> Node {
+    Node::new(NodeKind::File(Some(content.to_owned())))
+}
+
+/// A file with arbitrary content
+pub fn any_file() -> Node {
+    Node::new(NodeKind::File(None))
+}
+
+pub fn dir() -> Node {
+    Node::new(NodeKind::Directory)
+}
+
+// NOTE: symlinks are only used by the tests on unix
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn symlink(target: &str) -> Node {
+    Node::new(NodeKind::Symlink(PathBuf::from(target)))
+}
+
+/// The file written by the backup in place of a symlink
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn link_placeholder(target: &str) -> Node {
+    file(&format!("LINK {}", target))
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Self {
+        Self {
+            kind,
+            mtime: None,
+            mode: None,
+            link_group: None,
+        }
+    }
+
+    /// Set the modification time in seconds relative to the base time of the
+    /// spec, i.e., 10 minutes before the start of the test
+    pub fn mtime(mut self, offset: i64) -> Self {
+        self.mtime = Some(offset);
+        self
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Items of the same group are hard-links of each other
+    pub fn hard_link(mut self, group: &str) -> Self {
+        self.link_group = Some(group.to_owned());
+        self
+    }
+}
+
+/// A directory tree with paths relative to its root, e.g., `bar/baz.txt`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Tree {
+    nodes: Vec<(PathBuf, Node)>,
+}
+
+pub fn tree<const N: usize>(nodes: [(&str, Node); N]) -> Tree {
+    Tree {
+        nodes: Vec::from(nodes)
+            .into_iter()
+            .map(|(path, node)| (PathBuf::from(path), node))
+            .collect(),
+    }
+}
+
+impl Tree {
+    /// Add a single item, e.g., for generated trees
+    pub fn with(mut self, path: impl AsRef<Path>, node: Node) -> Self {
+        self.nodes.push((path.as_ref().to_owned(), node));
+        self
+    }
+
+    /// All nodes including the implied parent directories, sorted by path
+    fn expected_nodes(&self) -> BTreeMap<PathBuf, Node> {
+        let mut result = BTreeMap::new();
+        for (path, node) in &self.nodes {
+            for parent in path.ancestors().skip(1) {
+                if !parent.as_os_str().is_empty() && !result.contains_key(parent) {
+                    result.insert(parent.to_owned(), dir());
+                }
+            }
+            result.insert(path.clone(), node.clone());
+        }
+        result
+    }
+}
+
 impl Spec {
     pub fn new() -> Result<Self> {
         let result = Self {
@@ -72,6 +223,8 @@ impl Spec {
                 .as_secs(),
             expected_files: Vec::new(),
             expected_directories: Vec::new(),
+            expected_trees: Vec::new(),
+            link_groups: HashMap::new(),
         };
         Ok(result)
     }
@@ -121,15 +274,70 @@ impl Spec {
         file.write_all(content.as_bytes())
             .map_err(|e| format!("Spec::with_file: Cannot write content: {}", e))?;
 
-        if mtime > 600 {
-            return Err(Error::from("Cannot use times larger than 10 minutes"));
-        }
+        self.set_mtime(&path, mtime as i64)?;
+        Ok(self)
+    }
 
-        utime::set_file_times(&path, self.now + mtime - 600, self.now + mtime - 600).unwrap();
+    /// Create all items of the tree below the given root
+    ///
+    /// Directory modification times and permissions are applied after all
+    /// their children were created.
+    pub fn with_tree(mut self, root: impl RelativePathLike, tree: Tree) -> Result<Self> {
+        let root = root.to_path(self.tempdir.path());
+        self.add_directory(&root)?;
+
+        for (rel_path, node) in &tree.nodes {
+            let path = root.join(rel_path);
+            if let Some(parent) = path.parent() {
+                self.add_directory(parent)?;
+            }
+            match &node.kind {
+                NodeKind::Directory => self.add_directory(&path)?,
+                NodeKind::Symlink(target) => create_symlink(target, &path)?,
+                NodeKind::File(content) => {
+                    let first = node
+                        .link_group
+                        .as_ref()
+                        .and_then(|group| self.link_groups.get(group));
+                    match first {
+                        Some(first) => fs::hard_link(first, &path)
+                            .map_err(|e| format!("Spec::with_tree: Cannot create link: {}", e))?,
+                        None => fs::write(&path, content.as_deref().unwrap_or_default())
+                            .map_err(|e| format!("Spec::with_tree: Cannot write file: {}", e))?,
+                    }
+                    if let Some(group) = &node.link_group {
+                        self.link_groups
+                            .entry(group.clone())
+                            .or_insert_with(|| path.clone());
+                    }
+                }
+            }
+        }
 
+        for (rel_path, node) in tree.nodes.iter().rev() {
+            let path = root.join(rel_path);
+            if let NodeKind::Symlink(_) = node.kind {
+                continue;
+            }
+            if let Some(mode) = node.mode {
+                set_mode(&path, mode)?;
+            }
+            self.set_mtime(&path, node.mtime.unwrap_or_default())?;
+        }
         Ok(self)
     }
 
+    /// The base time of the spec, 10 minutes before the start of the test
+    pub fn base_time(&self) -> i64 {
+        self.now as i64 - 600
+    }
+
+    fn set_mtime(&self, path: &Path, offset: i64) -> Result<()> {
+        let mtime = (self.base_time() + offset).max(0) as u64;
+        utime::set_file_times(path, mtime, mtime)
+            .map_err(|e| Error::from(format!("Spec: Cannot set mtime of {:?}: {}", path, e)))
+    }
+
     /// Add the expectation of a file to this spec
     ///
     /// Arguments:
@@ -164,6 +372,142 @@ impl Spec {
         self
     }
 
+    /// Add the expectation, that the directory contains exactly this tree
+    ///
+    /// The metadata directory of snapshots is not compared.
+    pub fn expect_tree(mut self, root: impl RelativePathLike, tree: Tree) -> Self {
+        let root = root.to_path(self.tempdir.path());
+        self.expected_trees.push((root, tree));
+        self
+    }
+
+    /// Render the differences between the expected and the actual trees
+    pub fn tree_diff(&self) -> Result<Option<String>> {
+        let mut labels = LinkLabels::default();
+        let mut result = String::new();
+        for (root, tree) in &self.expected_trees {
+            if let Some(diff) = self.diff_tree(root, tree, &mut labels)? {
+                result.push_str(&format!("--- expected {:?}\n+++ actual\n{}", root, diff));
+            }
+        }
+        Ok(if result.is_empty() {
+            None
+        } else {
+            Some(result)
+        })
+    }
+
+    fn diff_tree(
+        &self,
+        root: &Path,
+        tree: &Tree,
+        labels: &mut LinkLabels,
+    ) -> Result<Option<String>> {
+        let expected_nodes = tree.expected_nodes();
+        let mut expected = BTreeMap::new();
+        let mut actual = BTreeMap::new();
+        for (path, node) in &expected_nodes {
+            expected.insert(path.clone(), describe(path, node));
+        }
+
+        // NOTE: sorted to assign the inodes of link groups deterministically
+        let walker = WalkDir::new(root)
+            .min_depth(1)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != METADATA_DIR);
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                // NOTE: a missing root shows up as missing items
+                Err(_) if !root.exists() => break,
+                Err(e) => {
+                    return Err(Error::from(format!(
+                        "diff_tree: cannot read {:?}: {}",
+                        root, e
+                    )))
+                }
+            };
+            let rel_path = entry
+                .path()
+                .strip_prefix(root)
+                .map_err(|e| format!("Cannot determine relative path: {}", e))?
+                .to_owned();
+            let node = self.observe(entry.path(), expected_nodes.get(&rel_path), labels)?;
+            actual.insert(rel_path.clone(), describe(&rel_path, &node));
+        }
+
+        let mut paths = expected.keys().chain(actual.keys()).collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+
+        let mut result = String::new();
+        let mut differs = false;
+        for path in paths {
+            match (expected.get(path), actual.get(path)) {
+                (Some(expected), Some(actual)) if expected == actual => {
+                    result.push_str(&format!("  {}\n", expected));
+                }
+                (expected, actual) => {
+                    differs = true;
+                    if let Some(expected) = expected {
+                        result.push_str(&format!("- {}\n", expected));
+                    }
+                    if let Some(actual) = actual {
+                        result.push_str(&format!("+ {}\n", actual));
+                    }
+                }
+            }
+        }
+        Ok(if differs { Some(result) } else { None })
+    }
+
+    /// Describe an existing item with the attributes given in the expectation
+    fn observe(
+        &self,
+        path: &Path,
+        expected: Option<&Node>,
+        labels: &mut LinkLabels,
+    ) -> Result<Node> {
+        let metadata = path
+            .symlink_metadata()
+            .map_err(|e| format!("Spec: Cannot read metadata of {:?}: {}", path, e))?;
+        let kind = if metadata.file_type().is_symlink() {
+            NodeKind::Symlink(
+                fs::read_link(path).map_err(|e| format!("Spec: Cannot read link: {}", e))?,
+            )
+        } else if metadata.is_dir() {
+            NodeKind::Directory
+        } else {
+            let compare_content =
+                !matches!(expected.map(|node| &node.kind), Some(NodeKind::File(None)));
+            NodeKind::File(if compare_content {
+                let content =
+                    fs::read(path).map_err(|e| format!("Spec: Cannot read file: {}", e))?;
+                Some(String::from_utf8_lossy(&content).into_owned())
+            } else {
+                None
+            })
+        };
+
+        let mut result = Node::new(kind);
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return Ok(result),
+        };
+        if expected.mtime.is_some() {
+            let mtime = manifest::mtime_seconds(&metadata);
+            result.mtime = Some(mtime - self.base_time());
+        }
+        if expected.mode.is_some() {
+            result.mode = mode_of(&metadata).or(expected.mode);
+        }
+        if let Some(group) = &expected.link_group {
+            result.link_group = Some(labels.label(group, &metadata));
+        }
+        Ok(result)
+    }
+
     pub fn assert(&self) -> Result<()> {
         for expected_directory in &self.expected_directories {
             assert!(
@@ -190,12 +534,118 @@ impl Spec {
                 assert_eq!(&actual, expected);
             }
 
-            // TODO: compare mtimes times if given
+            if let Some(when) = expected_file.when {
+                let mtime = expected_file
+                    .path
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|e| format!("Spec::assert: cannot read mtime: {}", e))?;
+                let mtime = mtime.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+                assert_eq!(
+                    mtime,
+                    (self.base_time() + when as i64).max(0),
+                    "Unexpected mtime of {:?}",
+                    expected_file.path,
+                );
+            }
+        }
+
+        if let Some(diff) = self.tree_diff()? {
+            panic!("Trees differ:\n{}", diff);
         }
         Ok(())
     }
 }
 
+/// A single line describing the node, e.g., `bin/run.sh file "..." mode=0o755`
+fn describe(path: &Path, node: &Node) -> String {
+    let mut result = manifest::manifest_path(path);
+    match &node.kind {
+        NodeKind::File(Some(content)) => result.push_str(&format!(" file {:?}", content)),
+        NodeKind::File(None) => result.push_str(" file"),
+        NodeKind::Directory => result.push_str(" dir"),
+        NodeKind::Symlink(target) => result.push_str(&format!(" symlink -> {:?}", target)),
+    }
+    if let Some(mode) = node.mode {
+        result.push_str(&format!(" mode={:#o}", mode));
+    }
+    if let Some(mtime) = node.mtime {
+        result.push_str(&format!(" mtime={}", mtime));
+    }
+    if let Some(group) = &node.link_group {
+        result.push_str(&format!(" link={}", group));
+    }
+    result
+}
+
+/// Map inodes to the hard-link groups of the expectations
+///
+/// The first item of a group determines its inode. Items with an inode not
+/// matching their group are labeled with the group of their inode or as
+/// distinct.
+#[derive(Default)]
+#[cfg_attr(not(unix), allow(dead_code))]
+struct LinkLabels {
+    groups: HashMap<String, (u64, u64)>,
+    inodes: HashMap<(u64, u64), String>,
+}
+
+impl LinkLabels {
+    #[cfg(unix)]
+    fn label(&mut self, group: &str, metadata: &fs::Metadata) -> String {
+        use std::os::unix::fs::MetadataExt;
+        let inode = (metadata.dev(), metadata.ino());
+        if let Some(label) = self.inodes.get(&inode) {
+            return label.clone();
+        }
+        if self.groups.contains_key(group) {
+            return format!("{} (distinct inode)", group);
+        }
+        self.groups.insert(group.to_owned(), inode);
+        self.inodes.insert(inode, group.to_owned());
+        group.to_owned()
+    }
+
+    #[cfg(not(unix))]
+    fn label(&mut self, group: &str, _metadata: &fs::Metadata) -> String {
+        group.to_owned()
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)
+        .map_err(|e| Error::from(format!("Spec: Cannot create symlink: {}", e)))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _path: &Path) -> Result<()> {
+    Err(Error::from("Spec: symlinks are only supported on unix"))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .map_err(|e| Error::from(format!("Spec: Cannot set permissions: {}", e)))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
 pub fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
     let mut file = File::open(path).unwrap();
     let mut contents = String::new();
@@ -203,3 +653,88 @@ pub fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
         .map_err(|e| format!("read_file: could not read file: {}", e))?;
     Ok(contents)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn created_trees_match_their_expectation() -> Result<()> {
+        let fixture = || {
+            tree([
+                ("foo.txt", file("hello").mtime(-86400).hard_link("foo")),
+                ("bar/foo.txt", file("hello").hard_link("foo")),
+                ("bar/baz/empty", dir().mtime(3600)),
+                ("any.txt", any_file()),
+            ])
+        };
+        let spec = Spec::new()?
+            .with_tree(["a", "b", "c", "d", "tree"], fixture())?
+            .expect_tree(["a", "b", "c", "d", "tree"], fixture());
+
+        assert_eq!(spec.tree_diff()?, None);
+        spec.assert()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_and_permissions_are_created() -> Result<()> {
+        let fixture = || {
+            tree([
+                ("run.sh", file("#!/bin/sh").mode(0o755)),
+                ("link", symlink("run.sh")),
+            ])
+        };
+        let spec = Spec::new()?
+            .with_tree("tree", fixture())?
+            .expect_tree("tree", fixture());
+
+        assert_eq!(spec.tree_diff()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn differences_are_rendered_as_diff() -> Result<()> {
+        let spec = Spec::new()?
+            .with_tree(
+                "tree",
+                tree([
+                    ("same.txt", file("same")),
+                    ("changed.txt", file("old")),
+                    ("extra.txt", file("extra")),
+                    ("a.txt", file("a")),
+                    ("b.txt", file("b")),
+                ]),
+            )?
+            .expect_tree(
+                "tree",
+                tree([
+                    ("same.txt", file("same")),
+                    ("changed.txt", file("new")),
+                    ("missing/file.txt", any_file()),
+                    ("a.txt", any_file().hard_link("a")),
+                    ("b.txt", any_file().hard_link("a")),
+                ]),
+            );
+
+        let diff = spec.tree_diff()?.unwrap();
+        let lines = diff.lines().skip(2).collect::<Vec<_>>();
+        let mut expected = vec![
+            "  a.txt file link=a",
+            "- b.txt file link=a",
+            "+ b.txt file link=a (distinct inode)",
+            "- changed.txt file \"new\"",
+            "+ changed.txt file \"old\"",
+            "+ extra.txt file \"extra\"",
+            "- missing dir",
+            "- missing/file.txt file",
+            "  same.txt file \"same\"",
+        ];
+        if cfg!(not(unix)) {
+            expected.retain(|line| !line.contains("b.txt"));
+            expected.insert(1, "  b.txt file link=a");
+        }
+        assert_eq!(lines, expected);
+        Ok(())
+    }
+}
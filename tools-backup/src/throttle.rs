@@ -0,0 +1,324 @@
+//! Helpers to limit the IO load caused by backups
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tools_utils::{Error, Result};
+
+use super::events::EventFormat;
+use super::faults;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Limits on the IO performed while copying files
+///
+/// Without any limits files are copied with `std::fs::copy`. Otherwise files
+/// are copied in chunks and the limits are checked after each chunk.
+#[derive(Default)]
+pub struct Throttle {
+    read: Option<RateLimit>,
+    write: Option<RateLimit>,
+    pause: Option<PauseState>,
+    event_format: EventFormat,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of bytes read per second
+    pub fn with_read_limit(mut self, bytes_per_second: u64) -> Self {
+        self.read = Some(RateLimit::new(bytes_per_second));
+        self
+    }
+
+    /// Limit the number of bytes written per second
+    pub fn with_write_limit(mut self, bytes_per_second: u64) -> Self {
+        self.write = Some(RateLimit::new(bytes_per_second));
+        self
+    }
+
+    /// Print messages on pauses to stderr with JSON events
+    pub fn with_event_format(mut self, event_format: EventFormat) -> Self {
+        self.event_format = event_format;
+        self
+    }
+
+    /// Pause the backup on `SIGUSR1` and resume it on `SIGUSR2`
+    #[cfg(unix)]
+    pub fn with_pause_signals(mut self) -> Result<Self> {
+        let state = PauseState::new();
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, state.pause_requested.clone())
+            .map_err(|e| format!("Throttle: cannot register SIGUSR1 handler: {}", e))?;
+        signal_hook::flag::register(signal_hook::consts::SIGUSR2, state.resume_requested.clone())
+            .map_err(|e| format!("Throttle: cannot register SIGUSR2 handler: {}", e))?;
+        self.pause = Some(state);
+        Ok(self)
+    }
+
+    #[cfg(not(unix))]
+    pub fn with_pause_signals(self) -> Result<Self> {
+        Err(Error::from(
+            "Throttle: pause signals are only supported on unix systems",
+        ))
+    }
+
+    fn is_active(&self) -> bool {
+        self.read.is_some() || self.write.is_some() || self.pause.is_some()
+    }
+
+    /// Block while the backup is paused
+    pub fn wait_if_paused(&self) {
+        if let Some(pause) = &self.pause {
+            pause.wait(self.event_format);
+        }
+    }
+
+    /// Copy a file while respecting the configured limits
+    ///
+    /// Returns the number of bytes copied.
+    pub fn copy(&self, source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<u64> {
+        let source = source.as_ref();
+        let target = target.as_ref();
+
+        self.wait_if_paused();
+        if !self.is_active() {
+            return fs::copy(source, target)
+                .map_err(|e| Error::from(format!("Throttle::copy: could not copy file: {}", e)));
+        }
+
+        let mut reader = File::open(source)
+            .map_err(|e| format!("Throttle::copy: could not open source: {}", e))?;
+        let mut writer = File::create(target)
+            .map_err(|e| format!("Throttle::copy: could not create target: {}", e))?;
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            self.wait_if_paused();
+
+            let read = reader
+                .read(&mut buffer)
+                .map_err(|e| format!("Throttle::copy: could not read source: {}", e))?;
+            if read == 0 {
+                break;
+            }
+            if let Some(limit) = &self.read {
+                limit.consume(read as u64);
+            }
+
+            writer
+                .write_all(&buffer[..read])
+                .map_err(|e| format!("Throttle::copy: could not write target: {}", e))?;
+            faults::check("write chunk")?;
+            if let Some(limit) = &self.write {
+                limit.consume(read as u64);
+            }
+            total += read as u64;
+        }
+
+        // mirror the behavior of fs::copy
+        let permissions = reader
+            .metadata()
+            .map_err(|e| format!("Throttle::copy: could not read metadata: {}", e))?
+            .permissions();
+        fs::set_permissions(target, permissions)
+            .map_err(|e| format!("Throttle::copy: could not set permissions: {}", e))?;
+
+        Ok(total)
+    }
+}
+
+/// A token bucket that allows bursts of up to one second
+struct RateLimit {
+    bytes_per_second: u64,
+    state: Mutex<RateLimitState>,
+}
+
+struct RateLimitState {
+    last_update: Instant,
+    available: f64,
+}
+
+impl RateLimit {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            state: Mutex::new(RateLimitState {
+                last_update: Instant::now(),
+                available: bytes_per_second as f64,
+            }),
+        }
+    }
+
+    /// Consume the given number of bytes, sleep if the limit is exceeded
+    fn consume(&self, bytes: u64) {
+        let rate = self.bytes_per_second as f64;
+        let mut state = self.state.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_update).as_secs_f64();
+        state.available = (state.available + elapsed * rate).min(rate);
+        state.available -= bytes as f64;
+        state.last_update = now;
+
+        if state.available < 0.0 {
+            // NOTE: keep the lock, to serialize all users of this limit
+            thread::sleep(Duration::from_secs_f64(-state.available / rate));
+        }
+    }
+}
+
+struct PauseState {
+    pause_requested: Arc<AtomicBool>,
+    resume_requested: Arc<AtomicBool>,
+    paused: AtomicBool,
+}
+
+impl PauseState {
+    fn new() -> Self {
+        Self {
+            pause_requested: Arc::new(AtomicBool::new(false)),
+            resume_requested: Arc::new(AtomicBool::new(false)),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    fn wait(&self, event_format: EventFormat) {
+        loop {
+            if self.pause_requested.swap(false, Ordering::SeqCst) {
+                event_format.print_message("PAUSE [send SIGUSR2 to resume]");
+                self.paused.store(true, Ordering::SeqCst);
+            }
+            if self.resume_requested.swap(false, Ordering::SeqCst) {
+                event_format.print_message("RESUME");
+                self.paused.store(false, Ordering::SeqCst);
+            }
+            if !self.paused.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+/// The IO scheduling priority of the backup process
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoPriority {
+    Normal,
+    /// The lowest priority of the best-effort class
+    Low,
+    /// Only perform IO if no other process requires the disk
+    Idle,
+}
+
+impl IoPriority {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            "idle" => Ok(Self::Idle),
+            _ => Err(Error::from(format!("Unknown IO priority {:?}", s))),
+        }
+    }
+}
+
+/// Set the IO priority of the current process (equivalent to `ionice`)
+#[cfg(target_os = "linux")]
+pub fn set_io_priority(priority: IoPriority) -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    const IOPRIO_CLASS_BE: libc::c_long = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+
+    let value = match priority {
+        IoPriority::Normal => return Ok(()),
+        IoPriority::Low => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 7,
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) };
+    if result != 0 {
+        return Err(Error::from(format!(
+            "set_io_priority: could not set IO priority: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_priority(priority: IoPriority) -> Result<()> {
+    if priority != IoPriority::Normal {
+        eprintln!("IO priorities are not supported on this platform, ignore");
+    }
+    Ok(())
+}
+
+/// Parse a rate given in bytes, with optional `K`, `M` or `G` suffix
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    let (number, factor) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024u64),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("parse_rate: invalid rate {:?}: {}", s, e))?;
+    if number == 0 {
+        return Err(Error::from("parse_rate: the rate must be positive"));
+    }
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| Error::from(format!("parse_rate: the rate {:?} is too large", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn parse_rate_examples() -> Result<()> {
+        assert_eq!(parse_rate("100")?, 100);
+        assert_eq!(parse_rate("4k")?, 4 * 1024);
+        assert_eq!(parse_rate("10M/s")?, 10 * 1024 * 1024);
+        assert_eq!(parse_rate("1G")?, 1024 * 1024 * 1024);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999999G").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_delays_after_burst() {
+        let limit = RateLimit::new(100_000);
+        let start = Instant::now();
+        limit.consume(100_000);
+        limit.consume(50_000);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn throttled_copy_keeps_content() -> Result<()> {
+        let spec = Spec::new()?.with_file("source", Some("hello world"), None)?;
+        let throttle = Throttle::new().with_read_limit(1024).with_write_limit(1024);
+
+        let copied = throttle.copy(spec.path("source"), spec.path("target"))?;
+
+        assert_eq!(copied, 11);
+        assert_eq!(read_file(spec.path("target"))?, "hello world");
+        Ok(())
+    }
+}
@@ -0,0 +1,547 @@
+//! Create snapshots continuously while watching the source for changes
+//!
+//! The watcher collects the paths modified in the source via inotify. After
+//! each interval, a new snapshot is created from the previous one: all items
+//! outside of the changed paths are linked from the previous snapshot and
+//! their manifest entries are reused, only the changed paths are read from the
+//! source again.
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+
+use super::backup::{self, BackupAction, BackupOptions, BackupReport, BackupRun, IgnoreSpec};
+use super::events::Event;
+use super::manifest::{self, EntryKind, ManifestEntry};
+
+/// Create a new snapshot from the previous one and the changed paths
+///
+/// The changes are given relative to the source. Each change covers the item
+/// itself and all its children. If the previous snapshot has no manifest or
+/// names are folded, a full backup with the previous snapshot as reference is
+/// performed instead.
+pub fn incremental_snapshot(
+    source: impl AsRef<Path>,
+    previous: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    changes: &BTreeSet<PathBuf>,
+    ignore_spec: &impl IgnoreSpec,
+    options: &BackupOptions,
+) -> Result<BackupReport> {
+    let source = source.as_ref();
+    let previous = previous.as_ref();
+    let target = target.as_ref();
+
+    // NOTE: stored names of new items may collide with the linked ones
+    let entries = match manifest::read_manifest(previous)? {
+        Some(entries) if !options.fold_names => entries,
+        _ => return backup::run_backup(source, target, Some(previous), ignore_spec, options),
+    };
+
+    let mut run = BackupRun::new(source, target, Some(previous), options)?;
+    let result = link_unchanged(
+        &mut run, source, previous, target, entries, changes, options,
+    )
+    .and_then(|_| {
+        for change in outermost_changes(changes) {
+            // NOTE: deleted items are simply not part of the new snapshot
+            let item = source.join(change);
+            if item.symlink_metadata().is_ok() {
+                run.backup_tree(&item, ignore_spec)?;
+            }
+        }
+        Ok(())
+    });
+    run.finish(result)
+}
+
+fn link_unchanged(
+    run: &mut BackupRun,
+    source: &Path,
+    previous: &Path,
+    target: &Path,
+    entries: Vec<ManifestEntry>,
+    changes: &BTreeSet<PathBuf>,
+    options: &BackupOptions,
+) -> Result<()> {
+    for entry in entries {
+        let path = manifest::native_path(&entry.path);
+        if path.ancestors().any(|ancestor| changes.contains(ancestor)) {
+            continue;
+        }
+        let stored_path = manifest::native_path(entry.stored_path());
+        let reference_item = previous.join(&stored_path);
+        let target_item = target.join(&stored_path);
+
+        let action = match entry.kind {
+            EntryKind::Directory => {
+                backup::backup_directory(&target_item)?;
+                BackupAction::Directory
+            }
+            EntryKind::File | EntryKind::Symlink => backup::backup_file(
+                &reference_item,
+                &target_item,
+                Some(&reference_item),
+                options,
+            )?,
+            kind => {
+                // NOTE: special files that could not be recreated only exist
+                // in the manifest
+                let recreated = reference_item.symlink_metadata().is_ok();
+                if recreated {
+                    fs::hard_link(&reference_item, &target_item).map_err(|e| {
+                        Error::from(format!(
+                            "incremental_snapshot: could not create link: {}",
+                            e
+                        ))
+                    })?;
+                }
+                BackupAction::Special { kind, recreated }
+            }
+        };
+        let item = source.join(&path);
+        run.report.record(&item, action);
+        run.log.record(
+            Event::from_action(action)
+                .with_source(&item)
+                .with_target(&target_item)
+                .with_reference(Some(&reference_item)),
+        )?;
+        run.add_entry(entry);
+    }
+    Ok(())
+}
+
+/// The changes that are not contained in any other change
+fn outermost_changes(changes: &BTreeSet<PathBuf>) -> Vec<&Path> {
+    changes
+        .iter()
+        .filter(|change| {
+            change
+                .ancestors()
+                .skip(1)
+                .all(|ancestor| !changes.contains(ancestor))
+        })
+        .map(PathBuf::as_path)
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::watch;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use chrono::Local;
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use std::{
+        collections::{BTreeSet, HashMap},
+        fs, io,
+        path::{Path, PathBuf},
+        thread,
+        time::{Duration, Instant},
+    };
+    use tools_utils::{Error, Result};
+    use walkdir::WalkDir;
+
+    use super::super::backup::{self, BackupOptions, BackupReport, IgnoreSpec};
+    use super::super::events::EventFormat;
+    use super::super::lock::RepositoryLock;
+    use super::super::manifest::METADATA_DIR;
+    use super::super::snapshot;
+    use super::incremental_snapshot;
+
+    /// How often the inotify events are read
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Watch the source and create a new snapshot for each interval with changes
+    ///
+    /// The snapshots are created inside the repository and named after the
+    /// current time. The first snapshot is a full backup referencing the
+    /// latest existing snapshot. If a snapshot cannot be created, e.g., while
+    /// another backup holds the lock, its changes are kept and it is retried
+    /// after the next interval. This function only returns if the source
+    /// cannot be watched.
+    pub fn watch(
+        source: impl AsRef<Path>,
+        repository: impl AsRef<Path>,
+        ignore_spec: &impl IgnoreSpec,
+        options: &BackupOptions,
+        interval: Duration,
+    ) -> Result<()> {
+        let source = source.as_ref();
+        let repository = repository.as_ref();
+
+        // NOTE: start watching first to not miss changes during the backup
+        let mut watcher = ChangeWatcher::new(source, ignore_spec)?;
+        let mut previous: Option<PathBuf> = None;
+        let mut changes = BTreeSet::new();
+        loop {
+            // NOTE: changes made during the backup are only polled afterwards
+            if previous.is_none() || !changes.is_empty() {
+                let result = create_snapshot(repository, options, |target| match &previous {
+                    Some(previous) => incremental_snapshot(
+                        source,
+                        previous,
+                        target,
+                        &changes,
+                        ignore_spec,
+                        options,
+                    ),
+                    None => {
+                        let latest = snapshot::list_snapshots(repository)?
+                            .into_iter()
+                            .rfind(|path| path != target);
+                        backup::run_backup(source, target, latest.as_ref(), ignore_spec, options)
+                    }
+                });
+                match result {
+                    Ok(target) => {
+                        changes.clear();
+                        previous = Some(target);
+                    }
+                    Err(e) => eprintln!(
+                        "Could not create a snapshot, retry in {} seconds: {}",
+                        interval.as_secs(),
+                        e
+                    ),
+                }
+            }
+
+            let deadline = Instant::now() + interval;
+            while Instant::now() < deadline {
+                watcher.poll(&mut changes, ignore_spec)?;
+                thread::sleep(POLL_INTERVAL);
+            }
+            watcher.poll(&mut changes, ignore_spec)?;
+        }
+    }
+
+    /// Create a new snapshot directory in the locked repository and fill it
+    ///
+    /// If the backup fails, the partial snapshot is removed again.
+    pub fn create_snapshot(
+        repository: &Path,
+        options: &BackupOptions,
+        backup: impl FnOnce(&Path) -> Result<BackupReport>,
+    ) -> Result<PathBuf> {
+        let _lock = RepositoryLock::acquire(repository)?;
+        let target = create_snapshot_directory(repository)?;
+        match backup(&target) {
+            Ok(report) => {
+                print_summary(&target, &report, options.event_format);
+                Ok(target)
+            }
+            Err(e) => {
+                if let Err(remove_error) = fs::remove_dir_all(&target) {
+                    eprintln!(
+                        "Could not remove the partial snapshot {:?}: {}",
+                        target, remove_error
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn create_snapshot_directory(repository: &Path) -> Result<PathBuf> {
+        let target = repository.join(Local::now().format("%Y-%m-%d_%H-%M-%S").to_string());
+        fs::create_dir(&target).map_err(|e| {
+            Error::from(format!("watch: cannot create snapshot {:?}: {}", target, e))
+        })?;
+        Ok(target)
+    }
+
+    fn print_summary(target: &Path, report: &BackupReport, event_format: EventFormat) {
+        event_format.print_message(format!(
+            "Created snapshot {:?}: copied {} files ({} bytes), linked {} files",
+            target, report.copied_files, report.copied_bytes, report.linked_files
+        ));
+    }
+
+    /// Collect the paths modified inside the source
+    ///
+    /// Each directory of the source is watched separately. Directories
+    /// created or moved into the source while watching are added, directories
+    /// moved out of it are removed.
+    pub struct ChangeWatcher {
+        source: PathBuf,
+        inotify: Inotify,
+        /// The watched directories relative to the source
+        directories: HashMap<WatchDescriptor, PathBuf>,
+        buffer: Vec<u8>,
+    }
+
+    impl ChangeWatcher {
+        pub fn new(source: impl AsRef<Path>, ignore_spec: &impl IgnoreSpec) -> Result<Self> {
+            let inotify = Inotify::init()
+                .map_err(|e| format!("ChangeWatcher: cannot initialize inotify: {}", e))?;
+            let mut result = Self {
+                source: source.as_ref().to_owned(),
+                inotify,
+                directories: HashMap::new(),
+                buffer: vec![0; 64 * 1024],
+            };
+            result.watch_tree(Path::new(""), ignore_spec)?;
+            Ok(result)
+        }
+
+        /// Add the changes since the last call, relative to the source
+        ///
+        /// If events were lost, the source itself, i.e., the empty path, is
+        /// added.
+        pub fn poll(
+            &mut self,
+            changes: &mut BTreeSet<PathBuf>,
+            ignore_spec: &impl IgnoreSpec,
+        ) -> Result<()> {
+            loop {
+                let mut created = Vec::new();
+                let mut moved = Vec::new();
+
+                let events = match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => {
+                        return Err(Error::from(format!(
+                            "ChangeWatcher: cannot read events: {}",
+                            e
+                        )))
+                    }
+                };
+                for event in events {
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        changes.insert(PathBuf::new());
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.directories.remove(&event.wd);
+                        continue;
+                    }
+                    let path = match (self.directories.get(&event.wd), event.name) {
+                        (Some(directory), Some(name)) => directory.join(name),
+                        (Some(directory), None) => directory.clone(),
+                        (None, _) => continue,
+                    };
+                    if event.mask.contains(EventMask::ISDIR) {
+                        if event
+                            .mask
+                            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                        {
+                            created.push(path.clone());
+                        } else if event.mask.contains(EventMask::MOVED_FROM) {
+                            moved.push(path.clone());
+                        }
+                    }
+                    changes.insert(path);
+                }
+
+                for path in moved {
+                    self.unwatch_tree(&path);
+                }
+                for path in created {
+                    self.watch_tree(&path, ignore_spec)?;
+                }
+            }
+        }
+
+        fn watch_tree(&mut self, rel_root: &Path, ignore_spec: &impl IgnoreSpec) -> Result<()> {
+            let mask = WatchMask::MODIFY
+                | WatchMask::ATTRIB
+                | WatchMask::CLOSE_WRITE
+                | WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::DONT_FOLLOW
+                | WatchMask::ONLYDIR;
+
+            let root = self.source.join(rel_root);
+            let mut walker = WalkDir::new(&root).into_iter();
+            while let Some(entry) = walker.next() {
+                // NOTE: items may be removed while they are added
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if !entry.file_type().is_dir() {
+                    continue;
+                }
+                let item = entry.path();
+                let rel_item = item
+                    .strip_prefix(&self.source)
+                    .map_err(|e| format!("Cannot determine relative path: {}", e))?;
+                if rel_item == Path::new(METADATA_DIR)
+                    || (item != self.source && ignore_spec.is_ignored(item)?)
+                {
+                    walker.skip_current_dir();
+                    continue;
+                }
+
+                match self.inotify.watches().add(item, mask) {
+                    Ok(wd) => {
+                        self.directories.insert(wd, rel_item.to_owned());
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(Error::from(format!(
+                        "ChangeWatcher: cannot watch {:?} (see fs.inotify.max_user_watches): {}",
+                        item, e
+                    )))
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn unwatch_tree(&mut self, rel_root: &Path) {
+            let removed = self
+                .directories
+                .iter()
+                .filter(|(_, path)| path.starts_with(rel_root))
+                .map(|(wd, _)| wd.clone())
+                .collect::<Vec<_>>();
+            for wd in removed {
+                self.directories.remove(&wd);
+                // NOTE: the watch may already be removed by the kernel
+                let _ = self.inotify.watches().remove(wd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backup::{run_backup, NoOpIgnoreSpec};
+    #[cfg(target_os = "linux")]
+    use super::super::lock::RepositoryLock;
+    #[cfg(target_os = "linux")]
+    use super::super::snapshot;
+    use super::super::test_spec::{read_file, Spec};
+    use super::*;
+
+    #[test]
+    fn incremental_snapshots_only_read_changed_paths() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "same.txt"), Some("same"), None)?
+            .with_file(("source", "changed.txt"), Some("old"), None)?
+            .with_file(("source", "removed.txt"), Some("removed"), None)?
+            .with_file(("source", "dir", "same.txt"), Some("same"), None)?
+            .with_directory("repository")?;
+        let options = BackupOptions::default();
+        let first = spec.path(("repository", "first"));
+        run_backup(
+            spec.path("source"),
+            &first,
+            Option::<&Path>::None,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+
+        // NOTE: the files must be newer than the copies of the first snapshot
+        fs::write(spec.path(("source", "changed.txt")), "new content").unwrap();
+        fs::create_dir(spec.path(("source", "dir", "new"))).unwrap();
+        fs::write(spec.path(("source", "dir", "new", "new.txt")), "new").unwrap();
+        fs::remove_file(spec.path(("source", "removed.txt"))).unwrap();
+        // NOTE: unchanged, but not reported, files are taken from the snapshot
+        fs::write(spec.path(("source", "same.txt")), "unreported").unwrap();
+
+        let changes = vec!["changed.txt", "removed.txt", "dir/new", "dir/new/new.txt"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        let second = spec.path(("repository", "second"));
+        let report = incremental_snapshot(
+            spec.path("source"),
+            &first,
+            &second,
+            &changes,
+            &NoOpIgnoreSpec,
+            &options,
+        )?;
+
+        assert_eq!(report.copied_files, 2);
+        assert_eq!(report.linked_files, 2);
+        assert_eq!(read_file(second.join("changed.txt"))?, "new content");
+        assert_eq!(read_file(second.join("same.txt"))?, "same");
+        assert_eq!(
+            read_file(second.join("dir").join("new").join("new.txt"))?,
+            "new"
+        );
+        assert!(!second.join("removed.txt").exists());
+
+        let mut paths = manifest::read_manifest(&second)?
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "changed.txt",
+                "dir",
+                "dir/new",
+                "dir/new/new.txt",
+                "dir/same.txt",
+                "same.txt"
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watcher_reports_changed_paths() -> Result<()> {
+        let spec = Spec::new()?
+            .with_file(("source", "a.txt"), Some("a"), None)?
+            .with_file(("source", "dir", "b.txt"), Some("b"), None)?;
+        let mut watcher = linux::ChangeWatcher::new(spec.path("source"), &NoOpIgnoreSpec)?;
+
+        fs::write(spec.path(("source", "dir", "b.txt")), "changed").unwrap();
+        fs::create_dir(spec.path(("source", "new"))).unwrap();
+        let mut changes = BTreeSet::new();
+        watcher.poll(&mut changes, &NoOpIgnoreSpec)?;
+
+        // NOTE: the new directory is watched after the first poll
+        fs::write(spec.path(("source", "new", "c.txt")), "c").unwrap();
+        watcher.poll(&mut changes, &NoOpIgnoreSpec)?;
+
+        let expected = vec!["dir/b.txt", "new", "new/c.txt"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(changes, expected);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_snapshots_are_removed() -> Result<()> {
+        let spec = Spec::new()?.with_directory("repository")?;
+        let repository = spec.path("repository");
+        let options = BackupOptions::default();
+
+        let result = linux::create_snapshot(&repository, &options, |target| {
+            fs::write(target.join("partial.txt"), "partial").unwrap();
+            Err(Error::from("backup failed"))
+        });
+        assert!(result.is_err());
+        assert!(snapshot::list_snapshots(&repository)?.is_empty());
+
+        // NOTE: e.g., a manual backup of the same repository
+        let lock = RepositoryLock::acquire(&repository)?;
+        let result = linux::create_snapshot(&repository, &options, |_| {
+            panic!("the backup must not run without the lock")
+        });
+        assert!(result.is_err());
+        assert!(snapshot::list_snapshots(&repository)?.is_empty());
+        drop(lock);
+
+        let target =
+            linux::create_snapshot(&repository, &options, |_| Ok(BackupReport::default()))?;
+        assert_eq!(snapshot::list_snapshots(&repository)?, vec![target]);
+        Ok(())
+    }
+}
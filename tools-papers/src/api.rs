@@ -0,0 +1,387 @@
+//! Client for the arXiv API
+//!
+//! The API returns the metadata of the requested papers as an Atom feed. Many
+//! papers are requested at once, and consecutive requests are delayed as
+//! requested by the API guidelines. Papers missing from the feed, e.g., since
+//! their batch could not be downloaded, can be fetched one by one from the text
+//! format of their abstract page.
+//!
+//! See: <https://arxiv.org/help/api/user-manual>
+use chrono::DateTime;
+use reqwest::header::USER_AGENT;
+use roxmltree::{Document, Node};
+use std::{thread, time::Duration};
+use tools_utils::{Error, Result};
+
+use super::arxiv::{normalize_whitespace, parse_arxiv_metadata, ArxivId, ArxivMetadata};
+
+pub const DEFAULT_URL: &str = "https://export.arxiv.org/api/query";
+pub const DEFAULT_ABSTRACT_URL: &str = "https://export.arxiv.org/abs";
+
+/// The maximum number of ids requested at once
+const BATCH_SIZE: usize = 100;
+
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+const ARXIV_NS: &str = "http://arxiv.org/schemas/atom";
+
+pub struct ArxivClient {
+    url: String,
+    abstract_url: String,
+    delay: Duration,
+    client: reqwest::blocking::Client,
+}
+
+impl ArxivClient {
+    pub fn new() -> Self {
+        Self::with_url(DEFAULT_URL)
+    }
+
+    /// Use a different endpoint, e.g., a mirror or a mock server
+    pub fn with_url(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            abstract_url: DEFAULT_ABSTRACT_URL.to_owned(),
+            // NOTE: the guidelines ask for 3 seconds between requests
+            delay: Duration::from_secs(3),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    /// Use the endpoints of a server started by `mock::serve`, without delay
+    #[cfg(test)]
+    pub fn with_mock_server(url: &str) -> Self {
+        Self {
+            abstract_url: url.replace("/api/query", "/abs"),
+            delay: Duration::from_millis(0),
+            ..Self::with_url(url)
+        }
+    }
+
+    /// Fetch the metadata of all papers, in batches of multiple ids
+    ///
+    /// Ids without version return the latest version. Batches that cannot be
+    /// downloaded are recorded as errors and do not affect the other batches.
+    pub fn fetch(&self, ids: &[ArxivId]) -> Feed {
+        let mut result = Feed::default();
+        for (idx, batch) in ids.chunks(BATCH_SIZE).enumerate() {
+            if idx != 0 {
+                thread::sleep(self.delay);
+            }
+            match self.fetch_batch(batch) {
+                Ok(feed) => {
+                    result.papers.extend(feed.papers);
+                    result.errors.extend(feed.errors);
+                }
+                Err(e) => result.errors.push(e),
+            }
+        }
+        result
+    }
+
+    fn fetch_batch(&self, batch: &[ArxivId]) -> Result<Feed> {
+        let feed = self
+            .client
+            .get(&self.url)
+            .query(&[
+                (
+                    "id_list",
+                    batch
+                        .iter()
+                        .map(ArxivId::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                ("max_results", batch.len().to_string()),
+            ])
+            .header(USER_AGENT, "ArxivPaperTools/1.0")
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .map_err(|e| format!("Error during download of metadata: {}", e))?;
+        parse_feed(&feed)
+    }
+
+    /// Fetch the metadata of a single paper from its abstract page
+    ///
+    /// The page always describes the latest version. Since it is requested
+    /// after other requests, it is delayed like consecutive batches.
+    pub fn fetch_abstract_page(&self, id: &ArxivId) -> Result<ArxivMetadata> {
+        thread::sleep(self.delay);
+        let page = self
+            .client
+            .get(&format!("{}/{}", self.abstract_url, id.base()))
+            .query(&[("fmt", "txt")])
+            .header(USER_AGENT, "ArxivPaperTools/1.0")
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .map_err(|e| {
+                format!(
+                    "Error during download of the abstract page of {}: {}",
+                    id, e
+                )
+            })?;
+        parse_arxiv_metadata(&page)
+            .map_err(|e| Error::from(format!("Invalid abstract page of {}: {}", id, e)))
+    }
+}
+
+/// The papers of an API response
+#[derive(Debug, Default)]
+pub struct Feed {
+    pub papers: Vec<ArxivMetadata>,
+    /// Entries reporting an error or that cannot be parsed, e.g., for
+    /// invalid ids, and batches that could not be downloaded
+    pub errors: Vec<Error>,
+}
+
+/// Parse the Atom feed returned by the API
+///
+/// Invalid entries do not affect the other papers of the feed.
+pub fn parse_feed(s: &str) -> Result<Feed> {
+    let document =
+        Document::parse(s).map_err(|e| format!("Cannot parse the arXiv response: {}", e))?;
+    let mut result = Feed::default();
+    for entry in document
+        .root_element()
+        .children()
+        .filter(|node| node.has_tag_name((ATOM_NS, "entry")))
+    {
+        match parse_entry(entry) {
+            Ok(metadata) => result.papers.push(metadata),
+            Err(e) => result.errors.push(e),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_entry(entry: Node) -> Result<ArxivMetadata> {
+    let url = child_text(entry, ATOM_NS, "id")
+        .ok_or_else(|| Error::from("Missing id in arXiv response"))?;
+
+    // NOTE: errors, e.g., for invalid ids, are reported as entries
+    if url.contains("/api/errors") {
+        let message = child_text(entry, ATOM_NS, "summary").unwrap_or(url);
+        return Err(Error::from(format!("arXiv API error: {}", message)));
+    }
+
+    let versioned_id = url
+        .find("/abs/")
+        .map(|idx| &url[idx + 5..])
+        .ok_or_else(|| Error::from(format!("Invalid id {:?} in arXiv response", url)))?;
+    let parsed = ArxivId::parse(versioned_id)
+        .ok_or_else(|| Error::from(format!("Invalid id {:?} in arXiv response", url)))?;
+    let id = parsed.base();
+    let version = parsed
+        .version()
+        .ok_or_else(|| Error::from(format!("Missing version in id {:?}", versioned_id)))?;
+
+    let date = |name: &str| {
+        let value = child_text(entry, ATOM_NS, name)
+            .ok_or_else(|| Error::from(format!("Missing {} date for {}", name, id)))?;
+        DateTime::parse_from_rfc3339(value)
+            .map_err(|e| Error::from(format!("Invalid {} date for {}: {}", name, id, e)))
+    };
+
+    Ok(ArxivMetadata {
+        id: id.to_owned(),
+        version,
+        title: child_text(entry, ATOM_NS, "title")
+            .map(normalize_whitespace)
+            .ok_or_else(|| Error::from(format!("Missing title for {}", id)))?,
+        authors: entry
+            .children()
+            .filter(|node| node.has_tag_name((ATOM_NS, "author")))
+            .filter_map(|author| child_text(author, ATOM_NS, "name"))
+            .map(normalize_whitespace)
+            .collect(),
+        abstract_: child_text(entry, ATOM_NS, "summary")
+            .map(|s| s.trim().to_owned())
+            .unwrap_or_default(),
+        categories: entry
+            .children()
+            .filter(|node| node.has_tag_name((ATOM_NS, "category")))
+            .filter_map(|node| node.attribute("term"))
+            .map(String::from)
+            .collect(),
+        published: date("published")?,
+        updated: date("updated")?,
+        doi: child_text(entry, ARXIV_NS, "doi").map(normalize_whitespace),
+        journal_ref: child_text(entry, ARXIV_NS, "journal_ref").map(normalize_whitespace),
+        comments: child_text(entry, ARXIV_NS, "comment").map(normalize_whitespace),
+        license: None,
+        revisions: Vec::new(),
+    })
+}
+
+fn child_text<'a>(node: Node<'a, '_>, namespace: &str, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name((namespace, name)))
+        .and_then(|child| child.text())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY_FIXTURE: &str = include_str!("../fixtures/arxiv_query.xml");
+    const ERROR_FIXTURE: &str = include_str!("../fixtures/arxiv_error.xml");
+    const ABSTRACT_FIXTURE: &str = include_str!("../fixtures/arxiv_abstract.txt");
+
+    #[test]
+    fn feed_is_parsed() -> Result<()> {
+        let papers = parse_feed(QUERY_FIXTURE)?.papers;
+        assert_eq!(papers.len(), 2);
+
+        assert_eq!(papers[0].id, "1706.03762");
+        assert_eq!(papers[0].version, 6);
+        assert_eq!(papers[0].title, "Attention Is All You Need");
+        assert_eq!(papers[0].authors.len(), 8);
+        assert_eq!(papers[0].authors[5], "Aidan N. Gomez");
+        assert_eq!(papers[0].categories, vec!["cs.CL", "cs.LG"]);
+        assert!(papers[0].abstract_.starts_with("The dominant sequence"));
+        assert_eq!(
+            papers[0].published,
+            DateTime::parse_from_rfc3339("2017-06-12T17:57:34Z").unwrap()
+        );
+        assert_eq!(papers[0].doi, None);
+        assert_eq!(papers[0].comments.as_deref(), Some("15 pages, 5 figures"));
+
+        assert_eq!(papers[1].id, "hep-th/9711200");
+        assert_eq!(papers[1].version, 3);
+        assert_eq!(papers[1].authors, vec!["Juan M. Maldacena"]);
+        assert_eq!(papers[1].doi.as_deref(), Some("10.1023/A:1026654312961"));
+        assert_eq!(
+            papers[1].journal_ref.as_deref(),
+            Some("Adv.Theor.Math.Phys.2:231-252,1998")
+        );
+        let id = |s| ArxivId::parse(s).unwrap();
+        assert!(papers[1].matches(&id("hep-th/9711200")));
+        assert!(papers[1].matches(&id("hep-th/9711200v3")));
+        assert!(!papers[1].matches(&id("hep-th/9711200v2")));
+        Ok(())
+    }
+
+    #[test]
+    fn api_errors_are_reported() -> Result<()> {
+        let feed = parse_feed(ERROR_FIXTURE)?;
+        assert!(feed.papers.is_empty());
+        assert_eq!(feed.errors.len(), 1);
+        assert!(feed.errors[0]
+            .to_string()
+            .contains("incorrect id format for 1234.abcde"));
+
+        // NOTE: the other papers of the feed are still returned
+        let start = ERROR_FIXTURE.find("<entry>").unwrap();
+        let mixed = QUERY_FIXTURE.replace("</feed>", &ERROR_FIXTURE[start..]);
+        let feed = parse_feed(&mixed)?;
+        assert_eq!(feed.papers.len(), 2);
+        assert_eq!(feed.errors.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn ids_are_requested_in_batches() -> Result<()> {
+        let (url, requests) = mock::serve(vec![(200, QUERY_FIXTURE)]);
+        let client = ArxivClient::with_mock_server(&url);
+
+        let ids = ["1706.03762", "hep-th/9711200v3"]
+            .iter()
+            .map(|s| ArxivId::parse(s).unwrap())
+            .collect::<Vec<_>>();
+        let papers = client.fetch(&ids).papers;
+        assert_eq!(papers.len(), 2);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["GET /api/query?id_list=1706.03762%2Chep-th%2F9711200v3&max_results=2 HTTP/1.1"]
+        );
+
+        let ids = vec![ids[0].clone(); BATCH_SIZE + 1];
+        client.fetch(&ids);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batches_keep_the_other_papers() -> Result<()> {
+        let (url, requests) = mock::serve(vec![(500, ""), (200, QUERY_FIXTURE)]);
+        let client = ArxivClient::with_mock_server(&url);
+
+        let ids = vec![ArxivId::parse("1706.03762").unwrap(); BATCH_SIZE + 1];
+        let feed = client.fetch(&ids);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert_eq!(feed.papers.len(), 2);
+        assert_eq!(feed.errors.len(), 1);
+        assert!(feed.errors[0].to_string().contains("500"));
+        Ok(())
+    }
+
+    #[test]
+    fn abstract_pages_are_fetched() -> Result<()> {
+        let (url, requests) = mock::serve(vec![(200, ABSTRACT_FIXTURE)]);
+        let client = ArxivClient::with_mock_server(&url);
+
+        let paper = client.fetch_abstract_page(&ArxivId::parse("1310.1757").unwrap())?;
+        assert_eq!(paper.title, "A Deep and Tractable Density Estimator");
+        assert_eq!(paper.version, 2);
+        assert_eq!(paper.revisions.len(), 2);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["GET /abs/1310.1757?fmt=txt HTTP/1.1"]
+        );
+        Ok(())
+    }
+}
+
+/// A minimal HTTP server for tests
+#[cfg(test)]
+pub mod mock {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    /// Answer the requests with the given status codes and bodies in order
+    ///
+    /// The last response is repeated for all further requests. Returns the
+    /// url of the query endpoint and the recorded request lines.
+    pub fn serve(responses: Vec<(u16, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/query", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for (idx, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                recorded.lock().unwrap().push(line.trim().to_owned());
+
+                // skip the headers
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                let (status, body) = responses[idx.min(responses.len() - 1)];
+                let reason = if status == 200 { "OK" } else { "Error" };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} {}\r\nContent-Type: application/atom+xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    reason,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+}
@@ -1,26 +1,304 @@
 //! Helper to handle papers from arxiv
 //!
+use chrono::{DateTime, FixedOffset};
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
 
-/// Return true if the passed string is the filename of an arXiv paper
-pub fn is_arxiv_paper(s: &str) -> bool {
-    lazy_static! {
-        static ref PATTERN: Regex = Regex::new(r##"^\d{4}\.\d*(v\d+)?$"##).unwrap();
+/// The metadata of a single version of an arXiv paper
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArxivMetadata {
+    /// The identifier without version, e.g., `1706.03762` or `hep-th/9711200`
+    pub id: String,
+    pub version: u32,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub abstract_: String,
+    pub categories: Vec<String>,
+    /// The submission time of the first version
+    pub published: DateTime<FixedOffset>,
+    /// The submission time of this version
+    pub updated: DateTime<FixedOffset>,
+    pub doi: Option<String>,
+    pub journal_ref: Option<String>,
+    pub comments: Option<String>,
+    /// Only available from the abstract page
+    pub license: Option<String>,
+    /// All versions up to this one, only available from the abstract page
+    pub revisions: Vec<Revision>,
+}
+
+/// A single version of a paper as listed on the abstract page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub version: u32,
+    pub date: DateTime<FixedOffset>,
+    /// The size of the submission in kilobytes
+    pub size_kb: u64,
+}
+
+/// The error returned if the metadata cannot be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataError {
+    /// The field that could not be parsed, e.g., `Title`
+    pub field: String,
+    pub message: String,
+}
+
+impl MetadataError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid field {:?}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<MetadataError> for tools_utils::Error {
+    fn from(error: MetadataError) -> Self {
+        error.to_string().into()
+    }
+}
+
+impl ArxivMetadata {
+    /// Check whether the metadata belongs to the requested identifier
+    ///
+    /// Identifiers without version match any version.
+    pub fn matches(&self, id: &ArxivId) -> bool {
+        id.base() == self.id && id.version().is_none_or(|version| version == self.version)
+    }
+
+    /// The identifier of exactly this version
+    pub fn versioned_id(&self) -> ArxivId {
+        ArxivId {
+            base: self.id.clone(),
+            version: Some(self.version),
+        }
+    }
+}
+
+/// An arXiv identifier with an optional version
+///
+/// Both new-style identifiers, e.g., `1706.03762v3`, and old-style identifiers,
+/// e.g., `hep-th/9901001`, are supported. For old-style identifiers the
+/// subject class, e.g., `GT` in `math.GT/0309136`, is dropped, since it is not
+/// part of the canonical identifier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArxivId {
+    base: String,
+    version: Option<u32>,
+}
+
+impl ArxivId {
+    /// Parse an identifier, optionally prefixed with `arXiv:`
+    pub fn parse(s: &str) -> Option<Self> {
+        lazy_static! {
+            static ref PATTERN: Regex = Regex::new(concat!(
+                r##"^(?i:arxiv:)?"##,
+                r##"(?:(?P<new>\d{4}\.\d{4,5})"##,
+                r##"|(?P<archive>[a-z]+(?:-[a-z]+)?)(?:\.[A-Z]{2})?/(?P<number>\d{7}))"##,
+                r##"(?:v(?P<version>\d+))?$"##,
+            ))
+            .unwrap();
+        }
+        Self::from_captures(PATTERN.captures(s.trim())?)
+    }
+
+    /// Parse the identifier from the file name of a downloaded paper
+    ///
+    /// In addition to the identifiers accepted by [`ArxivId::parse`], common
+    /// variants of file names are recognized, e.g., `arXiv-1706.03762`,
+    /// `1706.03762v3 (1)` as created by browsers for repeated downloads, or
+    /// `math.GT_0309136` and `hep-th9901001` for old-style identifiers.
+    pub fn from_file_name(stem: &str) -> Option<Self> {
+        lazy_static! {
+            static ref PATTERN: Regex = Regex::new(concat!(
+                r##"^(?i:arxiv[-_:. ]?)?"##,
+                r##"(?:(?P<new>\d{4}\.\d{4,5})"##,
+                r##"|(?P<archive>[a-z]+(?:-[a-z]+)?)(?:\.[A-Z]{2})?[_/]?(?P<number>\d{7}))"##,
+                r##"(?:v(?P<version>\d+))?"##,
+                r##"(?: ?\(\d+\))?$"##,
+            ))
+            .unwrap();
+        }
+        Self::from_captures(PATTERN.captures(stem.trim())?)
+    }
+
+    fn from_captures(captures: regex::Captures) -> Option<Self> {
+        let base = match (
+            captures.name("new"),
+            captures.name("archive"),
+            captures.name("number"),
+        ) {
+            (Some(new), _, _) => new.as_str().to_owned(),
+            (None, Some(archive), Some(number))
+                if OLD_STYLE_ARCHIVES.binary_search(&archive.as_str()).is_ok() =>
+            {
+                format!("{}/{}", archive.as_str(), number.as_str())
+            }
+            _ => return None,
+        };
+        let version = match captures.name("version") {
+            Some(version) => Some(version.as_str().parse().ok()?),
+            None => None,
+        };
+        Some(Self { base, version })
+    }
+
+    /// The identifier without version, e.g., `1706.03762` or `hep-th/9901001`
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    /// The identifier in a form usable in file names, i.e., without slashes
+    pub fn to_file_name(&self) -> String {
+        self.to_string().replace('/', "_")
+    }
+}
+
+/// The archives of old-style identifiers, used until March 2007, sorted
+///
+/// See: <https://info.arxiv.org/help/arxiv_identifier.html>
+const OLD_STYLE_ARCHIVES: &[&str] = &[
+    "acc-phys", "adap-org", "alg-geom", "ao-sci", "astro-ph", "atom-ph", "bayes-an", "chao-dyn",
+    "chem-ph", "cmp-lg", "comp-gas", "cond-mat", "cs", "dg-ga", "funct-an", "gr-qc", "hep-ex",
+    "hep-lat", "hep-ph", "hep-th", "math", "math-ph", "mtrl-th", "nlin", "nucl-ex", "nucl-th",
+    "patt-sol", "physics", "plasm-ph", "q-alg", "q-bio", "quant-ph", "solv-int", "supr-con",
+];
+
+impl fmt::Display for ArxivId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "{}v{}", self.base, version),
+            None => write!(f, "{}", self.base),
+        }
     }
-    PATTERN.is_match(s)
 }
 
 /// Parse the metadata of the arxiv text format
-pub fn parse_arxiv_metadata(s: &str) -> Option<HashMap<&str, &str>> {
-    split_arxiv_metadata(s).map(|(header, abstract_)| {
-        let mut data = parse_arxiv_header(header);
-        data.insert("abstract", abstract_);
-        data
+///
+/// Unlike the API, the text format of the abstract page
+/// (`https://export.arxiv.org/abs/{id}?fmt=txt`) includes the license and the
+/// sizes of all revisions. The metadata describes the latest revision.
+pub fn parse_arxiv_metadata(s: &str) -> Result<ArxivMetadata, MetadataError> {
+    let (header, abstract_) = split_arxiv_metadata(s)
+        .ok_or_else(|| MetadataError::new("header", "missing \\\\ separators"))?;
+    let data = parse_arxiv_header(header);
+    let required = |key: &str| {
+        data.get(key)
+            .map(|value| normalize_whitespace(value))
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| MetadataError::new(key, "missing"))
+    };
+    let optional = |key: &str| data.get(key).map(|value| normalize_whitespace(value));
+
+    let mut revisions = Vec::new();
+    for (key, value) in &data {
+        let version = if *key == "Date" {
+            1
+        } else if let Some(version) = parse_revision_key(key) {
+            version
+        } else {
+            continue;
+        };
+        revisions.push(parse_revision(key, version, value)?);
+    }
+    revisions.sort_by_key(|revision| revision.version);
+    let (first, last) = match (revisions.first(), revisions.last()) {
+        (Some(first), Some(last)) => (first.clone(), last.clone()),
+        _ => return Err(MetadataError::new("Date", "missing")),
+    };
+
+    let authors = split_authors(&required("Authors")?);
+    if authors.is_empty() {
+        return Err(MetadataError::new("Authors", "no authors listed"));
+    }
+
+    Ok(ArxivMetadata {
+        id: required("arXiv")?,
+        version: last.version,
+        title: required("Title")?,
+        authors,
+        abstract_: abstract_.to_owned(),
+        categories: required("Categories")?
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+        published: first.date,
+        updated: last.date,
+        doi: optional("DOI"),
+        journal_ref: optional("Journal-ref"),
+        comments: optional("Comments"),
+        license: optional("License"),
+        revisions,
+    })
+}
+
+/// Extract the version from keys of the form `Date (revised v2)`
+fn parse_revision_key(key: &str) -> Option<u32> {
+    key.strip_prefix("Date (revised v")?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+}
+
+/// Parse revisions of the form `Mon, 12 Jun 2017 17:57:34 GMT   (1102kb,D)`
+fn parse_revision(key: &str, version: u32, value: &str) -> Result<Revision, MetadataError> {
+    let (date, size) = match value.find('(') {
+        Some(idx) => (value[..idx].trim(), &value[idx + 1..]),
+        None => return Err(MetadataError::new(key, "missing size")),
+    };
+    let date = DateTime::parse_from_rfc2822(date)
+        .map_err(|e| MetadataError::new(key, format!("invalid date {:?}: {}", date, e)))?;
+    let size_kb = size
+        .find("kb")
+        .and_then(|idx| size[..idx].trim().parse().ok())
+        .ok_or_else(|| MetadataError::new(key, format!("invalid size {:?}", size)))?;
+    Ok(Revision {
+        version,
+        date,
+        size_kb,
     })
 }
 
+/// Split the author list, affiliations in parentheses are removed
+fn split_authors(s: &str) -> Vec<String> {
+    let mut without_affiliations = String::new();
+    let mut depth = 0;
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            c if depth == 0 => without_affiliations.push(c),
+            _ => {}
+        }
+    }
+    without_affiliations
+        .split(',')
+        .flat_map(|part| part.split(" and "))
+        .map(normalize_whitespace)
+        .filter(|author| !author.is_empty())
+        .collect()
+}
+
+/// Replace line breaks and repeated whitespace by single spaces
+pub fn normalize_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 fn split_arxiv_metadata(s: &str) -> Option<(&str, &str)> {
     let start_header = s.find(r"\\")?;
     let start_header = start_header + 2;
@@ -30,10 +308,7 @@ fn split_arxiv_metadata(s: &str) -> Option<(&str, &str)> {
     let start_abstract = start_header + start_abstract + 2;
 
     let end_abstract = s[start_abstract..].find(r"\\");
-    let end_abstract = end_abstract
-        .map(|v| v + start_abstract)
-        .or_else(|| Some(s.len()))
-        .unwrap();
+    let end_abstract = end_abstract.map(|v| v + start_abstract).unwrap_or(s.len());
 
     Some((
         &s[start_header..end_header],
@@ -62,7 +337,7 @@ fn parse_arxiv_header(s: &str) -> HashMap<&str, &str> {
         match state {
             HeaderParserState::ParseKey(start) => {
                 if c == ':' {
-                    current_key = &s[start..i].trim();
+                    current_key = s[start..i].trim();
                     state = HeaderParserState::ParseValue(i + 1);
                 }
             }
@@ -75,7 +350,7 @@ fn parse_arxiv_header(s: &str) -> HashMap<&str, &str> {
                 if c.is_whitespace() && c != '\n' {
                     state = HeaderParserState::ParseValue(start)
                 } else {
-                    result.insert(current_key, &s[start..i - 1].trim());
+                    result.insert(current_key, s[start..i - 1].trim());
                     state = HeaderParserState::ParseKey(i);
                 }
             }
@@ -84,10 +359,10 @@ fn parse_arxiv_header(s: &str) -> HashMap<&str, &str> {
 
     match state {
         HeaderParserState::AfterNewLine(start) => {
-            result.insert(current_key, &s[start..].trim());
+            result.insert(current_key, s[start..].trim());
         }
         HeaderParserState::ParseKey(start) => {
-            result.insert(current_key, &s[start..].trim());
+            result.insert(current_key, s[start..].trim());
         }
         _ => {}
     }
@@ -96,22 +371,115 @@ fn parse_arxiv_header(s: &str) -> HashMap<&str, &str> {
 }
 
 #[cfg(test)]
-mod is_arxiv_paper_tests {
-    use super::is_arxiv_paper;
+mod arxiv_id_tests {
+    use super::{ArxivId, OLD_STYLE_ARCHIVES};
+
+    fn id(base: &str, version: Option<u32>) -> Option<ArxivId> {
+        Some(ArxivId {
+            base: base.to_owned(),
+            version,
+        })
+    }
 
     #[test]
-    fn example() {
-        assert_eq!(true, is_arxiv_paper("1706.03762v3"));
+    fn identifiers() {
+        assert_eq!(ArxivId::parse("1706.03762v3"), id("1706.03762", Some(3)));
+        assert_eq!(ArxivId::parse("1310.1757"), id("1310.1757", None));
+        assert_eq!(ArxivId::parse("arXiv:1706.03762"), id("1706.03762", None));
+        assert_eq!(ArxivId::parse("hep-th/9901001"), id("hep-th/9901001", None));
+        assert_eq!(
+            ArxivId::parse("math.GT/0309136v2"),
+            id("math/0309136", Some(2))
+        );
+        assert_eq!(ArxivId::parse("hep-th_9901001"), None);
+        assert_eq!(ArxivId::parse("scan/2019001"), None);
+        assert_eq!(ArxivId::parse("1706.037"), None);
+    }
+
+    #[test]
+    fn file_names() {
         assert_eq!(
-            false,
-            is_arxiv_paper("2ef4811bc3112c2561c8e666b15980d8ca4700e6")
+            ArxivId::from_file_name("1706.03762v3"),
+            id("1706.03762", Some(3))
+        );
+        assert_eq!(
+            ArxivId::from_file_name("arXiv-1706.03762"),
+            id("1706.03762", None)
+        );
+        assert_eq!(
+            ArxivId::from_file_name("1706.03762v3 (1)"),
+            id("1706.03762", Some(3))
+        );
+        assert_eq!(
+            ArxivId::from_file_name("math.GT_0309136"),
+            id("math/0309136", None)
+        );
+        assert_eq!(
+            ArxivId::from_file_name("hep-th9901001v1"),
+            id("hep-th/9901001", Some(1))
+        );
+        assert_eq!(ArxivId::from_file_name("scan_2019001"), None);
+        assert_eq!(ArxivId::from_file_name("invoice2019001"), None);
+        assert_eq!(
+            ArxivId::from_file_name("2ef4811bc3112c2561c8e666b15980d8ca4700e6"),
+            None
+        );
+        assert_eq!(
+            ArxivId::from_file_name("1706.03762_attention_is_all_you_need"),
+            None
         );
     }
+
+    #[test]
+    fn old_style_archives_are_sorted() {
+        let mut sorted = OLD_STYLE_ARCHIVES.to_vec();
+        sorted.sort_unstable();
+        assert_eq!(sorted, OLD_STYLE_ARCHIVES);
+    }
+
+    #[test]
+    fn formatting() {
+        let old_style = ArxivId::parse("hep-th/9901001v2").unwrap();
+        assert_eq!(old_style.to_string(), "hep-th/9901001v2");
+        assert_eq!(old_style.to_file_name(), "hep-th_9901001v2");
+        assert_eq!(old_style.base(), "hep-th/9901001");
+        assert_eq!(old_style.version(), Some(2));
+    }
 }
 
 #[cfg(test)]
 mod parse_arxiv_metadata_tests {
-    use super::parse_arxiv_metadata;
+    use super::*;
+
+    #[test]
+    fn authors_are_split() {
+        assert_eq!(
+            split_authors("A. Author (1), B. Author ((1) MIT, (2) CERN) and C. Author"),
+            vec!["A. Author", "B. Author", "C. Author"]
+        );
+    }
+
+    #[test]
+    fn invalid_fields_are_reported() {
+        let metadata = r##"\\
+arXiv:1234.5678
+Date: Mon, 32 Jun 2017 17:57:34 GMT   (1102kb,D)
+
+Title: A title
+Authors: An Author
+Categories: cs.CL
+\\
+An abstract
+"##;
+        let error = parse_arxiv_metadata(metadata).unwrap_err();
+        assert_eq!(error.field, "Date");
+
+        let metadata = metadata
+            .replace("32 Jun", "12 Jun")
+            .replace("Title: A title\n", "");
+        let error = parse_arxiv_metadata(&metadata).unwrap_err();
+        assert_eq!(error, MetadataError::new("Title", "missing"));
+    }
 
     #[test]
     fn example() {
@@ -147,8 +515,33 @@ Transformer generalizes well to other tasks by applying it successfully to
 English constituency parsing both with large and limited training data.
 \\"##;
         let data = parse_arxiv_metadata(metadata).unwrap();
-        println!("keys: {:?}", data.keys().collect::<Vec<_>>());
-        assert_eq!(data["Title"], "Attention Is All You Need");
+        assert_eq!(data.id, "1706.03762");
+        assert_eq!(data.version, 3);
+        assert_eq!(data.title, "Attention Is All You Need");
+        assert_eq!(data.authors.len(), 8);
+        assert_eq!(data.authors[4], "Llion Jones");
+        assert_eq!(data.categories, vec!["cs.CL", "cs.LG"]);
+        assert_eq!(data.comments.as_deref(), Some("15 pages, 5 figure"));
+        assert_eq!(
+            data.license.as_deref(),
+            Some("http://arxiv.org/licenses/nonexclusive-distrib/1.0/")
+        );
+        assert_eq!(
+            data.revisions
+                .iter()
+                .map(|revision| (revision.version, revision.size_kb))
+                .collect::<Vec<_>>(),
+            vec![(1, 1102), (2, 1125), (3, 1125)]
+        );
+        assert_eq!(
+            data.published,
+            DateTime::parse_from_rfc3339("2017-06-12T17:57:34Z").unwrap()
+        );
+        assert_eq!(
+            data.updated,
+            DateTime::parse_from_rfc3339("2017-06-20T05:20:02Z").unwrap()
+        );
+        assert!(data.abstract_.starts_with("The dominant sequence"));
     }
 
     #[test]
@@ -182,7 +575,15 @@ models. Empirically, ensembles of Deep NADE models obtain state of the art
 density estimation performance.
 \\"##;
         let data = parse_arxiv_metadata(metadata).unwrap();
-        println!("keys: {:?}", data.keys().collect::<Vec<_>>());
-        assert_eq!(data["Title"], "A Deep and Tractable Density Estimator");
+        assert_eq!(data.title, "A Deep and Tractable Density Estimator");
+        assert_eq!(
+            data.authors,
+            vec!["Benigno Uria", "Iain Murray", "Hugo Larochelle"]
+        );
+        assert_eq!(
+            data.comments.as_deref(),
+            Some("9 pages, 4 tables, 1 algorithm, 5 figures. To appear ICML 2014, JMLR W&CP volume 32")
+        );
+        assert_eq!(data.version, 2);
     }
 }
@@ -0,0 +1,162 @@
+//! On-disk cache of the arXiv metadata
+//!
+//! Every entry is a JSON file named after the requested identifier, e.g.,
+//! `hep-th_9711200v3.json`. Fetched metadata is stored under its versioned
+//! identifier and, if requested without version, also under the plain
+//! identifier. Since the metadata of a specific version does not change,
+//! versioned entries never expire. Entries without version refer to the latest
+//! version at the time of the download and expire after the maximum age.
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+use tools_utils::{Error, Result};
+
+use super::arxiv::{ArxivId, ArxivMetadata};
+
+/// The maximum age of entries without version, if not configured otherwise
+pub const DEFAULT_MAX_AGE_DAYS: u32 = 30;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub fetched: DateTime<Utc>,
+    pub metadata: ArxivMetadata,
+}
+
+pub struct MetadataCache {
+    directory: PathBuf,
+    max_age: Duration,
+}
+
+impl MetadataCache {
+    pub fn new(directory: impl Into<PathBuf>, max_age: Duration) -> Self {
+        Self {
+            directory: directory.into(),
+            max_age,
+        }
+    }
+
+    fn entry_path(&self, id: &ArxivId) -> PathBuf {
+        self.directory.join(format!("{}.json", id.to_file_name()))
+    }
+
+    /// Read the cached entry, invalid entries are treated as missing
+    pub fn get(&self, id: &ArxivId) -> Result<Option<CacheEntry>> {
+        let path = self.entry_path(id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("get: cannot read file: {}", e))?;
+        match serde_json::from_str::<CacheEntry>(&content) {
+            Ok(entry) if entry.metadata.matches(id) => Ok(Some(entry)),
+            Ok(_) => {
+                println!("ignore cache entry {:?} [different id]", path);
+                Ok(None)
+            }
+            Err(e) => {
+                println!("ignore cache entry {:?} [{}]", path, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check whether the entry should be fetched again
+    pub fn is_expired(&self, id: &ArxivId, entry: &CacheEntry) -> bool {
+        id.version().is_none() && Utc::now() - entry.fetched > self.max_age
+    }
+
+    /// Store the metadata fetched for the requested identifier
+    pub fn insert(&self, id: &ArxivId, metadata: &ArxivMetadata) -> Result<()> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("insert: could not create directory: {}", e))?;
+        let entry = CacheEntry {
+            fetched: Utc::now(),
+            metadata: metadata.clone(),
+        };
+        let content = serde_json::to_string_pretty(&entry)
+            .map_err(|e| format!("insert: cannot serialize metadata: {}", e))?;
+
+        let versioned_id = metadata.versioned_id();
+        let mut ids = vec![&versioned_id];
+        if id.version().is_none() {
+            ids.push(id);
+        }
+        for id in ids {
+            // NOTE: replace the file, so concurrent runs never read partial entries
+            let path = self.entry_path(id);
+            let temp_path = path.with_extension("json.tmp");
+            fs::write(&temp_path, &content)
+                .map_err(|e| format!("insert: cannot write file: {}", e))?;
+            fs::rename(&temp_path, &path)
+                .map_err(|e| format!("insert: cannot replace file: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The platform specific cache directory, e.g., `~/.cache/tools-papers`
+pub fn default_directory() -> Result<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
+    base.map(|base| base.join("tools-papers"))
+        .ok_or_else(|| Error::from("Cannot determine the cache directory, use --cache-dir"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::api::parse_feed;
+    use super::*;
+
+    const QUERY_FIXTURE: &str = include_str!("../fixtures/arxiv_query.xml");
+
+    fn id(s: &str) -> ArxivId {
+        ArxivId::parse(s).unwrap()
+    }
+
+    #[test]
+    fn entries_are_stored_with_and_without_version() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache = MetadataCache::new(directory.path(), Duration::days(1));
+        let papers = parse_feed(QUERY_FIXTURE)?.papers;
+
+        assert_eq!(cache.get(&id("hep-th/9711200"))?, None);
+        cache.insert(&id("hep-th/9711200"), &papers[1])?;
+        cache.insert(&id("1706.03762v6"), &papers[0])?;
+
+        let entry = cache.get(&id("hep-th/9711200"))?.unwrap();
+        assert_eq!(entry.metadata, papers[1]);
+        assert!(!cache.is_expired(&id("hep-th/9711200"), &entry));
+        assert_eq!(
+            cache.get(&id("hep-th/9711200v3"))?.unwrap().metadata,
+            papers[1]
+        );
+        assert!(directory.path().join("hep-th_9711200v3.json").exists());
+
+        assert!(cache.get(&id("1706.03762v6"))?.is_some());
+        assert_eq!(cache.get(&id("1706.03762"))?, None);
+        assert_eq!(cache.get(&id("hep-th/9711200v2"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn only_entries_without_version_expire() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache = MetadataCache::new(directory.path(), Duration::days(1));
+        let entry = CacheEntry {
+            fetched: Utc::now() - Duration::days(2),
+            metadata: parse_feed(QUERY_FIXTURE)?.papers.remove(0),
+        };
+        assert!(cache.is_expired(&id("1706.03762"), &entry));
+        assert!(!cache.is_expired(&id("1706.03762v6"), &entry));
+
+        fs::write(directory.path().join("1706.03762.json"), "{").map_err(|e| e.to_string())?;
+        assert_eq!(cache.get(&id("1706.03762"))?, None);
+        Ok(())
+    }
+}
@@ -1,105 +1,359 @@
+mod api;
 mod arxiv;
+mod cache;
+mod rename;
 
+use chrono::Duration;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use lazy_static::lazy_static;
 use regex::Regex;
-use reqwest::header::USER_AGENT;
-use std::{
-    env,
-    path::{Path, PathBuf},
-    thread,
-    time::Duration,
-};
+use std::path::{Path, PathBuf};
 use tools_utils::{run_main, Result};
 
-use arxiv::{is_arxiv_paper, parse_arxiv_metadata};
+use api::ArxivClient;
+use arxiv::{ArxivId, ArxivMetadata};
+use cache::MetadataCache;
+use rename::CollisionPolicy;
 
 fn main() {
     run_main(main_impl);
 }
 
 fn main_impl() -> Result<i32> {
-    let args = parse_args()?;
-    println!("Sort papers");
+    match parse_args()? {
+        Command::Sort(arguments) => sort_main(arguments),
+        Command::Undo(arguments) => undo_main(arguments),
+    }
+}
+
+fn sort_main(args: SortArguments) -> Result<i32> {
+    if args.dry_run {
+        println!("Sort papers (dry run)");
+    } else {
+        println!("Sort papers");
+    }
     println!("Source: {:?}", args.source);
     println!("Target: {:?}", args.target);
 
-    process_directory(&args.source, &args.target)?;
+    let cache = MetadataCache::new(&args.cache_dir, args.cache_max_age);
+    let options = FetchOptions {
+        refresh: args.refresh_cache,
+        offline: args.offline,
+    };
+    let missing = process_directory(
+        &args.source,
+        &args.target,
+        &cache,
+        &options,
+        args.collisions,
+        args.dry_run,
+    )?;
+    if missing > 0 {
+        println!(
+            "{} papers were not renamed due to missing metadata",
+            missing
+        );
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+fn undo_main(args: UndoArguments) -> Result<i32> {
+    let log = match args.log {
+        Some(log) => log,
+        None => rename::latest_undo_log(&args.target)?,
+    };
+    if args.dry_run {
+        println!("Undo renames (dry run)");
+    } else {
+        println!("Undo renames");
+    }
+    println!("Undo log: {:?}", log);
+    rename::undo(&log, args.dry_run)?;
     Ok(0)
 }
 
-fn parse_args() -> Result<Arguments> {
-    let args = env::args_os().collect::<Vec<_>>();
-    if args.len() != 3 {
-        return Err(String::from("Usage: tools papers SRC DST").into());
+fn parse_args() -> Result<Command> {
+    let matches = App::new("tools-papers")
+        .about("Rename papers using the arXiv metadata")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Only print the planned renames"),
+        )
+        .arg(
+            Arg::with_name("collisions")
+                .long("collisions")
+                .takes_value(true)
+                .possible_values(&["skip", "suffix", "overwrite"])
+                .default_value("skip")
+                .help("How to handle papers whose new name is already taken"),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .help("The directory of the metadata cache, e.g., ~/.cache/tools-papers"),
+        )
+        .arg(
+            Arg::with_name("cache-max-age")
+                .long("cache-max-age")
+                .takes_value(true)
+                .help("The days after which metadata without version is fetched again"),
+        )
+        .arg(
+            Arg::with_name("refresh-cache")
+                .long("refresh-cache")
+                .help("Fetch the metadata of all papers and update the cache"),
+        )
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .conflicts_with("refresh-cache")
+                .help("Only use cached metadata, including expired entries"),
+        )
+        .arg(Arg::with_name("source").required(true))
+        .arg(Arg::with_name("target").required(true))
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Revert the renames of the latest run")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only print the renames to revert"),
+                )
+                .arg(
+                    Arg::with_name("log")
+                        .long("log")
+                        .takes_value(true)
+                        .help("The undo log of the run to revert, instead of the latest one"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("The target directory of the run"),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("undo", Some(matches)) => Ok(Command::Undo(parse_undo_args(matches)?)),
+        _ => Ok(Command::Sort(parse_sort_args(&matches)?)),
     }
+}
 
-    let result = Arguments {
-        source: args[1].clone().into(),
-        target: args[2].clone().into(),
+fn parse_sort_args(matches: &ArgMatches) -> Result<SortArguments> {
+    let cache_dir = match matches.value_of_os("cache-dir") {
+        Some(cache_dir) => cache_dir.into(),
+        None => cache::default_directory()?,
+    };
+    // NOTE: unsigned, as negative ages would expire all entries and large ones
+    // overflow the duration
+    let cache_max_age = matches
+        .value_of("cache-max-age")
+        .map(|s| {
+            s.parse::<u32>()
+                .map_err(|e| format!("Invalid cache max age {:?}: {}", s, e))
+        })
+        .transpose()?
+        .unwrap_or(cache::DEFAULT_MAX_AGE_DAYS);
+
+    let result = SortArguments {
+        source: matches
+            .value_of_os("source")
+            .ok_or_else(|| String::from("Missing argument source"))?
+            .into(),
+        target: matches
+            .value_of_os("target")
+            .ok_or_else(|| String::from("Missing argument target"))?
+            .into(),
+        cache_dir,
+        cache_max_age: Duration::days(i64::from(cache_max_age)),
+        refresh_cache: matches.is_present("refresh-cache"),
+        offline: matches.is_present("offline"),
+        collisions: CollisionPolicy::parse(matches.value_of("collisions").unwrap_or("skip"))?,
+        dry_run: matches.is_present("dry-run"),
+    };
+
+    Ok(result)
+}
+
+fn parse_undo_args(matches: &ArgMatches) -> Result<UndoArguments> {
+    let result = UndoArguments {
+        target: matches
+            .value_of_os("target")
+            .ok_or_else(|| String::from("Missing argument target"))?
+            .into(),
+        log: matches.value_of_os("log").map(PathBuf::from),
+        dry_run: matches.is_present("dry-run"),
     };
 
     Ok(result)
 }
 
-struct Arguments {
+enum Command {
+    Sort(SortArguments),
+    Undo(UndoArguments),
+}
+
+struct SortArguments {
     source: PathBuf,
     target: PathBuf,
+    cache_dir: PathBuf,
+    cache_max_age: Duration,
+    refresh_cache: bool,
+    offline: bool,
+    collisions: CollisionPolicy,
+    dry_run: bool,
 }
 
-fn process_directory<P, Q>(root: P, target: Q) -> Result<()>
+struct UndoArguments {
+    target: PathBuf,
+    log: Option<PathBuf>,
+    dry_run: bool,
+}
+
+struct FetchOptions {
+    /// Ignore the cached metadata
+    refresh: bool,
+    /// Never contact the API
+    offline: bool,
+}
+
+fn process_directory<P, Q>(
+    root: P,
+    target: Q,
+    cache: &MetadataCache,
+    options: &FetchOptions,
+    collisions: CollisionPolicy,
+    dry_run: bool,
+) -> Result<usize>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
 {
-    let root = root.as_ref();
-    let target = target.as_ref();
+    // NOTE: the undo log uses absolute paths, to be independent of the working directory
+    let root = root
+        .as_ref()
+        .canonicalize()
+        .map_err(|e| format!("Cannot resolve source directory: {}", e))?;
+    let target = target
+        .as_ref()
+        .canonicalize()
+        .map_err(|e| format!("Cannot resolve target directory: {}", e))?;
+
+    let mut papers = Vec::new();
     for entry in root
         .read_dir()
         .map_err(|e| format!("Cannot read directory: {}", e))?
     {
         let entry = entry.map_err(|e| format!("Cannot read item information: {}", e))?;
+        if entry.file_name() == rename::UNDO_DIR {
+            continue;
+        }
         let path = entry.path();
 
-        let paper = parse_paper_path(&path);
-
-        // println!("{}: {}", filename, is_arxiv_paper(stem));
-        match paper {
-            Paper::Arxiv { path, id } => {
-                // TODO: port to API and use xml response instead of parsing text format
-                // let url = format!("http://export.arxiv.org/api/query?id_list={}", id);
-                let url = format!("https://export.arxiv.org/abs/{}?fmt=txt", id);
-
-                let client = reqwest::blocking::Client::new();
-                let metadata = client
-                    .get(&url)
-                    .header(USER_AGENT, "ArxivPaperTools/1.0")
-                    .send()
-                    .and_then(|r| r.text())
-                    .map_err(|e| format!("Error during download of metadata: {}", e))?;
-
-                let metadata = parse_arxiv_metadata(&metadata).ok_or_else(|| {
-                    format!("Cannot parse metadata for {}. \n===\n{}", id, metadata)
-                })?;
-                let new_path = metadata
-                    .get("Title")
-                    .ok_or_else(|| format!("Missing title meta data for {}", id))?;
-                let new_path = normalize_title(new_path);
-                let new_path = format!("{}_{}.pdf", id, new_path);
-                let new_path = target.join(new_path);
-                println!("{:?} -> {:?}", path, new_path);
-                std::fs::rename(path, new_path)
-                    .map_err(|e| format!("Cannot rename path: {}", e))?;
-
-                // sleep to conform with Arxiv Usage guidelines
-                thread::sleep(Duration::from_millis(250));
-            }
+        match parse_paper_path(&path) {
+            Paper::Arxiv { path, id } => papers.push((path.to_owned(), id)),
             Paper::Unknown { path } => {
                 println!("ignore {:?} ", path);
             }
         }
     }
+    if papers.is_empty() {
+        return Ok(0);
+    }
+
+    let ids = papers.iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
+    let metadata = load_metadata(&ids, cache, options, ArxivClient::new)?;
+
+    // NOTE: papers without metadata are reported, the others are renamed
+    let mut renames = Vec::new();
+    let mut missing = 0;
+    for (path, id) in papers {
+        let metadata = match metadata.iter().find(|metadata| metadata.matches(&id)) {
+            Some(metadata) => metadata,
+            None => {
+                println!("skip {:?} [missing metadata for {}]", path, id);
+                missing += 1;
+                continue;
+            }
+        };
+        let new_path = normalize_title(&metadata.title);
+        let new_path = format!("{}_{}.pdf", id.to_file_name(), new_path);
+        renames.push((path, target.join(new_path)));
+    }
 
-    Ok(())
+    let plan = rename::plan_renames(renames, collisions);
+    rename::print_plan(&plan);
+    if !dry_run {
+        if let Some(log) = rename::apply_plan(&plan, &target)? {
+            println!("Undo log: {:?}", log);
+        }
+    }
+    Ok(missing)
+}
+
+/// Load the metadata from the cache and fetch the missing papers
+///
+/// The client is only created if any metadata is missing. Errors reported by
+/// the API and failed requests are printed. Papers missing from the API
+/// response, including those of failed batches, are fetched from their
+/// abstract page, if it describes the requested version. Offline, only the
+/// cached metadata is returned.
+fn load_metadata(
+    ids: &[ArxivId],
+    cache: &MetadataCache,
+    options: &FetchOptions,
+    client: impl FnOnce() -> ArxivClient,
+) -> Result<Vec<ArxivMetadata>> {
+    let mut result = Vec::new();
+    let mut missing = Vec::new();
+    for id in ids {
+        let cached = if options.refresh {
+            None
+        } else {
+            cache.get(id)?
+        };
+        match cached {
+            Some(entry) if options.offline || !cache.is_expired(id, &entry) => {
+                result.push(entry.metadata)
+            }
+            _ if !missing.contains(id) => missing.push(id.clone()),
+            _ => {}
+        }
+    }
+    // NOTE: offline, papers missing from the cache are skipped by the caller
+    if missing.is_empty() || options.offline {
+        return Ok(result);
+    }
+
+    // NOTE: the metadata of all missing papers is requested at once
+    let client = client();
+    let fetched = client.fetch(&missing);
+    for error in &fetched.errors {
+        eprintln!("{}", error);
+    }
+    for id in &missing {
+        if let Some(metadata) = fetched.papers.iter().find(|metadata| metadata.matches(id)) {
+            cache.insert(id, metadata)?;
+            continue;
+        }
+        match client.fetch_abstract_page(id) {
+            Ok(metadata) if metadata.matches(id) => {
+                cache.insert(id, &metadata)?;
+                result.push(metadata);
+            }
+            Ok(metadata) => eprintln!(
+                "The abstract page of {} describes version {}",
+                id, metadata.version
+            ),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    result.extend(fetched.papers);
+    Ok(result)
 }
 
 /// Normalize a paper title such that it is suitable for renaming the file  
@@ -118,10 +372,10 @@ fn normalize_title(s: &str) -> String {
 
 enum Paper<'a> {
     Unknown { path: &'a Path },
-    Arxiv { path: &'a Path, id: &'a str },
+    Arxiv { path: &'a Path, id: ArxivId },
 }
 
-fn parse_paper_path<P: AsRef<Path>>(path: &P) -> Paper {
+fn parse_paper_path<P: AsRef<Path>>(path: &P) -> Paper<'_> {
     let path = path.as_ref();
     let stem = path.file_stem().and_then(|s| s.to_str());
     if stem.is_none() {
@@ -129,9 +383,58 @@ fn parse_paper_path<P: AsRef<Path>>(path: &P) -> Paper {
     }
     let stem = stem.unwrap();
 
-    if is_arxiv_paper(stem) {
-        Paper::Arxiv { path, id: stem }
-    } else {
-        Paper::Unknown { path }
+    match ArxivId::from_file_name(stem) {
+        Some(id) => Paper::Arxiv { path, id },
+        None => Paper::Unknown { path },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY_FIXTURE: &str = include_str!("../fixtures/arxiv_query.xml");
+    const ABSTRACT_FIXTURE: &str = include_str!("../fixtures/arxiv_abstract.txt");
+
+    #[test]
+    fn offline_runs_only_use_the_cache() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache = MetadataCache::new(directory.path(), Duration::days(-1));
+        let options = FetchOptions {
+            refresh: false,
+            offline: true,
+        };
+        let id = |s| ArxivId::parse(s).unwrap();
+        let offline_client = || -> ArxivClient { panic!("offline runs must not fetch") };
+
+        cache.insert(
+            &id("1706.03762"),
+            &api::parse_feed(QUERY_FIXTURE)?.papers[0],
+        )?;
+        let ids = [id("hep-th/9711200"), id("1706.03762")];
+        let metadata = load_metadata(&ids, &cache, &options, offline_client)?;
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].title, "Attention Is All You Need");
+        Ok(())
+    }
+
+    #[test]
+    fn papers_of_failed_batches_are_fetched_from_their_abstract_page() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let cache = MetadataCache::new(directory.path(), Duration::days(1));
+        let options = FetchOptions {
+            refresh: false,
+            offline: false,
+        };
+        let (url, requests) = api::mock::serve(vec![(503, ""), (200, ABSTRACT_FIXTURE)]);
+        let client = || ArxivClient::with_mock_server(&url);
+
+        let id = ArxivId::parse("1310.1757").unwrap();
+        let metadata = load_metadata(std::slice::from_ref(&id), &cache, &options, client)?;
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].title, "A Deep and Tractable Density Estimator");
+        assert!(cache.get(&id)?.is_some());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        Ok(())
     }
 }
@@ -0,0 +1,404 @@
+//! Renames of papers with collision handling and an undo log
+//!
+//! All renames of a run are planned first, so they can be printed without
+//! touching any file. Before the files are renamed, the plan is written as an
+//! undo log into the `.tools-papers` directory of the target. Files replaced
+//! by a rename are moved into the same directory. The latest log is used to
+//! move the papers back to their original names and to restore the replaced
+//! files.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use tools_utils::{Error, Result};
+
+pub const UNDO_DIR: &str = ".tools-papers";
+
+/// How to handle renames to a path that is already taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// Keep the paper under its current name
+    Skip,
+    /// Append a counter to the name, e.g., `_2`
+    Suffix,
+    /// Replace the existing file, it is kept for an undo. Targets of earlier
+    /// renames of the same run are never replaced, these papers are skipped.
+    Overwrite,
+}
+
+impl CollisionPolicy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "suffix" => Ok(Self::Suffix),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(Error::from(format!("Invalid collision policy {:?}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rename {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Whether an existing file is replaced
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Where the replaced file is kept, set when the plan is applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaced: Option<PathBuf>,
+}
+
+impl Rename {
+    fn new(source: PathBuf, target: PathBuf) -> Self {
+        Self {
+            source,
+            target,
+            overwrite: false,
+            replaced: None,
+        }
+    }
+}
+
+/// The renames of a single run
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Plan {
+    pub renames: Vec<Rename>,
+    /// Renames not performed due to a collision
+    pub skipped: Vec<Rename>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct UndoLog {
+    created: DateTime<Utc>,
+    renames: Vec<Rename>,
+}
+
+/// Plan the renames, collisions include existing files and earlier renames
+pub fn plan_renames(requested: Vec<(PathBuf, PathBuf)>, policy: CollisionPolicy) -> Plan {
+    let mut plan = Plan::default();
+    let mut taken = HashSet::new();
+    // NOTE: papers renamed in this run are never replaced
+    let sources = requested
+        .iter()
+        .map(|(source, _)| source.clone())
+        .collect::<HashSet<_>>();
+
+    for (source, target) in requested {
+        // NOTE: papers already named correctly are kept as they are
+        if source == target {
+            taken.insert(target);
+            continue;
+        }
+        if !is_taken(&taken, &target) {
+            taken.insert(target.clone());
+            plan.renames.push(Rename::new(source, target));
+            continue;
+        }
+
+        match policy {
+            CollisionPolicy::Skip => plan.skipped.push(Rename::new(source, target)),
+            CollisionPolicy::Suffix => {
+                let target = (2..)
+                    .map(|counter| with_suffix(&target, counter))
+                    .find(|path| !is_taken(&taken, path))
+                    .unwrap();
+                taken.insert(target.clone());
+                plan.renames.push(Rename::new(source, target));
+            }
+            CollisionPolicy::Overwrite if taken.contains(&target) || sources.contains(&target) => {
+                plan.skipped.push(Rename::new(source, target))
+            }
+            CollisionPolicy::Overwrite => {
+                taken.insert(target.clone());
+                plan.renames.push(Rename {
+                    overwrite: true,
+                    ..Rename::new(source, target)
+                });
+            }
+        }
+    }
+    plan
+}
+
+fn is_taken(taken: &HashSet<PathBuf>, path: &Path) -> bool {
+    taken.contains(path) || path.exists()
+}
+
+/// Append a counter to the file stem, e.g., `paper.pdf` becomes `paper_2.pdf`
+fn with_suffix(path: &Path, counter: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, counter, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, counter),
+    };
+    path.with_file_name(name)
+}
+
+pub fn print_plan(plan: &Plan) {
+    for rename in &plan.renames {
+        if rename.overwrite {
+            println!("{:?} -> {:?} [overwrite]", rename.source, rename.target);
+        } else {
+            println!("{:?} -> {:?}", rename.source, rename.target);
+        }
+    }
+    for rename in &plan.skipped {
+        println!("skip {:?} [{:?} exists]", rename.source, rename.target);
+    }
+}
+
+/// Write the undo log into the target directory and rename the papers
+///
+/// Replaced files are moved into a `replaced-*` directory next to the undo
+/// log. Returns the path of the undo log, if any paper is renamed.
+pub fn apply_plan(plan: &Plan, target: impl AsRef<Path>) -> Result<Option<PathBuf>> {
+    if plan.renames.is_empty() {
+        return Ok(None);
+    }
+    let created = Utc::now();
+    let timestamp = created.format("%Y-%m-%dT%H-%M-%S-%3f").to_string();
+    let directory = target.as_ref().join(UNDO_DIR);
+    let replaced_directory = directory.join(format!("replaced-{}", timestamp));
+
+    let mut renames = plan.renames.clone();
+    for rename in renames.iter_mut().filter(|rename| rename.overwrite) {
+        // NOTE: the targets of a plan are distinct, and so are their names
+        rename.replaced = rename
+            .target
+            .file_name()
+            .map(|name| replaced_directory.join(name));
+    }
+    let log = UndoLog { created, renames };
+
+    fs::create_dir_all(&directory)
+        .map_err(|e| format!("apply_plan: could not create directory: {}", e))?;
+    let path = directory.join(format!("undo-{}.json", timestamp));
+    let content = serde_json::to_string_pretty(&log)
+        .map_err(|e| format!("apply_plan: cannot serialize undo log: {}", e))?;
+    fs::write(&path, content).map_err(|e| format!("apply_plan: cannot write undo log: {}", e))?;
+
+    for rename in &log.renames {
+        if let Some(replaced) = &rename.replaced {
+            fs::create_dir_all(&replaced_directory)
+                .map_err(|e| format!("apply_plan: could not create directory: {}", e))?;
+            fs::rename(&rename.target, replaced).map_err(|e| {
+                format!(
+                    "Cannot move the replaced file {:?}: {}. Use the undo log {:?} to revert the previous renames",
+                    rename.target, e, path
+                )
+            })?;
+        }
+        fs::rename(&rename.source, &rename.target).map_err(|e| {
+            format!(
+                "Cannot rename {:?}: {}. Use the undo log {:?} to revert the previous renames",
+                rename.source, e, path
+            )
+        })?;
+    }
+    Ok(Some(path))
+}
+
+/// The most recent undo log of the target directory, that was not yet undone
+pub fn latest_undo_log(target: impl AsRef<Path>) -> Result<PathBuf> {
+    let directory = target.as_ref().join(UNDO_DIR);
+    let mut logs = Vec::new();
+    if directory.exists() {
+        for entry in directory
+            .read_dir()
+            .map_err(|e| format!("latest_undo_log: cannot read directory: {}", e))?
+        {
+            let entry = entry
+                .map_err(|e| format!("latest_undo_log: cannot read item information: {}", e))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with("undo-") && name.ends_with(".json") {
+                logs.push(entry.path());
+            }
+        }
+    }
+    logs.sort();
+    logs.pop()
+        .ok_or_else(|| Error::from(format!("No undo log found in {:?}", directory)))
+}
+
+/// Move the papers of the undo log back to their original names
+///
+/// Papers moved or removed since the run are skipped. Replaced files are moved
+/// back, if their name is free again. Afterwards the log is marked as undone,
+/// such that the next undo reverts the run before.
+pub fn undo(log_path: impl AsRef<Path>, dry_run: bool) -> Result<()> {
+    let log_path = log_path.as_ref();
+    let content =
+        fs::read_to_string(log_path).map_err(|e| format!("undo: cannot read undo log: {}", e))?;
+    let log: UndoLog = serde_json::from_str(&content)
+        .map_err(|e| format!("undo: invalid undo log {:?}: {}", log_path, e))?;
+
+    for rename in log.renames.iter().rev() {
+        let target_free = if !rename.target.exists() {
+            println!("skip {:?} [missing]", rename.target);
+            true
+        } else if rename.source.exists() {
+            println!("skip {:?} [{:?} exists]", rename.target, rename.source);
+            false
+        } else {
+            println!("{:?} -> {:?}", rename.target, rename.source);
+            if !dry_run {
+                fs::rename(&rename.target, &rename.source)
+                    .map_err(|e| format!("Cannot rename {:?}: {}", rename.target, e))?;
+            }
+            true
+        };
+
+        match &rename.replaced {
+            Some(replaced) if !replaced.exists() => {
+                println!("skip {:?} [missing]", replaced);
+            }
+            Some(replaced) if target_free => {
+                println!("{:?} -> {:?}", replaced, rename.target);
+                if !dry_run {
+                    fs::rename(replaced, &rename.target)
+                        .map_err(|e| format!("Cannot rename {:?}: {}", replaced, e))?;
+                }
+            }
+            Some(replaced) => println!("keep {:?} [{:?} exists]", replaced, rename.target),
+            // NOTE: logs of older versions did not keep replaced files
+            None if rename.overwrite => println!(
+                "the file replaced by {:?} cannot be restored",
+                rename.target
+            ),
+            None => {}
+        }
+    }
+
+    if !dry_run {
+        let mut undone = log_path.as_os_str().to_owned();
+        undone.push(".undone");
+        fs::rename(log_path, undone)
+            .map_err(|e| format!("undo: cannot mark the undo log as undone: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path) -> Result<()> {
+        fs::write(path, path.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    #[test]
+    fn collisions_follow_the_policy() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = |name: &str| directory.path().join(name);
+        touch(&path("b.pdf"))?;
+        touch(&path("c.pdf"))?;
+        let requested = || {
+            vec![
+                (path("1.pdf"), path("a.pdf")),
+                (path("2.pdf"), path("a.pdf")),
+                (path("3.pdf"), path("b.pdf")),
+                (path("c.pdf"), path("c.pdf")),
+            ]
+        };
+
+        let plan = plan_renames(requested(), CollisionPolicy::Skip);
+        assert_eq!(plan.renames.len(), 1);
+        assert_eq!(plan.renames[0].target, path("a.pdf"));
+        assert_eq!(plan.skipped.len(), 2);
+
+        let plan = plan_renames(requested(), CollisionPolicy::Suffix);
+        let targets = plan
+            .renames
+            .iter()
+            .map(|rename| rename.target.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![path("a.pdf"), path("a_2.pdf"), path("b_2.pdf")]
+        );
+        assert!(plan.skipped.is_empty());
+
+        // NOTE: the target of the first rename is never replaced
+        let plan = plan_renames(requested(), CollisionPolicy::Overwrite);
+        let renames = plan
+            .renames
+            .iter()
+            .map(|rename| (rename.target.clone(), rename.overwrite))
+            .collect::<Vec<_>>();
+        assert_eq!(renames, vec![(path("a.pdf"), false), (path("b.pdf"), true)]);
+        assert_eq!(plan.skipped.len(), 1);
+        assert_eq!(plan.skipped[0].source, path("2.pdf"));
+
+        // NOTE: neither are papers renamed in the same run
+        let plan = plan_renames(
+            vec![
+                (path("1.pdf"), path("b.pdf")),
+                (path("b.pdf"), path("d.pdf")),
+            ],
+            CollisionPolicy::Overwrite,
+        );
+        assert_eq!(plan.renames.len(), 1);
+        assert_eq!(plan.renames[0].source, path("b.pdf"));
+        Ok(())
+    }
+
+    #[test]
+    fn replaced_files_are_restored() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = |name: &str| directory.path().join(name);
+        let read = |name: &str| fs::read_to_string(path(name)).map_err(|e| e.to_string());
+        touch(&path("1.pdf"))?;
+        touch(&path("a.pdf"))?;
+
+        let plan = plan_renames(
+            vec![(path("1.pdf"), path("a.pdf"))],
+            CollisionPolicy::Overwrite,
+        );
+        let log = apply_plan(&plan, directory.path())?.unwrap();
+        assert_eq!(read("a.pdf")?, path("1.pdf").to_string_lossy());
+
+        undo(&log, false)?;
+        assert_eq!(read("1.pdf")?, path("1.pdf").to_string_lossy());
+        assert_eq!(read("a.pdf")?, path("a.pdf").to_string_lossy());
+        Ok(())
+    }
+
+    #[test]
+    fn renames_are_undone() -> Result<()> {
+        let directory = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let path = |name: &str| directory.path().join(name);
+        touch(&path("1.pdf"))?;
+        touch(&path("2.pdf"))?;
+        assert!(latest_undo_log(directory.path()).is_err());
+
+        let plan = plan_renames(
+            vec![
+                (path("1.pdf"), path("a.pdf")),
+                (path("2.pdf"), path("b.pdf")),
+            ],
+            CollisionPolicy::Skip,
+        );
+        let log = apply_plan(&plan, directory.path())?.unwrap();
+        assert!(path("a.pdf").exists() && path("b.pdf").exists());
+        assert_eq!(latest_undo_log(directory.path())?, log);
+
+        // NOTE: papers changed after the run are kept
+        fs::remove_file(path("b.pdf")).map_err(|e| e.to_string())?;
+        undo(&log, true)?;
+        assert!(path("a.pdf").exists());
+
+        undo(&log, false)?;
+        assert!(path("1.pdf").exists());
+        assert!(!path("a.pdf").exists());
+        assert!(latest_undo_log(directory.path()).is_err());
+        Ok(())
+    }
+}
@@ -103,7 +103,7 @@ fn parse_args() -> Result<Arguments> {
     let root = env::args()
         .nth(1)
         .map(PathBuf::from)
-        .ok_or_else(|| "Wrong arguments. Usage: tools tags DIRECTORY")?;
+        .ok_or("Wrong arguments. Usage: tools tags DIRECTORY")?;
     let result = Arguments { root };
 
     Ok(result)
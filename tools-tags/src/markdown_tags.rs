@@ -113,6 +113,7 @@ pub struct TaggedEntry {
     pub line: usize,
     pub section: String,
     pub tag: String,
+    #[allow(dead_code)]
     pub value: Option<String>,
 }
 
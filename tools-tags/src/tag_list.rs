@@ -5,8 +5,10 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
 
 pub struct TagList {
+    #[allow(dead_code)]
     root: PathBuf,
     tags: Vec<TaggedEntry>,
+    #[allow(dead_code)]
     errors: Vec<String>,
     on_update_callback: Box<dyn Fn(Arc<TagList>) + Send + Sync>,
     self_ref: LateInitWeak<TagList>,
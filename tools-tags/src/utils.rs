@@ -10,6 +10,7 @@ impl<T, E> Ignorable for std::result::Result<T, E> {
 }
 
 pub trait ResultsCollector<T, E> {
+    #[allow(dead_code)]
     fn collect_results(self) -> (Vec<T>, Vec<E>);
 
     fn collect_results_transformed<A, F, B, G>(self, f: F, g: G) -> (Vec<A>, Vec<B>)